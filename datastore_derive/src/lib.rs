@@ -0,0 +1,300 @@
+mod schema;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Expr, Fields, Ident, LitInt, LitStr, Type,
+};
+
+//#[derive(Storable)] implements datastore::MongoStorable.
+//
+//#[derive(Serialize, Storable)]
+//#[storable(collection = "books", namespace = "books", ttl = 3600, version = 1, validate, soft_delete, history)]
+//#[storable(relation = Relation::many_to_one("bookstore", "data.bookstore_id", "bookstores"))]
+//#[storable(index = IndexSpec::single("data.bookstore_id"))]
+//#[storable(unique = UniqueKey::single("data.name"))]
+//#[storable(id_strategy = IdStrategy::UuidV7)]
+//#[storable(migration = Migration::new(1, "split author name", split_author))]
+//pub struct BookRecord {
+//    #[storable(id)]
+//    pub _id: String,
+//    #[storable(data)]
+//    pub data: Book,
+//}
+//
+//The id and data fields default to the fields called _id and data, the namespace
+//to the collection name, ttl to no expiry, version to 0 and id_strategy to ObjectId. validate needs the record
+//to derive BsonSchema as well
+#[proc_macro_derive(Storable, attributes(storable))]
+pub fn derive_storable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+//#[derive(BsonSchema)] implements datastore::BsonSchema for a struct with named fields
+#[proc_macro_derive(BsonSchema)]
+pub fn derive_bson_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    schema::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct ContainerAttrs {
+    collection: Option<LitStr>,
+    namespace: Option<LitStr>,
+    ttl: Option<LitInt>,
+    version: Option<LitInt>,
+    validate: bool,
+    soft_delete: bool,
+    history: bool,
+    id_strategy: Option<Expr>,
+    relations: Vec<Expr>,
+    migrations: Vec<Expr>,
+    indexes: Vec<Expr>,
+    unique_keys: Vec<Expr>,
+}
+
+fn parse_container_attrs(attrs: &[Attribute]) -> syn::Result<ContainerAttrs> {
+    let mut parsed = ContainerAttrs::default();
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("storable")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("collection") {
+                parsed.collection = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("namespace") {
+                parsed.namespace = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("ttl") {
+                parsed.ttl = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("version") {
+                parsed.version = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("id_strategy") {
+                parsed.id_strategy = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("validate") {
+                parsed.validate = true;
+            } else if meta.path.is_ident("soft_delete") {
+                parsed.soft_delete = true;
+            } else if meta.path.is_ident("history") {
+                parsed.history = true;
+            } else if meta.path.is_ident("migration") {
+                parsed.migrations.push(meta.value()?.parse()?);
+            } else if meta.path.is_ident("relation") {
+                parsed.relations.push(meta.value()?.parse()?);
+            } else if meta.path.is_ident("index") {
+                parsed.indexes.push(meta.value()?.parse()?);
+            } else if meta.path.is_ident("unique") {
+                parsed.unique_keys.push(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error(
+                    "expected one of collection, namespace, ttl, version, validate, soft_delete, history, id_strategy, relation, index, unique, migration",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(parsed)
+}
+
+//Which of #[storable(id)] / #[storable(data)] a field carries
+fn field_role(attrs: &[Attribute]) -> syn::Result<Option<&'static str>> {
+    let mut role = None;
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("storable")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                role = Some("id");
+            } else if meta.path.is_ident("data") {
+                role = Some("data");
+            } else {
+                return Err(meta.error("expected id or data"));
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(role)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let attrs = parse_container_attrs(&input.attrs)?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "Storable needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "Storable can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut id_field: Option<&Ident> = None;
+    let mut data_field: Option<(&Ident, &Type)> = None;
+
+    //Explicit markers win over the _id / data naming convention
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().expect("named field");
+
+        match field_role(&field.attrs)? {
+            Some("id") => id_field = Some(ident),
+            Some(_) => data_field = Some((ident, &field.ty)),
+            None => (),
+        }
+    }
+
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().expect("named field");
+
+        if id_field.is_none() && ident == "_id" {
+            id_field = Some(ident);
+        }
+        if data_field.is_none() && ident == "data" {
+            data_field = Some((ident, &field.ty));
+        }
+    }
+
+    let collection = attrs.collection.ok_or_else(|| {
+        syn::Error::new_spanned(name, "missing #[storable(collection = \"...\")]")
+    })?;
+    let id_field = id_field.ok_or_else(|| {
+        syn::Error::new_spanned(name, "no _id field, mark the id with #[storable(id)]")
+    })?;
+    let (data_field, data_type) = data_field.ok_or_else(|| {
+        syn::Error::new_spanned(name, "no data field, mark it with #[storable(data)]")
+    })?;
+
+    let namespace = match attrs.namespace {
+        Some(namespace) => quote! { const CACHE_NAMESPACE: &'static str = #namespace; },
+        None => quote! {},
+    };
+
+    let ttl = match attrs.ttl {
+        Some(ttl) => quote! { const CACHE_TTL: Option<usize> = Some(#ttl); },
+        None => quote! {},
+    };
+
+    let version = match attrs.version {
+        Some(version) => quote! { const SCHEMA_VERSION: u32 = #version; },
+        None => quote! {},
+    };
+
+    let soft_delete = match attrs.soft_delete {
+        true => quote! { const SOFT_DELETE: bool = true; },
+        false => quote! {},
+    };
+
+    let history = match attrs.history {
+        true => quote! { const HISTORY: bool = true; },
+        false => quote! {},
+    };
+
+    let relations = attrs.relations;
+    let relations = match relations.is_empty() {
+        true => quote! {},
+        false => quote! {
+            fn relations() -> Vec<::datastore::Relation> {
+                vec![#(#relations),*]
+            }
+        },
+    };
+
+    let indexes = attrs.indexes;
+    let indexes = match indexes.is_empty() {
+        true => quote! {},
+        false => quote! {
+            fn indexes() -> Vec<::datastore::IndexSpec> {
+                vec![#(#indexes),*]
+            }
+        },
+    };
+
+    let unique_keys = attrs.unique_keys;
+    let unique_keys = match unique_keys.is_empty() {
+        true => quote! {},
+        false => quote! {
+            fn unique_keys() -> Vec<::datastore::UniqueKey> {
+                vec![#(#unique_keys),*]
+            }
+        },
+    };
+
+    let id_strategy = match attrs.id_strategy {
+        Some(id_strategy) => quote! { const ID_STRATEGY: ::datastore::IdStrategy = #id_strategy; },
+        None => quote! {},
+    };
+
+    let json_schema = match attrs.validate {
+        true => quote! {
+            fn json_schema() -> Option<::datastore::__private::bson::Document> {
+                Some(<Self as ::datastore::BsonSchema>::bson_schema())
+            }
+        },
+        false => quote! {},
+    };
+
+    let migrations = attrs.migrations;
+    let migrations = match migrations.is_empty() {
+        true => quote! {},
+        false => quote! {
+            fn migrations() -> Vec<::datastore::Migration> {
+                vec![#(#migrations),*]
+            }
+        },
+    };
+
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::datastore::MongoStorable for #name #type_generics #where_clause {
+            type Data = #data_type;
+
+            const COLLECTION: &'static str = #collection;
+            #namespace
+            #ttl
+            #version
+            #soft_delete
+            #history
+            #id_strategy
+
+            fn get_id(&self) -> &str {
+                &self.#id_field
+            }
+
+            fn set_id(&mut self, id: String) {
+                self.#id_field = id;
+            }
+
+            fn get_data(&self) -> &Self::Data {
+                &self.#data_field
+            }
+
+            fn try_to_str(&self) -> ::datastore::Result<(String, String)> {
+                let key = format!("{}_{}", Self::CACHE_NAMESPACE, self.get_id());
+                let value = ::datastore::__private::serde_json::to_string(self.get_data())?;
+                Ok((key, value))
+            }
+
+            #relations
+            #indexes
+            #unique_keys
+            #json_schema
+            #migrations
+        }
+    })
+}
@@ -0,0 +1,147 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Attribute, Data, DeriveInput, Fields, LitStr, Token};
+
+//What the field's #[serde(...)] attributes change about the stored document
+#[derive(Default)]
+struct SerdeField {
+    rename: Option<String>,
+    default: bool,
+    skip: bool,
+    //skip_serializing_if, the field is left out of some documents
+    skip_if: bool,
+}
+
+//Values of serde options this derive does not care about, e.g. with = "..." or rename(serialize = "...")
+fn skip_value(meta: &syn::meta::ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        let _: syn::Expr = meta.value()?.parse()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        let content;
+        syn::parenthesized!(content in meta.input);
+        let _: TokenStream2 = content.parse()?;
+    }
+    Ok(())
+}
+
+fn serde_field(attrs: &[Attribute]) -> syn::Result<SerdeField> {
+    let mut parsed = SerdeField::default();
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") && meta.input.peek(Token![=]) {
+                let rename: LitStr = meta.value()?.parse()?;
+                parsed.rename = Some(rename.value());
+            } else if meta.path.is_ident("default") {
+                parsed.default = true;
+                skip_value(&meta)?;
+            } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                parsed.skip = true;
+            } else if meta.path.is_ident("skip_serializing_if") {
+                parsed.skip_if = true;
+                skip_value(&meta)?;
+            } else if meta.path.is_ident("flatten") {
+                return Err(meta.error("BsonSchema does not support #[serde(flatten)]"));
+            } else {
+                skip_value(&meta)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(parsed)
+}
+
+//Container options that change field names can't be followed field by field
+fn check_container(attrs: &[Attribute]) -> syn::Result<()> {
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename_all") {
+                return Err(meta
+                    .error("BsonSchema does not follow rename_all, rename the fields one by one"));
+            }
+            skip_value(&meta)
+        })?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    check_container(&input.attrs)?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "BsonSchema needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "BsonSchema can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut properties = Vec::new();
+
+    for field in fields.iter() {
+        let serde = serde_field(&field.attrs)?;
+        if serde.skip {
+            continue;
+        }
+
+        let ident = field.ident.as_ref().expect("named field");
+        let key = serde
+            .rename
+            .unwrap_or_else(|| ident.to_string().trim_start_matches("r#").to_owned());
+        let ty = &field.ty;
+
+        //A field serde fills in with its default, or only writes some of the time, may be
+        //missing from the document
+        let required = match serde.default || serde.skip_if {
+            true => quote! {},
+            false => quote! {
+                if !<#ty as ::datastore::BsonSchema>::is_optional() {
+                    required.push(#key);
+                }
+            },
+        };
+
+        properties.push(quote! {
+            properties.insert(#key, <#ty as ::datastore::BsonSchema>::bson_schema());
+            #required
+        });
+    }
+
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::datastore::BsonSchema for #name #type_generics #where_clause {
+            fn bson_schema() -> ::datastore::__private::bson::Document {
+                #[allow(unused_mut)]
+                let mut properties = ::datastore::__private::bson::Document::new();
+                #[allow(unused_mut)]
+                let mut required: Vec<&str> = Vec::new();
+
+                #(#properties)*
+
+                let mut schema = ::datastore::__private::bson::doc! {
+                    "bsonType": "object",
+                    "properties": properties,
+                };
+                //Mongo rejects an empty required list
+                if !required.is_empty() {
+                    schema.insert("required", required);
+                }
+                schema
+            }
+        }
+    })
+}
@@ -0,0 +1,495 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::sync::OnceCell;
+
+use crate::error::{DatastoreError, Result};
+use crate::timestamps::{CREATED_AT_FIELD, UPDATED_AT_FIELD};
+use crate::Datastore;
+
+//Stream entries are read back in pages of this size while filtering
+const AUDIT_STREAM_PAGE: usize = 100;
+
+//Collection name of the entries recorded for Datastore::try_clear_cache, the cache
+//spans every collection
+pub const CACHE_AUDIT_COLLECTION: &str = "cache";
+
+//Managed fields change on every write, recorded_at already tells when
+const UNAUDITED_FIELDS: [&str; 3] = ["_id", CREATED_AT_FIELD, UPDATED_AT_FIELD];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOperation {
+    Create,
+    Update,
+    Delete,
+    Clear,
+}
+
+impl AuditOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Create => "create",
+            AuditOperation::Update => "update",
+            AuditOperation::Delete => "delete",
+            AuditOperation::Clear => "clear",
+        }
+    }
+
+    fn parse(operation: &str) -> Result<Self> {
+        match operation {
+            "create" => Ok(AuditOperation::Create),
+            "update" => Ok(AuditOperation::Update),
+            "delete" => Ok(AuditOperation::Delete),
+            "clear" => Ok(AuditOperation::Clear),
+            _ => Err(DatastoreError::serialization(format!(
+                "Unknown audit operation {}",
+                operation
+            ))),
+        }
+    }
+}
+
+//One changed field, by dotted path into the stored document. A side the field is
+//missing from is null
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub path: String,
+    pub before: Bson,
+    pub after: Bson,
+}
+
+//A write as it was recorded. Clears have no record_id and no changes, latency covers
+//Atlas, the cache and the history, for transactions the whole transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub operation: AuditOperation,
+    pub collection: String,
+    pub record_id: Option<String>,
+    pub actor: Option<String>,
+    pub recorded_at: DateTime<Utc>,
+    pub changes: Vec<FieldChange>,
+    pub latency: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditSink {
+    //Expired by a TTL index on expires_at
+    Mongo { collection: String },
+    //Trimmed by MINID on every append
+    RedisStream { key: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditConfig {
+    pub sink: AuditSink,
+    pub retention: Duration,
+}
+
+impl AuditConfig {
+    pub fn mongo(collection: impl Into<String>, retention: Duration) -> Self {
+        Self {
+            sink: AuditSink::Mongo {
+                collection: collection.into(),
+            },
+            retention,
+        }
+    }
+
+    pub fn redis_stream(key: impl Into<String>, retention: Duration) -> Self {
+        Self {
+            sink: AuditSink::RedisStream { key: key.into() },
+            retention,
+        }
+    }
+}
+
+//Snapshot for monitoring, see Datastore::audit_status. A write whose entries can't be
+//recorded still goes through, the entries it lost are counted here
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditStatus {
+    pub failed_entries: u64,
+    pub last_error: Option<String>,
+}
+
+//Unset fields match everything. Entries come back newest first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditFilter {
+    pub collection: Option<String>,
+    pub record_id: Option<String>,
+    pub actor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let matches = |wanted: &Option<String>, value: Option<&str>| {
+            wanted.as_deref().is_none_or(|wanted| Some(wanted) == value)
+        };
+
+        matches(&self.collection, Some(entry.collection.as_str()))
+            && matches(&self.record_id, entry.record_id.as_deref())
+            && matches(&self.actor, entry.actor.as_deref())
+    }
+}
+
+//A write waiting to be recorded once it went through. Documents are the stored ones,
+//the diff is only worked out when auditing is on
+pub(crate) struct AuditDraft {
+    operation: AuditOperation,
+    collection: String,
+    record_id: Option<String>,
+    before: Option<Document>,
+    after: Option<Document>,
+}
+
+impl AuditDraft {
+    pub(crate) fn new(
+        operation: AuditOperation,
+        collection: &str,
+        record_id: &str,
+        before: Option<Document>,
+        after: Option<Document>,
+    ) -> Self {
+        Self {
+            operation,
+            collection: collection.to_owned(),
+            record_id: Some(record_id.to_owned()),
+            before,
+            after,
+        }
+    }
+
+    pub(crate) fn clear(collection: &str) -> Self {
+        Self {
+            operation: AuditOperation::Clear,
+            collection: collection.to_owned(),
+            record_id: None,
+            before: None,
+            after: None,
+        }
+    }
+}
+
+//The stored document of record_id among the documents read before a write
+pub(crate) fn prior_of(prior: &[Document], record_id: &str) -> Option<Document> {
+    prior
+        .iter()
+        .find(|document| document.get_str("_id") == Ok(record_id))
+        .cloned()
+}
+
+//The document as a $set of update leaves it
+pub(crate) fn merged(before: Option<&Document>, update: &Document) -> Document {
+    let mut after = before.cloned().unwrap_or_default();
+    for (key, value) in update {
+        after.insert(key, value.clone());
+    }
+    after
+}
+
+fn flatten(prefix: &str, document: &Document, fields: &mut BTreeMap<String, Bson>) {
+    for (key, value) in document {
+        let path = match prefix.is_empty() {
+            true => key.to_owned(),
+            false => format!("{}.{}", prefix, key),
+        };
+
+        match value {
+            Bson::Document(nested) if !nested.is_empty() => flatten(&path, nested, fields),
+            _ => {
+                fields.insert(path, value.clone());
+            }
+        }
+    }
+}
+
+fn diff(before: Option<&Document>, after: Option<&Document>) -> Vec<FieldChange> {
+    let mut before_fields = BTreeMap::new();
+    let mut after_fields = BTreeMap::new();
+    if let Some(before) = before {
+        flatten("", before, &mut before_fields);
+    }
+    if let Some(after) = after {
+        flatten("", after, &mut after_fields);
+    }
+
+    let paths: BTreeSet<&String> = before_fields.keys().chain(after_fields.keys()).collect();
+
+    paths
+        .into_iter()
+        .filter(|path| !UNAUDITED_FIELDS.contains(&path.as_str()))
+        .filter(|path| before_fields.get(*path) != after_fields.get(*path))
+        .map(|path| FieldChange {
+            path: path.to_owned(),
+            before: before_fields.get(path).cloned().unwrap_or(Bson::Null),
+            after: after_fields.get(path).cloned().unwrap_or(Bson::Null),
+        })
+        .collect()
+}
+
+fn changes_bson(changes: &[FieldChange]) -> Bson {
+    Bson::Array(
+        changes
+            .iter()
+            .map(|change| {
+                Bson::Document(doc! {
+                    "path": &change.path,
+                    "before": change.before.clone(),
+                    "after": change.after.clone(),
+                })
+            })
+            .collect(),
+    )
+}
+
+fn changes_of(changes: &Bson) -> Result<Vec<FieldChange>> {
+    let changes = changes
+        .as_array()
+        .ok_or_else(|| DatastoreError::serialization("Audit changes are not an array"))?;
+
+    changes
+        .iter()
+        .map(|change| {
+            let change = change
+                .as_document()
+                .ok_or_else(|| DatastoreError::serialization("Audit change is not a document"))?;
+            Ok(FieldChange {
+                path: change.get_str("path")?.to_owned(),
+                before: change.get("before").cloned().unwrap_or(Bson::Null),
+                after: change.get("after").cloned().unwrap_or(Bson::Null),
+            })
+        })
+        .collect()
+}
+
+fn entry_of_document(document: &Document) -> Result<AuditEntry> {
+    Ok(AuditEntry {
+        operation: AuditOperation::parse(document.get_str("operation")?)?,
+        collection: document.get_str("collection")?.to_owned(),
+        record_id: document.get_str("record_id").ok().map(str::to_owned),
+        actor: document.get_str("actor").ok().map(str::to_owned),
+        recorded_at: document.get_datetime("recorded_at")?.to_chrono(),
+        changes: changes_of(document.get("changes").unwrap_or(&Bson::Null))?,
+        latency: Duration::from_micros(document.get_i64("latency_micros")? as u64),
+    })
+}
+
+//Stream fields are strings, changes are kept as relaxed extended JSON
+fn stream_fields(entry: &AuditEntry) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("operation", entry.operation.as_str().to_owned()),
+        ("collection", entry.collection.clone()),
+        (
+            "recorded_at",
+            entry.recorded_at.timestamp_millis().to_string(),
+        ),
+        ("latency_micros", entry.latency.as_micros().to_string()),
+        (
+            "changes",
+            changes_bson(&entry.changes)
+                .into_relaxed_extjson()
+                .to_string(),
+        ),
+    ];
+    if let Some(record_id) = &entry.record_id {
+        fields.push(("record_id", record_id.clone()));
+    }
+    if let Some(actor) = &entry.actor {
+        fields.push(("actor", actor.clone()));
+    }
+    fields
+}
+
+fn entry_of_stream(fields: &HashMap<String, String>) -> Result<AuditEntry> {
+    let field = |name: &str| {
+        fields.get(name).ok_or_else(|| {
+            DatastoreError::serialization(format!("Audit stream entry is missing {}", name))
+        })
+    };
+
+    let recorded_at = field("recorded_at")?.parse::<i64>()?;
+    let changes: serde_json::Value = serde_json::from_str(field("changes")?)?;
+
+    Ok(AuditEntry {
+        operation: AuditOperation::parse(field("operation")?)?,
+        collection: field("collection")?.to_owned(),
+        record_id: fields.get("record_id").cloned(),
+        actor: fields.get("actor").cloned(),
+        recorded_at: Utc
+            .timestamp_millis_opt(recorded_at)
+            .single()
+            .ok_or_else(|| {
+                DatastoreError::serialization(format!("Invalid audit timestamp {}", recorded_at))
+            })?,
+        changes: changes_of(&Bson::try_from(changes)?)?,
+        latency: Duration::from_micros(field("latency_micros")?.parse::<u64>()?),
+    })
+}
+
+impl Datastore {
+    //Records every write made through this handle and its clones, e.g.
+    //datastore.with_audit(AuditConfig::mongo("audit_log", Duration::from_secs(90 * 86400)))
+    pub fn with_audit(mut self, config: AuditConfig) -> Self {
+        self.audit = Some(config);
+        self.audit_index = Arc::new(OnceCell::new());
+        self.audit_status = Arc::new(Mutex::new(AuditStatus::default()));
+        self
+    }
+
+    pub(crate) fn is_audited(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    pub fn audit_status(&self) -> AuditStatus {
+        match self.audit_status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    //Runs once the write is committed, a failing sink must not turn it into an error.
+    //Lost entries are counted in audit_status instead
+    pub(crate) async fn record_audit(&self, drafts: Vec<AuditDraft>, started: Instant) {
+        let count = drafts.len() as u64;
+
+        if let Err(err) = self.try_record_audit(drafts, started).await {
+            let mut status = match self.audit_status.lock() {
+                Ok(status) => status,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            status.failed_entries += count;
+            status.last_error = Some(err.to_string());
+        }
+    }
+
+    //Entries carry the clock's time, retention runs on the real one so a MockClock
+    //can't expire them early
+    async fn try_record_audit(&self, drafts: Vec<AuditDraft>, started: Instant) -> Result<()> {
+        let config = match &self.audit {
+            Some(config) if !drafts.is_empty() => config,
+            _ => return Ok(()),
+        };
+
+        let latency = started.elapsed();
+        let recorded_at = self.clock.now();
+        let entries = drafts.into_iter().map(|draft| AuditEntry {
+            operation: draft.operation,
+            collection: draft.collection,
+            record_id: draft.record_id,
+            actor: self.actor.clone(),
+            recorded_at,
+            changes: diff(draft.before.as_ref(), draft.after.as_ref()),
+            latency,
+        });
+
+        let retention_millis = config.retention.as_millis() as i64;
+
+        match &config.sink {
+            AuditSink::Mongo { collection } => {
+                self.audit_index
+                    .get_or_try_init(|| {
+                        self.database.try_ensure_ttl_index(collection, "expires_at")
+                    })
+                    .await?;
+
+                let expires_at = bson::DateTime::from_millis(
+                    bson::DateTime::now().timestamp_millis() + retention_millis,
+                );
+                let documents: Vec<Document> = entries
+                    .map(|entry| {
+                        doc! {
+                            "operation": entry.operation.as_str(),
+                            "collection": entry.collection,
+                            "record_id": entry.record_id,
+                            "actor": entry.actor,
+                            "recorded_at": bson::DateTime::from_chrono(entry.recorded_at),
+                            "changes": changes_bson(&entry.changes),
+                            "latency_micros": entry.latency.as_micros() as i64,
+                            "expires_at": expires_at,
+                        }
+                    })
+                    .collect();
+
+                let _ = self.database.try_insert_many(collection, documents).await?;
+            }
+            AuditSink::RedisStream { key } => {
+                let min_id_millis = bson::DateTime::now().timestamp_millis() - retention_millis;
+
+                for entry in entries {
+                    let _ = self
+                        .cache
+                        .try_append_stream(key, stream_fields(&entry), min_id_millis)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //Empty when auditing is off
+    pub async fn try_read_audit(&self, filter: AuditFilter) -> Result<Vec<AuditEntry>> {
+        let config = match &self.audit {
+            Some(config) => config,
+            None => return Ok(Vec::new()),
+        };
+
+        match &config.sink {
+            AuditSink::Mongo { collection } => {
+                let mut query = doc! {};
+                if let Some(table) = &filter.collection {
+                    query.insert("collection", table);
+                }
+                if let Some(record_id) = &filter.record_id {
+                    query.insert("record_id", record_id);
+                }
+                if let Some(actor) = &filter.actor {
+                    query.insert("actor", actor);
+                }
+
+                self.database
+                    .try_find_sorted(
+                        collection,
+                        query,
+                        doc! { "recorded_at": -1, "_id": -1 },
+                        filter.limit.map(|limit| limit as i64),
+                    )
+                    .await?
+                    .iter()
+                    .map(entry_of_document)
+                    .collect()
+            }
+            //Streams can't be queried by field, pages are filtered as they are read
+            AuditSink::RedisStream { key } => {
+                let limit = filter.limit.unwrap_or(usize::MAX);
+                let mut entries = Vec::new();
+                let mut before: Option<String> = None;
+
+                while entries.len() < limit {
+                    let page = self
+                        .cache
+                        .try_read_stream_rev(key, before.as_deref(), AUDIT_STREAM_PAGE)
+                        .await?;
+                    let exhausted = page.len() < AUDIT_STREAM_PAGE;
+
+                    for (entry_id, fields) in page {
+                        let entry = entry_of_stream(&fields)?;
+                        if filter.matches(&entry) && entries.len() < limit {
+                            entries.push(entry);
+                        }
+                        before = Some(entry_id);
+                    }
+
+                    if exhausted {
+                        break;
+                    }
+                }
+
+                Ok(entries)
+            }
+        }
+    }
+}
@@ -1,94 +1,117 @@
-use std::borrow::Borrow;
-
-use anyhow::Result;
-
-use bson::{doc, to_document, Document};
+use bson::{doc, Document};
+use datastore_derive::{BsonSchema, Storable};
 use serde::{Deserialize, Serialize};
 
+use crate::error::Result;
+use crate::ids::IdStrategy;
+use crate::indexes::IndexSpec;
+use crate::migrations::Migration;
+use crate::relations::{OnDelete, Relation};
+use crate::unique::UniqueKey;
+
 pub trait MongoStorable {
     type Data;
 
+    const COLLECTION: &'static str;
+
+    //Redis hash the records are cached in, also the prefix of the keys built by try_to_str.
+    //Relations pointing at a type that changes this must say so with Relation::cached_in,
+    //cascades evict the targets from there
+    const CACHE_NAMESPACE: &'static str = Self::COLLECTION;
+
+    //Cache expiry used when a write does not pass one
+    const CACHE_TTL: Option<usize> = None;
+
+    //Bumped with every migration, stored documents and cache entries carry the version
+    //they were written at
+    const SCHEMA_VERSION: u32 = 0;
+
+    //Used for records created with an empty id
+    const ID_STRATEGY: IdStrategy = IdStrategy::ObjectId;
+
+    //Deletes only set deleted_at and hide the record from reads until it is restored or
+    //purged. Cascade and set-null rules wait for the purge, restrict is checked right away
+    const SOFT_DELETE: bool = false;
+
+    //Every write that replaces or deletes a record first keeps the version it replaces in
+    //<collection>_history. Set-null cascades from other collections are not recorded
+    const HISTORY: bool = false;
+
     fn get_id(&self) -> &str;
 
-    fn get_data(&self) -> &Self::Data;
+    fn set_id(&mut self, id: String);
 
-    fn get_bookstore_id(&self) -> &str;
+    fn get_data(&self) -> &Self::Data;
 
     fn try_to_str(&self) -> Result<(String, String)>;
+
+    fn relations() -> Vec<Relation> {
+        Vec::new()
+    }
+
+    fn unique_keys() -> Vec<UniqueKey> {
+        Vec::new()
+    }
+
+    //Unique keys bring their own index, they do not need to be repeated here
+    fn indexes() -> Vec<IndexSpec> {
+        Vec::new()
+    }
+
+    //$jsonSchema installed by Datastore::try_apply_validator, None leaves the collection open
+    fn json_schema() -> Option<Document> {
+        None
+    }
+
+    //One migration per version from 1 up to SCHEMA_VERSION
+    fn migrations() -> Vec<Migration> {
+        Vec::new()
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(BsonSchema, Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+#[storable(collection = "books", validate)]
+#[storable(relation = Relation::many_to_one(
+    "bookstore",
+    "data.bookstore_id",
+    BookstoreRecord::COLLECTION,
+)
+.cached_in(BookstoreRecord::CACHE_NAMESPACE)
+.indexed("book_stores", "store_books")
+.required())]
+//Backs the $lookup joins and the on-delete rules of bookstores
+#[storable(index = IndexSpec::single("data.bookstore_id"))]
 pub struct BookRecord {
     pub _id: String,
     pub data: Book,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(BsonSchema, Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Book {
     pub name: String,
     pub author: String,
     pub bookstore_id: String,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(BsonSchema, Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+#[storable(collection = "bookstores", validate)]
+#[storable(relation = Relation::one_to_many(
+    "books",
+    BookRecord::COLLECTION,
+    "data.bookstore_id",
+)
+.cached_in(BookRecord::CACHE_NAMESPACE)
+.indexed("book_stores", "store_books")
+.on_delete(OnDelete::Cascade))]
+#[storable(unique = UniqueKey::single("data.name"))]
 pub struct BookstoreRecord {
     pub _id: String,
     pub data: Bookstore,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(BsonSchema, Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Bookstore {
     pub name: String,
     pub address: String,
     pub number: String,
 }
-
-impl MongoStorable for BookRecord {
-    type Data = Book;
-
-    fn get_id(&self) -> &str {
-        &self._id
-    }
-
-    fn get_data(&self) -> &Book {
-        &self.data
-    }
-
-    fn get_bookstore_id(&self) -> &str {
-        &self.data.bookstore_id
-    }
-
-    fn try_to_str(&self) -> Result<(String, String)> {
-        let mut key = self.get_id().to_owned();
-        key.insert_str(0, "book_");
-
-        let book_data = self.get_data();
-        let value = serde_json::to_string(&book_data)?;
-        Ok((key, value))
-    }
-}
-
-impl MongoStorable for BookstoreRecord {
-    type Data = Bookstore;
-
-    fn get_id(&self) -> &str {
-        &self._id
-    }
-
-    fn get_data(&self) -> &Bookstore {
-        &self.data
-    }
-
-    fn get_bookstore_id(&self) -> &str {
-        &self.data.name
-    }
-
-    fn try_to_str(&self) -> Result<(String, String)> {
-        let mut key = self.get_id().to_owned();
-        key.insert_str(0, "book_");
-
-        let book_data = self.get_data();
-        let value = serde_json::to_string(&book_data)?;
-        Ok((key, value))
-    }
-}
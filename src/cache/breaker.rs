@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mobc_redis::redis::RedisError;
+
+use crate::cache::redis::MobcError;
+use crate::config::CircuitBreakerConfig;
+use crate::error::DatastoreError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    //Redis is used as normal
+    Closed,
+    //Redis is skipped until the cool-down is over, reads go to Atlas and writes only
+    //remember what they would have touched
+    Open,
+    //The cool-down is over and one caller is let through to see if Redis is back
+    HalfOpen,
+}
+
+//Snapshot for monitoring, see Datastore::cache_status
+#[derive(Clone, Debug, PartialEq)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    //Cache entries written around while Redis was unavailable, dropped once it is back
+    pub pending_invalidations: usize,
+    //Too many entries were written around to track, every namespace they were in is
+    //cleared on recovery
+    pub invalidation_overflow: bool,
+    pub last_error: Option<String>,
+}
+
+//A cache entry that may be stale because a write skipped Redis. Keys are stored with the
+//handle's prefix already applied, the breaker is shared by every scoped handle
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Invalidation {
+    Field {
+        hash_key: String,
+        field: String,
+    },
+    Key(String),
+    //The relation sets of a record, whatever targets they held by the time Redis is back
+    Relation {
+        forward_key: String,
+        reverse_prefix: String,
+        composite_prefix: String,
+    },
+}
+
+//What a flush has to drop. On overflow the namespaces are cleared instead of the
+//invalidations, see RedisCache::namespaces
+pub(crate) struct Pending {
+    pub(crate) invalidations: Vec<Invalidation>,
+    pub(crate) namespaces: Vec<String>,
+    pub(crate) overflow: bool,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    //Checkouts that timed out since the last one that went through
+    consecutive_timeouts: u32,
+    opened_at: Option<Instant>,
+    //A half-open probe is out, everyone else keeps failing fast until it reports back
+    probing: bool,
+    pending: HashSet<Invalidation>,
+    //Hashes and key prefixes the pending invalidations fall under, kept past an overflow
+    namespaces: HashSet<String>,
+    overflow: bool,
+    last_error: Option<String>,
+}
+
+//Clones share their state, so one outage is seen the same way by every handle on a pool
+#[derive(Clone)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    //Of the handle the breaker was made for, an overflow clears everything under it
+    inner: Arc<Mutex<BreakerInner>>,
+}
+
+//Only failures to reach Redis count, a command Redis answered with an error does not.
+//Checkouts that timed out waiting on the pool are counted apart, see record_checkout_failure
+pub(crate) fn is_unavailable(err: &DatastoreError) -> bool {
+    match err {
+        DatastoreError::CacheUnavailable(_) => true,
+        DatastoreError::Timeout(_) => !is_pool_timeout(err),
+        _ => false,
+    }
+}
+
+fn is_pool_timeout(err: &DatastoreError) -> bool {
+    let pool_err = err.downcast_ref::<mobc::Error<RedisError>>().or_else(|| {
+        match err.downcast_ref::<MobcError>() {
+            Some(MobcError::RedisPoolError(pool_err)) => Some(pool_err),
+            _ => None,
+        }
+    });
+
+    matches!(pool_err, Some(mobc::Error::Timeout))
+}
+
+fn count_failure(inner: &mut BreakerInner, err: &DatastoreError, threshold: u32) {
+    inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+    inner.last_error = Some(err.to_string());
+    inner.probing = false;
+
+    let trips = match inner.state {
+        BreakerState::HalfOpen => true,
+        BreakerState::Closed => {
+            inner.consecutive_failures >= threshold || inner.consecutive_timeouts >= threshold
+        }
+        BreakerState::Open => false,
+    };
+    if trips {
+        inner.state = BreakerState::Open;
+        inner.opened_at = Some(Instant::now());
+    }
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Arc::new(Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                consecutive_timeouts: 0,
+                opened_at: None,
+                probing: false,
+                pending: HashSet::new(),
+                namespaces: HashSet::new(),
+                overflow: false,
+                last_error: None,
+            })),
+        }
+    }
+
+    fn with_inner<R>(&self, f: impl FnOnce(&mut BreakerInner) -> R) -> R {
+        //A panic while holding the lock leaves the counters usable, nothing to recover
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut inner)
+    }
+
+    //Whether a call may go to Redis now. Moves an open breaker whose cool-down is over
+    //to half-open and lets this one caller through as the probe
+    pub(crate) fn try_acquire(&self) -> bool {
+        let open_for = Duration::from_secs(self.config.open_seconds);
+
+        self.with_inner(|inner| match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => {
+                let cooled_down = inner
+                    .opened_at
+                    .is_none_or(|opened_at| opened_at.elapsed() >= open_for);
+                if cooled_down {
+                    inner.state = BreakerState::HalfOpen;
+                    inner.probing = true;
+                }
+                cooled_down
+            }
+            BreakerState::HalfOpen if inner.probing => false,
+            BreakerState::HalfOpen => {
+                inner.probing = true;
+                true
+            }
+        })
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.with_inner(|inner| {
+            inner.state = BreakerState::Closed;
+            inner.consecutive_failures = 0;
+            inner.consecutive_timeouts = 0;
+            inner.opened_at = None;
+            inner.probing = false;
+        })
+    }
+
+    pub(crate) fn record_failure(&self, err: &DatastoreError) {
+        let threshold = self.config.failure_threshold.max(1);
+
+        self.with_inner(|inner| count_failure(inner, err, threshold))
+    }
+
+    //A checkout from the pool that failed, it always ends a half-open probe. A timeout
+    //may just be a busy pool, but a Redis that accepts no connections looks the same.
+    //Closed breakers count timeouts once failure_threshold of them came in a row, open and
+    //half-open ones count every one. Other errors count if Redis could not be reached
+    pub(crate) fn record_checkout_failure(&self, err: &DatastoreError) {
+        let threshold = self.config.failure_threshold.max(1);
+
+        self.with_inner(|inner| {
+            let counts = match is_pool_timeout(err) {
+                true => {
+                    inner.consecutive_timeouts = inner.consecutive_timeouts.saturating_add(1);
+                    inner.state != BreakerState::Closed || inner.consecutive_timeouts >= threshold
+                }
+                false => is_unavailable(err),
+            };
+
+            match counts {
+                true => count_failure(inner, err, threshold),
+                false => inner.probing = false,
+            }
+        })
+    }
+
+    pub(crate) fn mark_stale(&self, invalidations: Vec<Invalidation>, namespaces: Vec<String>) {
+        let max_pending = self.config.max_pending_invalidations;
+
+        self.with_inner(|inner| {
+            inner.namespaces.extend(namespaces);
+            if inner.overflow {
+                return;
+            }
+            inner.pending.extend(invalidations);
+            if inner.pending.len() > max_pending {
+                inner.pending.clear();
+                inner.overflow = true;
+            }
+        })
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        self.with_inner(|inner| inner.overflow || !inner.pending.is_empty())
+    }
+
+    //Hands the pending invalidations to whoever flushes them
+    pub(crate) fn take_pending(&self) -> Pending {
+        self.with_inner(|inner| Pending {
+            invalidations: inner.pending.drain().collect(),
+            namespaces: inner.namespaces.drain().collect(),
+            overflow: std::mem::take(&mut inner.overflow),
+        })
+    }
+
+    //A flush that failed puts back what it took
+    pub(crate) fn restore_pending(&self, pending: Pending) {
+        if pending.overflow {
+            self.with_inner(|inner| {
+                inner.pending.clear();
+                inner.namespaces.extend(pending.namespaces);
+                inner.overflow = true;
+            });
+        } else {
+            self.mark_stale(pending.invalidations, pending.namespaces);
+        }
+    }
+
+    pub(crate) fn status(&self) -> BreakerStatus {
+        self.with_inner(|inner| BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            pending_invalidations: inner.pending.len(),
+            invalidation_overflow: inner.overflow,
+            last_error: inner.last_error.clone(),
+        })
+    }
+}
@@ -1,19 +1,26 @@
-use anyhow::Result;
-use bson::Document;
-use dotenv::dotenv;
 
-use mobc_redis::redis::{AsyncCommands, ToRedisArgs};
+use mobc_redis::redis::AsyncCommands;
 use mobc_redis::{redis, RedisConnectionManager};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::{to_string, Map, Value};
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{env, println};
 use thiserror::Error;
 
-use crate::book_types::{Book, BookRecord, MongoStorable};
+use crate::book_types::MongoStorable;
+use crate::cache::breaker::{is_unavailable, BreakerStatus, CircuitBreaker, Invalidation};
 use crate::cache::redis::MobcError::*;
-
+use crate::config::{DatastoreConfig, RetryPolicy};
+use crate::error::{DatastoreError, Result};
+use crate::relations::{relation_cache_ops, RelationIndex, INDEX_POPULATED_MEMBER};
+use crate::retry::{try_with_retry, Idempotency};
+use crate::tenant::TENANT_CACHE_PREFIX;
+use crate::unique::unique_cache_ops;
+
+//Named after what failed, the variants are matched on outside the crate
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum MobcError {
     #[error("could not get redis connection from pool : {0}")]
@@ -27,59 +34,370 @@ pub enum MobcError {
 }
 
 pub type MobcPool = mobc::Pool<RedisConnectionManager>;
+type MobcConnection = mobc::Connection<RedisConnectionManager>;
+
+//A single hash mutation, queued up so a whole batch can go out in one pipeline
+#[derive(Clone, Debug)]
+pub enum CacheOp {
+    Set {
+        hash_key: String,
+        field: String,
+        value: String,
+        expiry: Option<usize>,
+    },
+    Delete {
+        hash_key: String,
+        field: String,
+    },
+    //Replaces the targets of record_id in a relation index, both directions.
+    //Composites of the old and new targets are evicted along with it
+    Relate {
+        index: RelationIndex,
+        record_id: String,
+        target_ids: Vec<String>,
+    },
+    //Plain keys to drop, e.g. a cached composite read
+    Evict {
+        keys: Vec<String>,
+    },
+    //Moves record_id to value in a uniqueness index, None releases what it held
+    Unique {
+        hash_key: String,
+        owner_hash_key: String,
+        record_id: String,
+        value: Option<String>,
+    },
+}
+
+//Keys SCAN returns per round trip while clearing a prefix
+const CACHE_SCAN_COUNT: usize = 500;
+
+//Times a batch is rebuilt after a concurrent write touched the keys it read
+const CACHE_WATCH_ATTEMPTS: usize = 5;
 
-const CACHE_POOL_MAX_OPEN: u64 = 16;
-const CACHE_POOL_MAX_IDLE: u64 = 8;
-const CACHE_POOL_TIMEOUT_SECONDS: u64 = 1;
-const CACHE_POOL_EXPIRE_SECONDS: u64 = 60;
+//INCRBY that leaves a missing key missing and answers nil, see RedisCache::try_increment
+const INCREMENT_EXISTING_SCRIPT: &str =
+    "if redis.call('EXISTS', KEYS[1]) == 0 then return nil end \
+     return redis.call('INCRBY', KEYS[1], ARGV[1])";
 
+#[derive(Clone)]
 pub struct RedisCache {
     pub pool: MobcPool,
+    //Prepended to every key this handle touches, see RedisCache::scoped
+    key_prefix: Option<String>,
+    //Expiry of record hashes written without one, by namespace
+    default_ttls: Arc<HashMap<String, usize>>,
+    //Shared with every scoped handle on the pool
+    breaker: CircuitBreaker,
+    retry_policy: RetryPolicy,
+}
+
+//Reads and cascades look a T up under T::CACHE_NAMESPACE, a write caching it under any
+//other hash would never be seen by them
+pub(crate) fn check_hash_key<T>(hash_key: &str) -> Result<()>
+where
+    T: MongoStorable,
+{
+    if hash_key != T::CACHE_NAMESPACE {
+        return Err(DatastoreError::validation(format!(
+            "{} records are cached under {}, not {}",
+            T::COLLECTION,
+            T::CACHE_NAMESPACE,
+            hash_key
+        )));
+    }
+
+    Ok(())
+}
+
+//Caches value as the copy of record, along with the relation sets and unique values it
+//holds. book_stores:<book_id> holds the store ids of a book, store_books:<store_id> the
+//book ids of a store, all of it goes out in the same MULTI/EXEC as the record itself
+pub(crate) fn record_cache_ops<T>(
+    table: &str,
+    hash_key: &str,
+    record: &T,
+    value: String,
+    expiry_time: Option<usize>,
+) -> Result<Vec<CacheOp>>
+where
+    T: Serialize + MongoStorable,
+{
+    let mut cache_ops = vec![CacheOp::Set {
+        hash_key: hash_key.to_owned(),
+        field: record.get_id().to_owned(),
+        value,
+        expiry: expiry_time.or(T::CACHE_TTL),
+    }];
+
+    cache_ops.extend(relation_cache_ops(record)?);
+    cache_ops.extend(unique_cache_ops(table, record)?);
+
+    Ok(cache_ops)
 }
 
 impl RedisCache {
     pub async fn try_new() -> Result<Self> {
-        let pool = RedisCache::connect().await?;
+        let config = DatastoreConfig::from_env()?;
+
+        Self::try_with_config(&config).await
+    }
 
-        Ok(Self { pool })
+    pub async fn try_with_config(config: &DatastoreConfig) -> Result<Self> {
+        let pool = RedisCache::connect(config).await?;
+
+        Ok(Self {
+            pool,
+            key_prefix: config.key_prefix.clone(),
+            default_ttls: Arc::new(config.cache_ttls.clone()),
+            breaker: CircuitBreaker::new(config.circuit_breaker.clone()),
+            retry_policy: config.redis_retry.clone(),
+        })
     }
 
-    async fn connect() -> Result<MobcPool> {
-        dotenv().ok();
-        let redis_uri = env::var("REDIS_URI").expect("Please set the REDIS_URI environment var!");
+    //A handle on the same pool whose keys all live under prefix, e.g. tenant:acme:books.
+    //Callers keep using the plain keys, the prefix is added on the way out
+    pub(crate) fn scoped(&self, prefix: &str) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            key_prefix: Some(self.key(prefix)),
+            default_ttls: self.default_ttls.clone(),
+            breaker: self.breaker.clone(),
+            retry_policy: self.retry_policy.clone(),
+        }
+    }
 
-        let client = redis::Client::open(redis_uri).map_err(RedisClientError)?;
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    //Every command gets its connection here. While the breaker is open callers fail fast
+    //without touching the pool, the first connection after an outage drops whatever
+    //writes skipped in the meantime before anyone reads it
+    async fn try_connection(&self) -> Result<MobcConnection> {
+        if !self.breaker.try_acquire() {
+            return Err(DatastoreError::CacheUnavailable(anyhow::anyhow!(
+                "circuit breaker is open, Redis is skipped"
+            )));
+        }
+
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                let err = DatastoreError::from(err);
+                self.breaker.record_checkout_failure(&err);
+                return Err(err);
+            }
+        };
+        self.breaker.record_success();
+
+        if self.breaker.has_pending() {
+            self.try_flush_invalidations(&mut conn).await?;
+        }
+
+        Ok(conn)
+    }
+
+    //Every attempt gets its own connection. Attempts that fail to reach Redis count against
+    //the breaker, once it opens the remaining ones fail fast
+    async fn with_retry<T, F, Fut>(&self, idempotency: Idempotency, operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        try_with_retry(&self.retry_policy, idempotency, operation).await
+    }
+
+    //Counts the error against the breaker if Redis could not be reached
+    fn failed(&self, err: impl Into<DatastoreError>) -> DatastoreError {
+        let err = err.into();
+        if is_unavailable(&err) {
+            self.breaker.record_failure(&err);
+        }
+        err
+    }
+
+    async fn try_flush_invalidations(&self, conn: &mut MobcConnection) -> Result<()> {
+        let pending = self.breaker.take_pending();
+
+        let flushed = match pending.overflow {
+            true => try_clear_namespaces(conn, &pending.namespaces).await,
+            false => try_invalidate(conn, &pending.invalidations).await,
+        };
+
+        if let Err(err) = flushed {
+            self.breaker.restore_pending(pending);
+            return Err(self.failed(err));
+        }
+
+        Ok(())
+    }
+
+    //What a batch would have touched, to be dropped once Redis is back. A unique value
+    //left behind by the record only costs an Atlas check when someone else claims it
+    fn invalidations(&self, cache_ops: &[CacheOp]) -> Vec<Invalidation> {
+        let mut invalidations = Vec::new();
+
+        for cache_op in cache_ops {
+            match cache_op {
+                CacheOp::Set {
+                    hash_key, field, ..
+                }
+                | CacheOp::Delete { hash_key, field } => {
+                    invalidations.push(Invalidation::Field {
+                        hash_key: self.key(hash_key),
+                        field: field.to_owned(),
+                    });
+                }
+                CacheOp::Relate {
+                    index,
+                    record_id,
+                    target_ids,
+                } => {
+                    invalidations.push(Invalidation::Relation {
+                        forward_key: self.key(&index.forward_key(record_id)),
+                        reverse_prefix: self.key(index.reverse),
+                        composite_prefix: self.key(&index.composite_prefix()),
+                    });
+                    for target_id in target_ids {
+                        invalidations
+                            .push(Invalidation::Key(self.key(&index.reverse_key(target_id))));
+                        invalidations
+                            .push(Invalidation::Key(self.key(&index.composite_key(target_id))));
+                    }
+                }
+                CacheOp::Evict { keys } => {
+                    invalidations.extend(self.keys(keys).into_iter().map(Invalidation::Key));
+                }
+                CacheOp::Unique {
+                    hash_key,
+                    owner_hash_key,
+                    record_id,
+                    value,
+                } => {
+                    invalidations.push(Invalidation::Field {
+                        hash_key: self.key(owner_hash_key),
+                        field: record_id.to_owned(),
+                    });
+                    if let Some(value) = value {
+                        invalidations.push(Invalidation::Field {
+                            hash_key: self.key(hash_key),
+                            field: value.to_owned(),
+                        });
+                    }
+                }
+            }
+        }
+
+        invalidations
+    }
+
+    //The hashes and key families a batch touches, what gets cleared when there were too
+    //many invalidations to track. An evicted key falls under the part before its id
+    fn namespaces(&self, cache_ops: &[CacheOp]) -> Vec<String> {
+        let mut namespaces = Vec::new();
+
+        for cache_op in cache_ops {
+            match cache_op {
+                CacheOp::Set { hash_key, .. } | CacheOp::Delete { hash_key, .. } => {
+                    namespaces.push(self.key(hash_key));
+                }
+                CacheOp::Relate { index, .. } => {
+                    namespaces.push(self.key(index.forward));
+                    namespaces.push(self.key(index.reverse));
+                    namespaces.push(self.key(&index.composite_prefix()));
+                }
+                CacheOp::Evict { keys } => {
+                    namespaces.extend(keys.iter().map(|key| {
+                        let namespace = key
+                            .rsplit_once(':')
+                            .map_or(key.as_str(), |(namespace, _)| namespace);
+                        self.key(namespace)
+                    }));
+                }
+                CacheOp::Unique {
+                    hash_key,
+                    owner_hash_key,
+                    ..
+                } => {
+                    namespaces.push(self.key(hash_key));
+                    namespaces.push(self.key(owner_hash_key));
+                }
+            }
+        }
+
+        namespaces
+    }
+
+    fn key(&self, key: &str) -> String {
+        match &self.key_prefix {
+            Some(prefix) => format!("{}:{}", prefix, key),
+            None => key.to_owned(),
+        }
+    }
+
+    fn keys(&self, keys: &[String]) -> Vec<String> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    async fn connect(config: &DatastoreConfig) -> Result<MobcPool> {
+        let client = redis::Client::open(config.try_redis_uri()?).map_err(RedisClientError)?;
         let manager = RedisConnectionManager::new(client);
+        let pool = &config.redis_pool;
 
         Ok(mobc::Pool::builder()
-            .get_timeout(Some(Duration::from_secs(CACHE_POOL_TIMEOUT_SECONDS)))
-            .max_open(CACHE_POOL_MAX_OPEN)
-            .max_idle(CACHE_POOL_MAX_IDLE)
-            .max_lifetime(Some(Duration::from_secs(CACHE_POOL_EXPIRE_SECONDS)))
+            .get_timeout(Some(Duration::from_secs(pool.timeout_seconds)))
+            .max_open(pool.max_open)
+            .max_idle(pool.max_idle)
+            .max_lifetime(Some(Duration::from_secs(pool.expire_seconds)))
             .build(manager))
     }
 
-    pub async fn get_info(&self) -> Result<()> {
+    //Health checks go straight to the pool, they should see Redis as it is now. Neither
+    //the breaker nor retries are involved and the breaker's state is left alone
+    pub async fn try_ping(&self) -> Result<()> {
         let mut conn = self.pool.get().await?;
 
-        let res: String = redis::cmd("INFO")
+        let _: String = redis::cmd("PING")
             .query_async(&mut conn as &mut redis::aio::Connection)
-            .await?;
-
-        println!("{:#?}", res);
+            .await
+            .map_err(RedisCMDError)?;
 
         Ok(())
     }
 
+    //INFO <section> as key/value pairs, e.g. role=master from the replication section.
+    //Section headers and blank lines are left out
+    pub async fn try_info(&self, section: &str) -> Result<HashMap<String, String>> {
+        let mut conn = self.pool.get().await?;
+
+        let info: String = redis::cmd("INFO")
+            .arg(section)
+            .query_async(&mut conn as &mut redis::aio::Connection)
+            .await
+            .map_err(RedisCMDError)?;
+
+        Ok(info
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.to_owned(), value.trim().to_owned()))
+            .collect())
+    }
+
     pub async fn record_to_redis_map<T>(&self, record: T) -> Result<(String, Map<String, Value>)>
     where
         T: Serialize + MongoStorable,
     {
         let json_value = serde_json::to_value(&record)?;
-        let json_object = json_value.as_object().unwrap();
+        let json_object = json_value
+            .as_object()
+            .ok_or_else(|| DatastoreError::serialization("Record is not a JSON object"))?;
 
-        let key = json_object.get("_id").unwrap();
-        let key_str = to_string(key).unwrap();
+        let key = json_object
+            .get("_id")
+            .ok_or_else(|| DatastoreError::serialization("Record has no _id"))?;
+        let key_str = to_string(key)?;
 
         let redis_book_entry = (key_str, json_object.clone());
 
@@ -97,185 +415,694 @@ impl RedisCache {
     where
         T: Serialize + MongoStorable,
     {
-        let mut conn = self.pool.get().await?;
+        let value = serde_json::to_string(&record)?;
+        let cache_ops = record_cache_ops(T::COLLECTION, hash_key, &record, value, expiry_time)?;
 
-        let (field, value) = self.record_to_redis_map(record).await?;
+        self.try_apply(cache_ops).await
+    }
+
+    pub async fn try_cache_many<T>(
+        &self,
+        hash_key: &str,
+        records: Vec<T>,
+        expiry_time: Option<usize>,
+    ) -> Result<()>
+    where
+        T: Serialize + MongoStorable,
+    {
+        let mut cache_ops = Vec::new();
+
+        for record in records.iter() {
+            let value = serde_json::to_string(record)?;
+            cache_ops.extend(record_cache_ops(
+                T::COLLECTION,
+                hash_key,
+                record,
+                value,
+                expiry_time,
+            )?);
+        }
 
-        let book_id = &field[1..(field.len() - 1)];
-        let book_record: String = serde_json::to_string(&value)?;
+        self.try_apply(cache_ops).await
+    }
 
-        let _: () = conn
-            .hset(hash_key, book_id, book_record)
-            .await
-            .map_err(RedisCMDError)
-            .unwrap();
+    //Atlas has taken the write by the time the cache is updated, so Redis being unavailable
+    //does not fail it. The batch is skipped and what it touches is dropped once Redis is back
+    pub async fn try_apply(&self, cache_ops: Vec<CacheOp>) -> Result<()> {
+        if cache_ops.is_empty() {
+            return Ok(());
+        }
 
-        //Incorporate both in try_cache_one API and expire both
-        //book_stores hash will consist of many records of book_id: Vec<StoreID>
-        //store_books hash will consist of many records of store_id:Vec<BookID>
-
-        //Enum that wraps the whole argument
-        //If 1 to many, do additional steps,
-        //1 to 1 dont do anything
-
-        match hash_key {
-            "books" => {
-                // let bookstore_id = record.get_bookstore_id();
-                let mut test = book_id.to_owned();
-                test.insert(0, '"'); // Insert 'a' at the beginning
-                test.push('"'); // Append 'a' at the end
-                let existing_bookstore_ids: Option<String> =
-                    conn.hget("book_stores", &test).await.unwrap();
-                println!("{:#?} {:#?}", test, existing_bookstore_ids);
-
-                // let new_bookstore_ids = match existing_bookstore_ids {
-                //     Some(existing) => {
-                //         let mut combined = existing;
-                //         combined
-                //     }
-                //     None => (),
-                // };
-
-                // let test = vec![1, 2, 3];
-                // let vector = serde_json::to_string(&test).unwrap();
-
-                // let _: () = conn
-                //     .hset("book_store_list", 1, &vector)
-                //     .await
-                //     .map_err(RedisCMDError)
-                //     .unwrap();
+        //The batch is rebuilt from what Redis holds on every attempt, so an EXEC whose reply
+        //got lost is safe to send again
+        for _ in 0..CACHE_WATCH_ATTEMPTS {
+            let applied = self
+                .with_retry(Idempotency::Idempotent, || self.try_apply_now(&cache_ops))
+                .await;
+
+            match applied {
+                Ok(true) => return Ok(()),
+                Ok(false) => continue,
+                Err(err) if is_unavailable(&err) => break,
+                Err(err) => return Err(err),
             }
-            _ => (),
         }
 
-        // book_store_list
+        //Unreachable or still contended, what the batch touches is dropped instead
+        self.breaker
+            .mark_stale(self.invalidations(&cache_ops), self.namespaces(&cache_ops));
+        Ok(())
+    }
 
-        // store_book_list
+    //Runs the whole batch inside MULTI/EXEC so the cache never shows half of it. The index
+    //keys it reads first are WATCHed, false means one of them changed and nothing was written
+    async fn try_apply_now(&self, cache_ops: &[CacheOp]) -> Result<bool> {
+        let mut conn = self.try_connection().await?;
 
-        if let Some(expiry_seconds) = expiry_time {
-            let _ = conn
-                .expire(hash_key, expiry_seconds)
+        let watched_keys: Vec<String> = cache_ops
+            .iter()
+            .filter_map(|cache_op| match cache_op {
+                CacheOp::Relate {
+                    index, record_id, ..
+                } => Some(self.key(&index.forward_key(record_id))),
+                CacheOp::Unique { owner_hash_key, .. } => Some(self.key(owner_hash_key)),
+                _ => None,
+            })
+            .collect();
+
+        if !watched_keys.is_empty() {
+            let _: () = redis::cmd("WATCH")
+                .arg(&watched_keys)
+                .query_async(&mut conn as &mut redis::aio::Connection)
                 .await
-                .map_err(RedisCMDError)?;
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
         }
 
-        Ok(())
+        let pipeline = match self.try_build_pipeline(&mut conn, cache_ops).await {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                //The connection goes back to the pool, it must not carry the WATCH along
+                let _: redis::RedisResult<()> = redis::cmd("UNWATCH")
+                    .query_async(&mut conn as &mut redis::aio::Connection)
+                    .await;
+                return Err(err);
+            }
+        };
+
+        //EXEC replies nil when a watched key changed since the WATCH
+        let executed: Option<()> = pipeline
+            .query_async(&mut conn as &mut redis::aio::Connection)
+            .await
+            .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+        Ok(executed.is_some())
     }
 
-    pub async fn try_cache_many<T>(&self, records: Vec<T>, expiry_time: Option<usize>) -> Result<()>
-    where
-        T: Serialize + MongoStorable,
-    {
-        let mut conn = self.pool.get().await?;
+    async fn try_build_pipeline(
+        &self,
+        conn: &mut MobcConnection,
+        cache_ops: &[CacheOp],
+    ) -> Result<redis::Pipeline> {
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+
+        //Index membership as it will be once the earlier ops of this batch are applied
+        let mut pending_members: HashMap<String, Vec<String>> = HashMap::new();
+        let mut pending_values: HashMap<(String, String), Option<String>> = HashMap::new();
+
+        for cache_op in cache_ops.iter() {
+            match cache_op {
+                CacheOp::Set {
+                    hash_key,
+                    field,
+                    value,
+                    expiry,
+                } => {
+                    let expiry = expiry.or_else(|| self.default_ttls.get(hash_key).copied());
+                    let hash_key = self.key(hash_key);
+                    pipeline.hset(&hash_key, field, value).ignore();
+                    if let Some(expiry_seconds) = expiry {
+                        pipeline.expire(&hash_key, expiry_seconds).ignore();
+                    }
+                }
+                CacheOp::Delete { hash_key, field } => {
+                    pipeline.hdel(self.key(hash_key), field).ignore();
+                }
+                CacheOp::Relate {
+                    index,
+                    record_id,
+                    target_ids,
+                } => {
+                    let forward_key = self.key(&index.forward_key(record_id));
+
+                    let current_ids = match pending_members.get(&forward_key) {
+                        Some(current_ids) => current_ids.clone(),
+                        None => without_marker(
+                            conn.smembers::<_, Vec<String>>(&forward_key)
+                                .await
+                                .map_err(|err| self.failed(RedisCMDError(err)))?,
+                        ),
+                    };
+
+                    for stale_id in current_ids.iter().filter(|id| !target_ids.contains(id)) {
+                        pipeline
+                            .srem(self.key(&index.reverse_key(stale_id)), record_id)
+                            .ignore();
+                    }
+
+                    let composite_keys: Vec<String> = current_ids
+                        .iter()
+                        .chain(target_ids.iter())
+                        .map(|target_id| self.key(&index.composite_key(target_id)))
+                        .collect();
+
+                    if !composite_keys.is_empty() {
+                        pipeline.del(composite_keys).ignore();
+                    }
+
+                    //The record's own keys are all of them, its forward set is always complete
+                    pipeline.del(&forward_key).ignore();
+                    pipeline.sadd(&forward_key, INDEX_POPULATED_MEMBER).ignore();
+
+                    if !target_ids.is_empty() {
+                        pipeline.sadd(&forward_key, target_ids).ignore();
+                    }
+
+                    for target_id in target_ids.iter() {
+                        pipeline
+                            .sadd(self.key(&index.reverse_key(target_id)), record_id)
+                            .ignore();
+                    }
+
+                    pending_members.insert(forward_key, target_ids.clone());
+                }
+                CacheOp::Evict { keys } => {
+                    if !keys.is_empty() {
+                        pipeline.del(self.keys(keys)).ignore();
+                    }
+                }
+                CacheOp::Unique {
+                    hash_key,
+                    owner_hash_key,
+                    record_id,
+                    value,
+                } => {
+                    let hash_key = self.key(hash_key);
+                    let owner_hash_key = self.key(owner_hash_key);
+                    let owner = (owner_hash_key.clone(), record_id.to_owned());
+
+                    let current_value = match pending_values.get(&owner) {
+                        Some(current_value) => current_value.clone(),
+                        None => conn
+                            .hget::<_, _, Option<String>>(&owner_hash_key, record_id)
+                            .await
+                            .map_err(|err| self.failed(RedisCMDError(err)))?,
+                    };
+
+                    if let Some(stale_value) = current_value.filter(|stale| Some(stale) != value.as_ref()) {
+                        pipeline.hdel(&hash_key, stale_value).ignore();
+                    }
+
+                    match value {
+                        Some(value) => {
+                            pipeline.hset(&hash_key, value, record_id).ignore();
+                            pipeline.hset(&owner_hash_key, record_id, value).ignore();
+                        }
+                        None => {
+                            pipeline.hdel(&owner_hash_key, record_id).ignore();
+                        }
+                    }
+
+                    pending_values.insert(owner, value.clone());
+                }
+            }
+        }
+
+        Ok(pipeline)
+    }
+
+    pub async fn try_read(&self, hash_key: &str, record_id: &str) -> Result<String> {
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let read_res: String = conn
+                .hget(self.key(hash_key), record_id)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
 
-        let redis_kv_records: Vec<(String, String)> = records
+            Ok(read_res)
+        })
+        .await
+    }
+
+    //Union of the relation sets of every given id, e.g. all store ids of a list of books
+    pub async fn try_index_members(&self, index: &str, ids: Vec<&str>) -> Result<Vec<String>> {
+        let keys: &Vec<String> = &ids
             .iter()
-            .map(|record| (record.try_to_str().unwrap()))
+            .map(|id| self.key(&format!("{}:{}", index, id)))
             .collect();
 
-        let _: () = redis::cmd("MSET")
-            .arg(redis_kv_records)
-            .query_async(&mut conn as &mut redis::aio::Connection)
-            .await?;
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
 
-        Ok(())
+            let members: Vec<String> = redis::cmd("SUNION")
+                .arg(keys)
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(without_marker(members))
+        })
+        .await
     }
 
-    pub async fn try_read<T>(&self, record_id: &str) -> Result<String>
-    where
-        T: for<'de> Deserialize<'de>,
-    {
-        let mut conn = self.pool.get().await?;
+    //Same union, None unless every one of the sets is known to be complete. A set only
+    //holds every member once it was written whole, see try_rebuild_index
+    pub async fn try_populated_members(
+        &self,
+        index: &str,
+        ids: Vec<&str>,
+    ) -> Result<Option<Vec<String>>> {
+        let keys: &Vec<String> = &ids
+            .iter()
+            .map(|id| self.key(&format!("{}:{}", index, id)))
+            .collect();
 
-        let read_res: String = conn.hget("books", record_id).await.map_err(RedisCMDError)?;
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let mut pipeline = redis::pipe();
+            for key in keys {
+                pipeline.sismember(key, INDEX_POPULATED_MEMBER);
+            }
+            let populated: Vec<bool> = pipeline
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
 
-        Ok(read_res)
+            if populated.contains(&false) {
+                return Ok(None);
+            }
+
+            let members: Vec<String> = redis::cmd("SUNION")
+                .arg(keys)
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(Some(without_marker(members)))
+        })
+        .await
     }
 
-    pub async fn try_read_many<T, U>(&self, ids: Vec<String>) -> Result<()>
+    //Replaces the relation sets of the given ids with the members load reads from Atlas and
+    //marks them complete. The sets are WATCHed before load runs, a write landing on any of
+    //them in the meantime leaves all of them as they were
+    pub async fn try_rebuild_index<Fut>(&self, index: &str, ids: Vec<&str>, load: Fut) -> Result<()>
     where
-        T: for<'de> Deserialize<'de> + std::fmt::Debug,
+        Fut: Future<Output = Result<HashMap<String, Vec<String>>>>,
     {
-        let mut conn = self.pool.get().await?;
+        let keys: Vec<String> = ids
+            .iter()
+            .map(|id| self.key(&format!("{}:{}", index, id)))
+            .collect();
 
-        let ids: Vec<String> = ids.iter().map(|id| format!("book_{}", id)).collect(); //Sure we still want this?
+        let mut conn = self.try_connection().await?;
 
-        let values: Vec<String> = conn.mget(ids).await.map_err(RedisCMDError)?;
+        let _: () = redis::cmd("WATCH")
+            .arg(&keys)
+            .query_async(&mut conn as &mut redis::aio::Connection)
+            .await
+            .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+        let mut members = match load.await {
+            Ok(members) => members,
+            Err(err) => {
+                //The connection goes back to the pool, it must not carry the WATCH along
+                let _: redis::RedisResult<()> = redis::cmd("UNWATCH")
+                    .query_async(&mut conn as &mut redis::aio::Connection)
+                    .await;
+                return Err(err);
+            }
+        };
 
-        // for value in values {
-        //     let test: T = serde_json::from_str(value.as_str())?;
-        // }
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
 
-        // let arr: Vec<Book> = values
-        //     .iter()
-        //     .map(|value| serde_json::from_str(value).unwrap())
-        //     .collect();
+        for (id, key) in ids.iter().zip(keys.iter()) {
+            pipeline.del(key).ignore();
+            pipeline.sadd(key, INDEX_POPULATED_MEMBER).ignore();
 
-        // let vec: Vec<BookRecord> = arr
-        //     .into_iter()
-        //     .map(|book: Book| BookRecord {
-        //         _id: "123".to_owned(),
-        //         data: book,
-        //     })
-        //     .collect();
+            match members.remove(*id) {
+                Some(set_members) if !set_members.is_empty() => {
+                    pipeline.sadd(key, set_members).ignore();
+                }
+                _ => (),
+            }
+        }
 
-        // println!("read_many_res: {:#?}", vec);
+        //EXEC replies nil when a watched set changed, the next read rebuilds it again
+        let _: Option<()> = pipeline
+            .query_async(&mut conn as &mut redis::aio::Connection)
+            .await
+            .map_err(|err| self.failed(RedisCMDError(err)))?;
 
-        // Ok(read_many_res);
         Ok(())
     }
 
-    pub async fn try_read_all(&self, table_name: &str) -> Result<Vec<String>> {
-        let mut conn = self.pool.get().await?;
-        let prefix = "book_";
-        let keys: Vec<String> = conn
-            .keys(format!("{}*", prefix))
-            .await
-            .map_err(RedisCMDError)?;
-        let values: Vec<String> = conn.mget(keys).await.map_err(RedisCMDError)?;
-        Ok(values)
+    pub async fn try_read_fields(
+        &self,
+        hash_key: &str,
+        fields: Vec<String>,
+    ) -> Result<Vec<Option<String>>> {
+        if fields.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let fields = &fields;
+
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let values: Vec<Option<String>> = redis::cmd("HMGET")
+                .arg(self.key(hash_key))
+                .arg(fields)
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(values)
+        })
+        .await
+    }
+
+    pub async fn try_get(&self, key: &str) -> Result<Option<String>> {
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let value: Option<String> = conn
+                .get(self.key(key))
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(value)
+        })
+        .await
+    }
+
+    //INCRBY on a counter that exists, returns the value after the increment. None when the
+    //key is gone, e.g. after a flush or an eviction, so the caller can seed it again instead
+    //of counting from 0. Only tried again if the command never reached Redis, a lost reply
+    //would otherwise skip a block of values
+    pub async fn try_increment(&self, key: &str, by: u64) -> Result<Option<u64>> {
+        let key = &self.key(key);
+
+        self.with_retry(Idempotency::NonIdempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let value: Option<u64> = redis::cmd("EVAL")
+                .arg(INCREMENT_EXISTING_SCRIPT)
+                .arg(1)
+                .arg(key)
+                .arg(by)
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(value)
+        })
+        .await
+    }
+
+    //SET NX, a counter someone else seeded first is left alone
+    pub async fn try_seed_counter(&self, key: &str, start: u64) -> Result<()> {
+        let key = &self.key(key);
+
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let _: bool = conn
+                .set_nx(key, start)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn try_set(
+        &self,
+        key: &str,
+        value: String,
+        expiry_time: Option<usize>,
+    ) -> Result<()> {
+        let (key, value) = (&self.key(key), &value);
+
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            match expiry_time {
+                Some(expiry_seconds) => conn.set_ex::<_, _, ()>(key, value, expiry_seconds).await,
+                None => conn.set::<_, _, ()>(key, value).await,
+            }
+            .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    //XADD with MINID trimming, entries with ids older than min_id_millis are dropped.
+    //Trimming is approximate, Redis only drops whole nodes of the stream
+    pub async fn try_append_stream(
+        &self,
+        key: &str,
+        fields: Vec<(&str, String)>,
+        min_id_millis: i64,
+    ) -> Result<String> {
+        let mut command = redis::cmd("XADD");
+        command
+            .arg(self.key(key))
+            .arg("MINID")
+            .arg("~")
+            .arg(min_id_millis)
+            .arg("*");
+        for (field, value) in fields {
+            command.arg(field).arg(value);
+        }
+        let command = &command;
+
+        //Every XADD adds an entry, a lost reply must not add a second one
+        self.with_retry(Idempotency::NonIdempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let entry_id: String = command
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(entry_id)
+        })
+        .await
+    }
+
+    //XREVRANGE, newest entry first. Pages continue below the last id seen, exclusive
+    pub async fn try_read_stream_rev(
+        &self,
+        key: &str,
+        before: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<(String, HashMap<String, String>)>> {
+        let end = match before {
+            Some(entry_id) => format!("({}", entry_id),
+            None => "+".to_owned(),
+        };
+
+        let mut command = redis::cmd("XREVRANGE");
+        command
+            .arg(self.key(key))
+            .arg(end)
+            .arg("-")
+            .arg("COUNT")
+            .arg(count);
+        let command = &command;
+
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            let entries: Vec<(String, HashMap<String, String>)> = command
+                .query_async(&mut conn as &mut redis::aio::Connection)
+                .await
+                .map_err(|err| self.failed(RedisCMDError(err)))?;
+
+            Ok(entries)
+        })
+        .await
     }
 
-    pub async fn try_update_many<T>(&self, updated_records: Vec<T>) -> Result<()>
+    pub async fn try_update_many<T>(
+        &self,
+        hash_key: &str,
+        updated_records: Vec<T>,
+        expiry_time: Option<usize>,
+    ) -> Result<()>
     where
         T: Serialize + MongoStorable,
     {
-        for record in updated_records.into_iter() {
-            let _ = self.try_cache_one("books", record, None).await.unwrap();
-        }
-
-        Ok(())
+        self.try_cache_many(hash_key, updated_records, expiry_time)
+            .await
     }
 
     pub async fn try_delete(&self, hash_key: &str, record_id: &str) -> Result<()> {
-        let mut conn = self.pool.get().await?;
+        self.try_delete_many(hash_key, vec![record_id.to_owned()])
+            .await
+    }
+
+    //Goes through try_apply, so a delete Redis misses is still dropped once it is back
+    pub async fn try_delete_many(&self, hash_key: &str, delete_ids: Vec<String>) -> Result<()> {
+        let cache_ops = delete_ids
+            .into_iter()
+            .map(|record_id| CacheOp::Delete {
+                hash_key: hash_key.to_owned(),
+                field: record_id,
+            })
+            .collect();
+
+        self.try_apply(cache_ops).await
+    }
+
+    //A scoped handle only drops the keys under its prefix, everything else stays. The root
+    //handle drops everything but the tenants' keys
+    pub async fn try_clear_cache(&self) -> Result<()> {
+        self.with_retry(Idempotency::Idempotent, || async move {
+            let mut conn = self.try_connection().await?;
+
+            try_clear_prefix(&mut conn, self.key_prefix.as_deref())
+                .await
+                .map_err(|err| self.failed(err))
+        })
+        .await
+    }
+}
+
+//Every key under the prefix. Without one every key outside the tenants, they are
+//cleared through their own handles
+async fn try_clear_prefix(conn: &mut MobcConnection, prefix: Option<&str>) -> Result<()> {
+    match prefix {
+        Some(prefix) => try_delete_prefixed(conn, prefix).await,
+        None => try_delete_untenanted(conn).await,
+    }
+}
 
-        let _ = conn
-            .hdel(hash_key, record_id)
+//Each namespace is a hash or a family of keys under it, never the whole server
+async fn try_clear_namespaces(conn: &mut MobcConnection, namespaces: &[String]) -> Result<()> {
+    for namespace in namespaces {
+        let _: () = conn.del(namespace).await.map_err(RedisCMDError)?;
+        try_delete_prefixed(conn, namespace).await?;
+    }
+
+    Ok(())
+}
+
+//Every key under prefix, a SCAN round trip at a time
+async fn try_delete_prefixed(conn: &mut MobcConnection, prefix: &str) -> Result<()> {
+    let mut cursor: u64 = 0;
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}:*", prefix))
+            .arg("COUNT")
+            .arg(CACHE_SCAN_COUNT)
+            .query_async(conn as &mut redis::aio::Connection)
             .await
             .map_err(RedisCMDError)?;
-        Ok(())
-    }
 
-    pub async fn try_delete_many(&self, delete_ids: Vec<String>) -> Result<()> {
-        //Double check input
-        let mut conn = self.pool.get().await?;
+        if !keys.is_empty() {
+            let _: () = conn.del(keys).await.map_err(RedisCMDError)?;
+        }
 
-        let delete_keys: Vec<String> = delete_ids.iter().map(|id| format!("book_{}", id)).collect();
+        match next_cursor {
+            0 => return Ok(()),
+            _ => cursor = next_cursor,
+        }
+    }
+}
 
-        let _: () = conn.del(delete_keys).await?;
+//One MULTI/EXEC dropping every invalidated entry. Relation sets are read first, the
+//targets they hold by now have their reverse sets and composites dropped with them
+async fn try_invalidate(conn: &mut MobcConnection, invalidations: &[Invalidation]) -> Result<()> {
+    let mut pipeline = redis::pipe();
+    pipeline.atomic();
 
-        Ok(())
+    for invalidation in invalidations {
+        match invalidation {
+            Invalidation::Field { hash_key, field } => {
+                pipeline.hdel(hash_key, field).ignore();
+            }
+            Invalidation::Key(key) => {
+                pipeline.del(key).ignore();
+            }
+            Invalidation::Relation {
+                forward_key,
+                reverse_prefix,
+                composite_prefix,
+            } => {
+                let target_ids: Vec<String> =
+                    without_marker(conn.smembers(forward_key).await.map_err(RedisCMDError)?);
+                for target_id in target_ids {
+                    pipeline
+                        .del(format!("{}:{}", reverse_prefix, target_id))
+                        .ignore();
+                    pipeline
+                        .del(format!("{}:{}", composite_prefix, target_id))
+                        .ignore();
+                }
+                pipeline.del(forward_key).ignore();
+            }
+        }
     }
 
-    pub async fn try_clear_cache(&self) -> Result<()> {
-        let mut conn = self.pool.get().await?;
+    let _: () = pipeline
+        .query_async(conn as &mut redis::aio::Connection)
+        .await
+        .map_err(RedisCMDError)?;
 
-        let _: () = redis::cmd("FLUSHALL")
-            .query_async(&mut conn as &mut redis::aio::Connection)
-            .await?;
+    Ok(())
+}
 
-        Ok(())
+//Relation set members without the marker telling the set is complete
+fn without_marker(mut members: Vec<String>) -> Vec<String> {
+    members.retain(|member| member != INDEX_POPULATED_MEMBER);
+    members
+}
+
+//Same SCAN as try_delete_prefixed over the whole keyspace, tenant keys are skipped
+async fn try_delete_untenanted(conn: &mut MobcConnection) -> Result<()> {
+    let tenant_prefix = format!("{}:", TENANT_CACHE_PREFIX);
+    let mut cursor: u64 = 0;
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(CACHE_SCAN_COUNT)
+            .query_async(conn as &mut redis::aio::Connection)
+            .await
+            .map_err(RedisCMDError)?;
+
+        let keys: Vec<String> = keys
+            .into_iter()
+            .filter(|key| !key.starts_with(&tenant_prefix))
+            .collect();
+        if !keys.is_empty() {
+            let _: () = conn.del(keys).await.map_err(RedisCMDError)?;
+        }
+
+        match next_cursor {
+            0 => return Ok(()),
+            _ => cursor = next_cursor,
+        }
     }
 }
 
@@ -283,7 +1110,7 @@ impl RedisCache {
 mod tests {
     use super::*;
     use crate::{
-        book_types::{Book, BookRecord, Bookstore, BookstoreRecord},
+        book_types::{Book, BookRecord},
         cache::redis::RedisCache,
     };
 
@@ -305,7 +1132,7 @@ mod tests {
         cache.try_cache_one("books", book, None).await.unwrap();
 
         let cache_read_res: String = cache
-            .try_read::<BookRecord>("b5e276f4924b4235d96e5d35b872b012")
+            .try_read("books", "b5e276f4924b4235d96e5d35b872b012")
             .await
             .unwrap();
 
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use bson::{from_document, to_document, Document};
+use chrono::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::book_types::MongoStorable;
+use crate::error::Result;
+use crate::migrations::try_upgrade;
+use crate::soft_delete::ReadOptions;
+use crate::timestamps::{ModifiedCursor, Timestamped};
+use crate::{Cache, Datastore, Upsert, WriteOp, WriteOutcome};
+
+//Typed handle over one record type. The Atlas collection and the cache hash come from
+//T, so a BookRecord can only ever be written to books and read back as a BookRecord
+pub struct Collection<'a, T> {
+    datastore: &'a Datastore,
+    read_options: ReadOptions,
+    record_type: PhantomData<fn() -> T>,
+}
+
+impl Datastore {
+    //let books = datastore.collection::<BookRecord>();
+    //let book = books.try_read(id).await?;
+    pub fn collection<T>(&self) -> Collection<'_, T>
+    where
+        T: MongoStorable,
+    {
+        Collection {
+            datastore: self,
+            read_options: ReadOptions::default(),
+            record_type: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Collection<'a, T>
+where
+    T: MongoStorable + Serialize + DeserializeOwned + Clone,
+{
+    pub fn name(&self) -> &'static str {
+        T::COLLECTION
+    }
+
+    pub fn cache_namespace(&self) -> &'static str {
+        T::CACHE_NAMESPACE
+    }
+
+    //A handle whose reads see soft-deleted records as well
+    pub fn include_deleted(&self) -> Self {
+        Collection {
+            datastore: self.datastore,
+            read_options: ReadOptions::include_deleted(),
+            record_type: PhantomData,
+        }
+    }
+
+    pub async fn try_create_one(&self, record: T, cache_expiry: Option<usize>) -> Result<T> {
+        self.datastore
+            .try_create_one(T::COLLECTION, T::CACHE_NAMESPACE, record, cache_expiry)
+            .await
+    }
+
+    pub async fn try_create_many(
+        &self,
+        records: Vec<T>,
+        cache_expiry: Option<usize>,
+    ) -> Result<Vec<T>> {
+        self.datastore
+            .try_create_many(T::COLLECTION, T::CACHE_NAMESPACE, records, cache_expiry)
+            .await
+    }
+
+    pub async fn try_read(&self, record_id: &str) -> Result<Cache<T>> {
+        self.datastore
+            .try_read_with::<T>(T::COLLECTION, record_id, self.read_options)
+            .await
+    }
+
+    pub async fn try_read_many(&self, ids: Vec<String>) -> Result<Vec<T>> {
+        let documents = self
+            .datastore
+            .try_read_many_with(T::COLLECTION, ids, self.read_options)
+            .await?;
+        from_documents(documents)
+    }
+
+    pub async fn try_read_all(&self) -> Result<Vec<T>> {
+        let documents = self
+            .datastore
+            .try_read_all_with(T::COLLECTION, self.read_options)
+            .await?;
+        from_documents(documents)
+    }
+
+    pub async fn try_read_timestamped(&self, record_id: &str) -> Result<Timestamped<T>> {
+        self.datastore
+            .try_read_timestamped_with::<T>(record_id, self.read_options)
+            .await
+    }
+
+    pub async fn try_read_modified_since(
+        &self,
+        since: impl Into<ModifiedCursor>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Timestamped<T>>> {
+        self.datastore
+            .try_read_modified_since_with::<T>(since, limit, self.read_options)
+            .await
+    }
+
+    pub async fn try_update_one(&self, record: T, cache_expiry: Option<usize>) -> Result<T> {
+        self.datastore
+            .try_update_one(T::COLLECTION, T::CACHE_NAMESPACE, record, cache_expiry)
+            .await
+    }
+
+    pub async fn try_update_many(&self, records: Vec<T>) -> Result<Vec<T>> {
+        let mut update_map = HashMap::new();
+        for record in records.iter() {
+            update_map.insert(record.get_id().to_owned(), to_document(record)?);
+        }
+
+        let _ = self
+            .datastore
+            .try_update_many::<T>(T::COLLECTION, T::CACHE_NAMESPACE, update_map)
+            .await?;
+
+        Ok(records)
+    }
+
+    pub async fn try_upsert_one(
+        &self,
+        record: T,
+        set_on_insert: Option<Document>,
+        cache_expiry: Option<usize>,
+    ) -> Result<Upsert<T>> {
+        self.datastore
+            .try_upsert_one(
+                T::COLLECTION,
+                T::CACHE_NAMESPACE,
+                record,
+                set_on_insert,
+                cache_expiry,
+            )
+            .await
+    }
+
+    pub async fn try_upsert_many(
+        &self,
+        records: Vec<T>,
+        set_on_insert: Option<Document>,
+        cache_expiry: Option<usize>,
+    ) -> Result<Vec<Upsert<T>>> {
+        self.datastore
+            .try_upsert_many(
+                T::COLLECTION,
+                T::CACHE_NAMESPACE,
+                records,
+                set_on_insert,
+                cache_expiry,
+            )
+            .await
+    }
+
+    pub async fn bulk_write(
+        &self,
+        operations: Vec<WriteOp<T>>,
+        ordered: bool,
+        cache_expiry: Option<usize>,
+    ) -> Result<Vec<WriteOutcome>> {
+        self.datastore
+            .bulk_write(
+                T::COLLECTION,
+                T::CACHE_NAMESPACE,
+                operations,
+                ordered,
+                cache_expiry,
+            )
+            .await
+    }
+
+    pub async fn try_delete(&self, record_id: &str) -> Result<()> {
+        self.datastore
+            .try_delete::<T>(T::COLLECTION, record_id)
+            .await
+    }
+
+    pub async fn try_delete_many(&self, delete_ids: Vec<String>) -> Result<()> {
+        self.datastore
+            .try_delete_many::<T>(T::COLLECTION, delete_ids)
+            .await
+    }
+
+    pub async fn try_restore(&self, record_id: &str, cache_expiry: Option<usize>) -> Result<T> {
+        self.datastore
+            .try_restore::<T>(record_id, cache_expiry)
+            .await
+    }
+
+    pub async fn try_purge_deleted(&self, older_than: Duration) -> Result<u64> {
+        self.datastore.try_purge_deleted::<T>(older_than).await
+    }
+}
+
+//Outdated documents are upgraded in memory, try_read and try_migrate write them back
+fn from_documents<T>(documents: Vec<Document>) -> Result<Vec<T>>
+where
+    T: MongoStorable + DeserializeOwned,
+{
+    documents
+        .into_iter()
+        .map(|mut document| {
+            let _ = try_upgrade::<T>(&mut document)?;
+            Ok(from_document::<T>(document)?)
+        })
+        .collect()
+}
@@ -0,0 +1,86 @@
+use bson::{from_bson, from_document};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::book_types::{BookRecord, BookstoreRecord, MongoStorable};
+use crate::error::{DatastoreError, Result};
+use crate::relations::relation_to;
+use crate::{Cache, CacheState, Datastore};
+
+//A record together with its related records, e.g. a bookstore with its books
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Composite<P, C> {
+    pub parent: P,
+    pub children: Vec<C>,
+}
+
+impl Datastore {
+    //Parent and children come back from a single $lookup aggregation. The result is cached
+    //as one Redis key, which every write to the parent or to a member child evicts. Only
+    //relations the parent declares as one-to-many with an index can be kept in sync, any
+    //other relation is still served, just never cached
+    pub async fn try_read_composite<Parent, Child>(
+        &self,
+        parent_id: &str,
+        cache_expiry: Option<usize>,
+    ) -> Result<Cache<Composite<Parent, Child>>>
+    where
+        Parent: MongoStorable + Serialize + DeserializeOwned,
+        Child: MongoStorable + Serialize + DeserializeOwned,
+    {
+        let relation = relation_to::<Parent>(Child::COLLECTION)?;
+
+        let composite_key = relation
+            .index
+            .filter(|_| !relation.holds_keys())
+            .map(|index| index.composite_key(parent_id));
+
+        if let Some(composite_key) = composite_key.as_deref() {
+            //A cache outage or an unreadable entry falls through to Atlas
+            if let Ok(Some(cached_value)) = self.cache.try_get(composite_key).await {
+                if let Ok(composite) = serde_json::from_str(&cached_value) {
+                    return Ok(Cache {
+                        state: CacheState::Hit,
+                        data: composite,
+                    });
+                }
+            }
+        }
+
+        let mut document = self
+            .database
+            .try_lookup_composite(Parent::COLLECTION, &relation, parent_id)
+            .await?
+            .ok_or_else(|| DatastoreError::not_found(Parent::COLLECTION, parent_id))?;
+
+        let children = match document.remove(relation.name) {
+            Some(children) => from_bson::<Vec<Child>>(children)?,
+            None => Vec::new(),
+        };
+
+        let composite = Composite {
+            parent: from_document::<Parent>(document)?,
+            children,
+        };
+
+        if let Some(composite_key) = composite_key.as_deref() {
+            let _ = self
+                .cache
+                .try_set(composite_key, serde_json::to_string(&composite)?, cache_expiry)
+                .await;
+        }
+
+        Ok(Cache {
+            state: CacheState::Miss,
+            data: composite,
+        })
+    }
+
+    pub async fn try_read_bookstore_with_books(
+        &self,
+        bookstore_id: &str,
+        cache_expiry: Option<usize>,
+    ) -> Result<Cache<Composite<BookstoreRecord, BookRecord>>> {
+        self.try_read_composite::<BookstoreRecord, BookRecord>(bookstore_id, cache_expiry)
+            .await
+    }
+}
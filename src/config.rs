@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::{env, fs};
+
+use dotenv::dotenv;
+use mongodb::options::{Acknowledgment, ReadConcern, WriteConcern};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::error::Result;
+
+pub const DEFAULT_CACHE_POOL_MAX_OPEN: u64 = 16;
+pub const DEFAULT_CACHE_POOL_MAX_IDLE: u64 = 8;
+pub const DEFAULT_CACHE_POOL_TIMEOUT_SECONDS: u64 = 1;
+pub const DEFAULT_CACHE_POOL_EXPIRE_SECONDS: u64 = 60;
+pub const DEFAULT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_BREAKER_OPEN_SECONDS: u64 = 30;
+pub const DEFAULT_BREAKER_MAX_PENDING_INVALIDATIONS: usize = 10_000;
+pub const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_INITIAL_BACKOFF_MS: u64 = 50;
+pub const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 1000;
+pub const DEFAULT_HEALTH_DEGRADED_LATENCY_MS: u64 = 250;
+pub const DEFAULT_HEALTH_TIMEOUT_MS: u64 = 2000;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfigError {
+    #[error("missing {key}, set it in the config or through {env_var}")]
+    Missing {
+        key: &'static str,
+        env_var: &'static str,
+    },
+    #[error("invalid value {value:?} for {key}: {reason}")]
+    Invalid {
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("unsupported config file {0}, use .toml, .yaml or .yml")]
+    UnsupportedFormat(String),
+}
+
+//Unset values are left to the driver's defaults
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoPoolConfig {
+    pub max_pool_size: Option<u32>,
+    pub min_pool_size: Option<u32>,
+    pub connect_timeout_seconds: Option<u64>,
+    pub server_selection_timeout_seconds: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisPoolConfig {
+    pub max_open: u64,
+    pub max_idle: u64,
+    //How long a caller waits for a free connection
+    pub timeout_seconds: u64,
+    //Connections are recycled after this long
+    pub expire_seconds: u64,
+}
+
+impl Default for RedisPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_open: DEFAULT_CACHE_POOL_MAX_OPEN,
+            max_idle: DEFAULT_CACHE_POOL_MAX_IDLE,
+            timeout_seconds: DEFAULT_CACHE_POOL_TIMEOUT_SECONDS,
+            expire_seconds: DEFAULT_CACHE_POOL_EXPIRE_SECONDS,
+        }
+    }
+}
+
+//When Redis is considered down, see BreakerState
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    //Consecutive failures to reach Redis before the breaker opens
+    pub failure_threshold: u32,
+    //How long an open breaker waits before letting a probe through
+    pub open_seconds: u64,
+    //Past this many, stale entries are no longer tracked one by one and the whole cache
+    //is cleared once Redis is back
+    pub max_pending_invalidations: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: DEFAULT_BREAKER_FAILURE_THRESHOLD,
+            open_seconds: DEFAULT_BREAKER_OPEN_SECONDS,
+            max_pending_invalidations: DEFAULT_BREAKER_MAX_PENDING_INVALIDATIONS,
+        }
+    }
+}
+
+//How often a failed Mongo or Redis call is tried again, see crate::retry for which
+//failures count. The wait doubles after every attempt, up to max_backoff_ms
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    //Including the first one, 1 turns retries off
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    //Waits a random time up to the backoff instead, so callers that failed together
+    //do not all come back at once
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            initial_backoff_ms: DEFAULT_RETRY_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_RETRY_MAX_BACKOFF_MS,
+            jitter: true,
+        }
+    }
+}
+
+//Thresholds of Datastore::health
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    //A dependency answering slower than this is reported as degraded
+    pub degraded_latency_ms: u64,
+    //How long a probe waits for an answer before the dependency counts as unhealthy
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            degraded_latency_ms: DEFAULT_HEALTH_DEGRADED_LATENCY_MS,
+            timeout_ms: DEFAULT_HEALTH_TIMEOUT_MS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadConcernLevel {
+    Local,
+    Available,
+    Majority,
+    Linearizable,
+    Snapshot,
+}
+
+impl ReadConcernLevel {
+    pub(crate) fn read_concern(&self) -> ReadConcern {
+        match self {
+            ReadConcernLevel::Local => ReadConcern::local(),
+            ReadConcernLevel::Available => ReadConcern::available(),
+            ReadConcernLevel::Majority => ReadConcern::majority(),
+            ReadConcernLevel::Linearizable => ReadConcern::linearizable(),
+            ReadConcernLevel::Snapshot => ReadConcern::snapshot(),
+        }
+    }
+}
+
+impl FromStr for ReadConcernLevel {
+    type Err = ConfigError;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "local" => Ok(ReadConcernLevel::Local),
+            "available" => Ok(ReadConcernLevel::Available),
+            "majority" => Ok(ReadConcernLevel::Majority),
+            "linearizable" => Ok(ReadConcernLevel::Linearizable),
+            "snapshot" => Ok(ReadConcernLevel::Snapshot),
+            _ => Err(ConfigError::Invalid {
+                key: "read_concern".to_owned(),
+                value: level.to_owned(),
+                reason: "expected local, available, majority, linearizable or snapshot".to_owned(),
+            }),
+        }
+    }
+}
+
+//w is a node count, "majority" or a custom tag set name
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteConcernConfig {
+    pub w: Option<String>,
+    pub journal: Option<bool>,
+    pub timeout_ms: Option<u64>,
+}
+
+impl WriteConcernConfig {
+    pub(crate) fn write_concern(&self) -> WriteConcern {
+        let w = self.w.as_deref().map(|w| match (w, w.parse::<u32>()) {
+            (_, Ok(nodes)) => Acknowledgment::Nodes(nodes),
+            ("majority", _) => Acknowledgment::Majority,
+            (tag, _) => Acknowledgment::Custom(tag.to_owned()),
+        });
+
+        WriteConcern::builder()
+            .w(w)
+            .journal(self.journal)
+            .w_timeout(self.timeout_ms.map(std::time::Duration::from_millis))
+            .build()
+    }
+}
+
+//Everything a Datastore connects with, built in code, read from the environment or
+//from a TOML/YAML file. Only the URIs and the database name are required, they are
+//checked when connecting
+//
+//let config = DatastoreConfig::from_env()?
+//    .with_database("fnchart")
+//    .with_cache_ttl("books", 3600);
+//let datastore = Datastore::try_with_config(config).await?;
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatastoreConfig {
+    pub mongodb_uri: Option<String>,
+    pub redis_uri: Option<String>,
+    pub database: Option<String>,
+    pub mongo_pool: MongoPoolConfig,
+    pub redis_pool: RedisPoolConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub mongo_retry: RetryPolicy,
+    pub redis_retry: RetryPolicy,
+    pub health: HealthConfig,
+    //Seconds, by cache namespace. Only used for records whose write and type set none
+    pub cache_ttls: HashMap<String, usize>,
+    //Prepended to every Redis key, for deployments sharing one Redis
+    pub key_prefix: Option<String>,
+    pub read_concern: Option<ReadConcernLevel>,
+    pub write_concern: Option<WriteConcernConfig>,
+}
+
+fn env_value(env_var: &str) -> Option<String> {
+    env::var(env_var).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_env<T>(env_var: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    env_value(env_var)
+        .map(|value| {
+            value.parse::<T>().map_err(|err| ConfigError::Invalid {
+                key: env_var.to_owned(),
+                value: value.clone(),
+                reason: err.to_string(),
+            })
+        })
+        .transpose()
+}
+
+//<prefix>_MAX_ATTEMPTS, <prefix>_INITIAL_BACKOFF_MS, <prefix>_MAX_BACKOFF_MS and <prefix>_JITTER
+fn parse_retry_env(prefix: &str, retry: &mut RetryPolicy) -> Result<(), ConfigError> {
+    if let Some(max_attempts) = parse_env(&format!("{}_MAX_ATTEMPTS", prefix))? {
+        retry.max_attempts = max_attempts;
+    }
+    if let Some(initial_backoff_ms) = parse_env(&format!("{}_INITIAL_BACKOFF_MS", prefix))? {
+        retry.initial_backoff_ms = initial_backoff_ms;
+    }
+    if let Some(max_backoff_ms) = parse_env(&format!("{}_MAX_BACKOFF_MS", prefix))? {
+        retry.max_backoff_ms = max_backoff_ms;
+    }
+    if let Some(jitter) = parse_env(&format!("{}_JITTER", prefix))? {
+        retry.jitter = jitter;
+    }
+    Ok(())
+}
+
+//books=3600,bookstores=60
+fn parse_cache_ttls(env_var: &str, value: &str) -> Result<HashMap<String, usize>, ConfigError> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let invalid = |reason: &str| ConfigError::Invalid {
+                key: env_var.to_owned(),
+                value: entry.to_owned(),
+                reason: reason.to_owned(),
+            };
+            let (namespace, seconds) = entry
+                .split_once('=')
+                .ok_or_else(|| invalid("expected <namespace>=<seconds>"))?;
+            let seconds = seconds
+                .trim()
+                .parse::<usize>()
+                .map_err(|err| invalid(&err.to_string()))?;
+            Ok((namespace.trim().to_owned(), seconds))
+        })
+        .collect()
+}
+
+impl DatastoreConfig {
+    //MONGODB_URI, REDIS_URI and the DATASTORE_* variables, a .env file is read first.
+    //Nothing is required here, a malformed value is an error
+    pub fn from_env() -> Result<Self> {
+        dotenv().ok();
+
+        let mut config = DatastoreConfig {
+            mongodb_uri: env_value("MONGODB_URI"),
+            redis_uri: env_value("REDIS_URI"),
+            database: env_value("DATASTORE_DATABASE"),
+            key_prefix: env_value("DATASTORE_KEY_PREFIX"),
+            read_concern: parse_env("DATASTORE_READ_CONCERN")?,
+            ..DatastoreConfig::default()
+        };
+
+        config.mongo_pool = MongoPoolConfig {
+            max_pool_size: parse_env("DATASTORE_MONGO_MAX_POOL_SIZE")?,
+            min_pool_size: parse_env("DATASTORE_MONGO_MIN_POOL_SIZE")?,
+            connect_timeout_seconds: parse_env("DATASTORE_MONGO_CONNECT_TIMEOUT_SECONDS")?,
+            server_selection_timeout_seconds: parse_env(
+                "DATASTORE_MONGO_SERVER_SELECTION_TIMEOUT_SECONDS",
+            )?,
+        };
+
+        let redis_pool = &mut config.redis_pool;
+        if let Some(max_open) = parse_env("DATASTORE_REDIS_MAX_OPEN")? {
+            redis_pool.max_open = max_open;
+        }
+        if let Some(max_idle) = parse_env("DATASTORE_REDIS_MAX_IDLE")? {
+            redis_pool.max_idle = max_idle;
+        }
+        if let Some(timeout_seconds) = parse_env("DATASTORE_REDIS_TIMEOUT_SECONDS")? {
+            redis_pool.timeout_seconds = timeout_seconds;
+        }
+        if let Some(expire_seconds) = parse_env("DATASTORE_REDIS_EXPIRE_SECONDS")? {
+            redis_pool.expire_seconds = expire_seconds;
+        }
+
+        let circuit_breaker = &mut config.circuit_breaker;
+        if let Some(failure_threshold) = parse_env("DATASTORE_BREAKER_FAILURE_THRESHOLD")? {
+            circuit_breaker.failure_threshold = failure_threshold;
+        }
+        if let Some(open_seconds) = parse_env("DATASTORE_BREAKER_OPEN_SECONDS")? {
+            circuit_breaker.open_seconds = open_seconds;
+        }
+        if let Some(max_pending) = parse_env("DATASTORE_BREAKER_MAX_PENDING_INVALIDATIONS")? {
+            circuit_breaker.max_pending_invalidations = max_pending;
+        }
+
+        parse_retry_env("DATASTORE_MONGO_RETRY", &mut config.mongo_retry)?;
+        parse_retry_env("DATASTORE_REDIS_RETRY", &mut config.redis_retry)?;
+
+        if let Some(degraded_latency_ms) = parse_env("DATASTORE_HEALTH_DEGRADED_LATENCY_MS")? {
+            config.health.degraded_latency_ms = degraded_latency_ms;
+        }
+        if let Some(timeout_ms) = parse_env("DATASTORE_HEALTH_TIMEOUT_MS")? {
+            config.health.timeout_ms = timeout_ms;
+        }
+
+        let write_concern = WriteConcernConfig {
+            w: env_value("DATASTORE_WRITE_CONCERN_W"),
+            journal: parse_env("DATASTORE_WRITE_CONCERN_JOURNAL")?,
+            timeout_ms: parse_env("DATASTORE_WRITE_CONCERN_TIMEOUT_MS")?,
+        };
+        if write_concern != WriteConcernConfig::default() {
+            config.write_concern = Some(write_concern);
+        }
+
+        if let Some(cache_ttls) = env_value("DATASTORE_CACHE_TTLS") {
+            config.cache_ttls = parse_cache_ttls("DATASTORE_CACHE_TTLS", &cache_ttls)?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+
+    //The format goes by the file extension
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&contents),
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents),
+            _ => Err(ConfigError::UnsupportedFormat(path.display().to_string()).into()),
+        }
+    }
+
+    pub fn with_mongodb_uri(mut self, mongodb_uri: impl Into<String>) -> Self {
+        self.mongodb_uri = Some(mongodb_uri.into());
+        self
+    }
+
+    pub fn with_redis_uri(mut self, redis_uri: impl Into<String>) -> Self {
+        self.redis_uri = Some(redis_uri.into());
+        self
+    }
+
+    pub fn with_database(mut self, database: impl Into<String>) -> Self {
+        self.database = Some(database.into());
+        self
+    }
+
+    pub fn with_mongo_pool(mut self, mongo_pool: MongoPoolConfig) -> Self {
+        self.mongo_pool = mongo_pool;
+        self
+    }
+
+    pub fn with_redis_pool(mut self, redis_pool: RedisPoolConfig) -> Self {
+        self.redis_pool = redis_pool;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    pub fn with_mongo_retry(mut self, mongo_retry: RetryPolicy) -> Self {
+        self.mongo_retry = mongo_retry;
+        self
+    }
+
+    pub fn with_redis_retry(mut self, redis_retry: RetryPolicy) -> Self {
+        self.redis_retry = redis_retry;
+        self
+    }
+
+    pub fn with_health(mut self, health: HealthConfig) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, namespace: impl Into<String>, seconds: usize) -> Self {
+        self.cache_ttls.insert(namespace.into(), seconds);
+        self
+    }
+
+    pub fn with_key_prefix(mut self, key_prefix: impl Into<String>) -> Self {
+        self.key_prefix = Some(key_prefix.into());
+        self
+    }
+
+    pub fn with_read_concern(mut self, read_concern: ReadConcernLevel) -> Self {
+        self.read_concern = Some(read_concern);
+        self
+    }
+
+    pub fn with_write_concern(mut self, write_concern: WriteConcernConfig) -> Self {
+        self.write_concern = Some(write_concern);
+        self
+    }
+
+    pub(crate) fn try_mongodb_uri(&self) -> Result<&str, ConfigError> {
+        self.mongodb_uri.as_deref().ok_or(ConfigError::Missing {
+            key: "mongodb_uri",
+            env_var: "MONGODB_URI",
+        })
+    }
+
+    pub(crate) fn try_redis_uri(&self) -> Result<&str, ConfigError> {
+        self.redis_uri.as_deref().ok_or(ConfigError::Missing {
+            key: "redis_uri",
+            env_var: "REDIS_URI",
+        })
+    }
+
+    pub(crate) fn try_database(&self) -> Result<&str, ConfigError> {
+        self.database.as_deref().ok_or(ConfigError::Missing {
+            key: "database",
+            env_var: "DATASTORE_DATABASE",
+        })
+    }
+}
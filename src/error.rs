@@ -0,0 +1,286 @@
+use std::fmt::{Debug, Display};
+use std::num::ParseIntError;
+
+use bson::document::ValueAccessError;
+use mobc_redis::redis::{ErrorKind as RedisErrorKind, RedisError};
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+use thiserror::Error;
+
+use crate::cache::redis::MobcError;
+use crate::config::ConfigError;
+use crate::integrity::IntegrityError;
+use crate::migrations::MigrationError;
+use crate::mongodb::atlas::DUPLICATE_KEY_CODE;
+use crate::tenant::TenantError;
+use crate::unique::UniqueViolation;
+
+pub type Result<T, E = DatastoreError> = std::result::Result<T, E>;
+
+//Server codes https://www.mongodb.com/docs/manual/reference/error-codes/
+const WRITE_CONFLICT_CODE: i32 = 112;
+const DOCUMENT_VALIDATION_FAILURE_CODE: i32 = 121;
+const UNAUTHORIZED_CODES: [i32; 2] = [13, 18];
+const TIMEOUT_CODES: [i32; 4] = [50, 64, 89, 262];
+const UNAVAILABLE_CODES: [i32; 10] = [6, 7, 91, 189, 9001, 10107, 11600, 11602, 13435, 13436];
+
+//What every public method fails with. Callers branch on the variant, the error underneath
+//is kept and can still be reached through downcast_ref, e.g. the UniqueViolation behind a
+//Conflict to tell which field was taken
+#[derive(Error, Debug)]
+pub enum DatastoreError {
+    #[error("no record {id} in {collection}")]
+    NotFound { collection: String, id: String },
+    //Unique values, restricted deletes, write conflicts, reused idempotency keys
+    #[error("conflict: {0}")]
+    Conflict(anyhow::Error),
+    //The input was rejected: dangling references, schema validation, bad arguments
+    #[error("validation failed: {0}")]
+    Validation(anyhow::Error),
+    #[error("invalid configuration: {0}")]
+    Configuration(anyhow::Error),
+    #[error("cache unavailable: {0}")]
+    CacheUnavailable(anyhow::Error),
+    #[error("database unavailable: {0}")]
+    DatabaseUnavailable(anyhow::Error),
+    #[error("serialization failed: {0}")]
+    Serialization(anyhow::Error),
+    #[error("timed out: {0}")]
+    Timeout(anyhow::Error),
+    //Mongo or Redis refused for a reason none of the above covers
+    #[error("{0}")]
+    Internal(anyhow::Error),
+}
+
+impl DatastoreError {
+    pub fn not_found(collection: &str, id: &str) -> Self {
+        DatastoreError::NotFound {
+            collection: collection.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    pub fn validation(message: impl Display + Debug + Send + Sync + 'static) -> Self {
+        DatastoreError::Validation(anyhow::Error::msg(message))
+    }
+
+    pub fn serialization(message: impl Display + Debug + Send + Sync + 'static) -> Self {
+        DatastoreError::Serialization(anyhow::Error::msg(message))
+    }
+
+    //For errors Mongo reports as a plain code inside a command response
+    pub(crate) fn with_code(code: i32, err: anyhow::Error) -> Self {
+        code_category(code)(err)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, DatastoreError::NotFound { .. })
+    }
+
+    //The error the variant was classified from, None for NotFound
+    pub fn inner(&self) -> Option<&anyhow::Error> {
+        match self {
+            DatastoreError::NotFound { .. } => None,
+            DatastoreError::Conflict(err)
+            | DatastoreError::Validation(err)
+            | DatastoreError::Configuration(err)
+            | DatastoreError::CacheUnavailable(err)
+            | DatastoreError::DatabaseUnavailable(err)
+            | DatastoreError::Serialization(err)
+            | DatastoreError::Timeout(err)
+            | DatastoreError::Internal(err) => Some(err),
+        }
+    }
+
+    pub fn is<E>(&self) -> bool
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.downcast_ref::<E>().is_some()
+    }
+
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.inner().and_then(|err| err.downcast_ref::<E>())
+    }
+}
+
+//Errors that already are a DatastoreError pass through, everything else is classified by
+//what it wraps
+impl From<anyhow::Error> for DatastoreError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<DatastoreError>() {
+            Ok(datastore_err) => datastore_err,
+            Err(err) => category_of(&err)(err),
+        }
+    }
+}
+
+//Lets `?` classify the errors of the crates underneath without going through anyhow first
+macro_rules! classified_from {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for DatastoreError {
+                fn from(err: $error) -> Self {
+                    DatastoreError::from(anyhow::Error::from(err))
+                }
+            }
+        )*
+    };
+}
+
+classified_from!(
+    MongoError,
+    RedisError,
+    mobc::Error<RedisError>,
+    MobcError,
+    bson::ser::Error,
+    bson::de::Error,
+    ValueAccessError,
+    bson::extjson::de::Error,
+    serde_json::Error,
+    ParseIntError,
+    std::io::Error,
+    toml::de::Error,
+    serde_yaml::Error,
+    ConfigError,
+    IntegrityError,
+    MigrationError,
+    TenantError,
+    UniqueViolation,
+);
+
+type Category = fn(anyhow::Error) -> DatastoreError;
+
+fn category_of(err: &anyhow::Error) -> Category {
+    if let Some(mongo_err) = err.downcast_ref::<MongoError>() {
+        return mongo_category(mongo_err);
+    }
+    if let Some(redis_err) = err.downcast_ref::<RedisError>() {
+        return redis_category(redis_err);
+    }
+    if let Some(pool_err) = err.downcast_ref::<mobc::Error<RedisError>>() {
+        return pool_category(pool_err);
+    }
+    if let Some(mobc_err) = err.downcast_ref::<MobcError>() {
+        return match mobc_err {
+            MobcError::RedisPoolError(pool_err) => pool_category(pool_err),
+            MobcError::RedisTypeError(_) => DatastoreError::Serialization,
+            MobcError::RedisCMDError(redis_err) | MobcError::RedisClientError(redis_err) => {
+                redis_category(redis_err)
+            }
+        };
+    }
+    if let Some(integrity_err) = err.downcast_ref::<IntegrityError>() {
+        return match integrity_err {
+            IntegrityError::DanglingReference { .. } => DatastoreError::Validation,
+            IntegrityError::Restricted { .. } => DatastoreError::Conflict,
+        };
+    }
+    if err.is::<UniqueViolation>() {
+        return DatastoreError::Conflict;
+    }
+    if err.is::<TenantError>() || err.is::<MigrationError>() {
+        return DatastoreError::Validation;
+    }
+    //Files and formats are only read when loading a DatastoreConfig
+    if err.is::<ConfigError>()
+        || err.is::<std::io::Error>()
+        || err.is::<toml::de::Error>()
+        || err.is::<serde_yaml::Error>()
+    {
+        return DatastoreError::Configuration;
+    }
+    if err.is::<bson::ser::Error>()
+        || err.is::<bson::de::Error>()
+        || err.is::<ValueAccessError>()
+        || err.is::<bson::extjson::de::Error>()
+        || err.is::<serde_json::Error>()
+        || err.is::<ParseIntError>()
+    {
+        return DatastoreError::Serialization;
+    }
+
+    DatastoreError::Internal
+}
+
+fn mongo_category(err: &MongoError) -> Category {
+    match err.kind.as_ref() {
+        ErrorKind::Io(io_err) if io_err.kind() == std::io::ErrorKind::TimedOut => {
+            DatastoreError::Timeout
+        }
+        ErrorKind::Io(_)
+        | ErrorKind::ConnectionPoolCleared { .. }
+        | ErrorKind::ServerSelection { .. }
+        | ErrorKind::DnsResolve { .. } => DatastoreError::DatabaseUnavailable,
+        ErrorKind::Authentication { .. } | ErrorKind::InvalidTlsConfig { .. } => {
+            DatastoreError::Configuration
+        }
+        ErrorKind::BsonSerialization(_) | ErrorKind::BsonDeserialization(_) => {
+            DatastoreError::Serialization
+        }
+        ErrorKind::InvalidArgument { .. } => DatastoreError::Validation,
+        ErrorKind::Command(command_err) => code_category(command_err.code),
+        ErrorKind::Write(WriteFailure::WriteError(write_err)) => code_category(write_err.code),
+        ErrorKind::Write(WriteFailure::WriteConcernError(concern_err)) => {
+            code_category(concern_err.code)
+        }
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .map(|write_err| write_err.code)
+            .chain(
+                failure
+                    .write_concern_error
+                    .iter()
+                    .map(|concern_err| concern_err.code),
+            )
+            .next()
+            .map(code_category)
+            .unwrap_or(DatastoreError::Internal),
+        _ => DatastoreError::Internal,
+    }
+}
+
+fn code_category(code: i32) -> Category {
+    match code {
+        DUPLICATE_KEY_CODE | WRITE_CONFLICT_CODE => DatastoreError::Conflict,
+        DOCUMENT_VALIDATION_FAILURE_CODE => DatastoreError::Validation,
+        code if TIMEOUT_CODES.contains(&code) => DatastoreError::Timeout,
+        code if UNAVAILABLE_CODES.contains(&code) => DatastoreError::DatabaseUnavailable,
+        code if UNAUTHORIZED_CODES.contains(&code) => DatastoreError::Configuration,
+        _ => DatastoreError::Internal,
+    }
+}
+
+fn redis_category(err: &RedisError) -> Category {
+    if err.is_timeout() {
+        return DatastoreError::Timeout;
+    }
+    if err.is_io_error() || err.is_connection_refusal() || err.is_connection_dropped() {
+        return DatastoreError::CacheUnavailable;
+    }
+
+    match err.kind() {
+        RedisErrorKind::BusyLoadingError
+        | RedisErrorKind::TryAgain
+        | RedisErrorKind::ClusterDown
+        | RedisErrorKind::MasterDown
+        | RedisErrorKind::ReadOnly => DatastoreError::CacheUnavailable,
+        RedisErrorKind::AuthenticationFailed | RedisErrorKind::InvalidClientConfig => {
+            DatastoreError::Configuration
+        }
+        RedisErrorKind::TypeError => DatastoreError::Serialization,
+        _ => DatastoreError::Internal,
+    }
+}
+
+fn pool_category(err: &mobc::Error<RedisError>) -> Category {
+    match err {
+        mobc::Error::Inner(redis_err) => redis_category(redis_err),
+        mobc::Error::Timeout => DatastoreError::Timeout,
+        mobc::Error::BadConn | mobc::Error::PoolClosed => DatastoreError::CacheUnavailable,
+    }
+}
@@ -0,0 +1,258 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use bson::Document;
+
+use crate::cache::breaker::{BreakerState, BreakerStatus};
+use crate::config::HealthConfig;
+use crate::error::{DatastoreError, Result};
+use crate::Datastore;
+
+//Ordered from best to worst, the report takes the worst of its dependencies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Healthy,
+    //Requests are still served, slower or without the cache
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplicationRole {
+    //Mongo's writable primary, or a Redis master. A Redis without replicas reports master too
+    Primary,
+    Secondary,
+    Standalone,
+    //mongos in front of a sharded cluster
+    Router,
+    Unknown(String),
+}
+
+//Connections of the Redis pool, as mobc reports them. wait_count and wait_duration add up
+//over the life of the pool
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolUsage {
+    pub max_open: u64,
+    pub connections: u64,
+    pub in_use: u64,
+    pub idle: u64,
+    pub wait_count: u64,
+    pub wait_duration: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DependencyHealth {
+    pub status: HealthStatus,
+    //Round trip of the ping, None when it got no answer
+    pub latency: Option<Duration>,
+    pub role: Option<ReplicationRole>,
+    //Redis only, the Mongo driver does not expose its pool
+    pub pool: Option<PoolUsage>,
+    //Why the status is not Healthy
+    pub reasons: Vec<String>,
+}
+
+impl DependencyHealth {
+    fn unreachable(err: DatastoreError) -> Self {
+        Self {
+            status: HealthStatus::Unhealthy,
+            latency: None,
+            role: None,
+            pool: None,
+            reasons: vec![err.to_string()],
+        }
+    }
+
+    fn degrade(&mut self, reason: String) {
+        self.status = self.status.max(HealthStatus::Degraded);
+        self.reasons.push(reason);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub mongo: DependencyHealth,
+    pub redis: DependencyHealth,
+    pub cache_breaker: BreakerStatus,
+}
+
+impl HealthReport {
+    //Atlas holds every record, requests can be served as long as it answers
+    pub fn is_ready(&self) -> bool {
+        self.status != HealthStatus::Unhealthy
+    }
+}
+
+//hello from mongod in a replica set carries setName, from mongos msg: isdbgrid
+fn mongo_role(hello: &Document) -> ReplicationRole {
+    if hello.get_str("msg") == Ok("isdbgrid") {
+        return ReplicationRole::Router;
+    }
+    if !hello.contains_key("setName") {
+        return ReplicationRole::Standalone;
+    }
+
+    match (
+        hello.get_bool("isWritablePrimary"),
+        hello.get_bool("secondary"),
+    ) {
+        (Ok(true), _) => ReplicationRole::Primary,
+        (_, Ok(true)) => ReplicationRole::Secondary,
+        _ => ReplicationRole::Unknown(
+            hello
+                .get_bool("arbiterOnly")
+                .ok()
+                .filter(|arbiter| *arbiter)
+                .map(|_| "arbiter".to_owned())
+                .unwrap_or_else(|| "member".to_owned()),
+        ),
+    }
+}
+
+fn redis_role(role: &str) -> ReplicationRole {
+    match role {
+        "master" => ReplicationRole::Primary,
+        "slave" | "replica" => ReplicationRole::Secondary,
+        role => ReplicationRole::Unknown(role.to_owned()),
+    }
+}
+
+//A probe that hangs counts as failed once the configured timeout is up
+async fn try_probe<T>(config: &HealthConfig, probe: impl Future<Output = Result<T>>) -> Result<T> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    tokio::time::timeout(timeout, probe)
+        .await
+        .unwrap_or_else(|_| {
+            Err(DatastoreError::Timeout(anyhow::anyhow!(
+                "no answer within {} ms",
+                config.timeout_ms
+            )))
+        })
+}
+
+impl Datastore {
+    //For health and readiness endpoints. Mongo and Redis are probed at the same time,
+    //each probe bounded by HealthConfig::timeout_ms. The report is Unhealthy only when
+    //Atlas can't be reached, a Redis outage leaves it Degraded since reads fall back to
+    //Atlas. Nothing here fails, every problem ends up in the report
+    //
+    //let report = datastore.health().await;
+    //let code = if report.is_ready() { 200 } else { 503 };
+    pub async fn health(&self) -> HealthReport {
+        let (mongo, redis) = tokio::join!(self.mongo_health(), self.redis_health());
+        let cache_breaker = self.cache_status();
+
+        let status = mongo.status.max(redis.status.min(HealthStatus::Degraded));
+
+        HealthReport {
+            status,
+            mongo,
+            redis,
+            cache_breaker,
+        }
+    }
+
+    async fn mongo_health(&self) -> DependencyHealth {
+        let config = &self.health;
+
+        let started = Instant::now();
+        if let Err(err) = try_probe(config, self.database.try_ping()).await {
+            return DependencyHealth::unreachable(err);
+        }
+        let latency = started.elapsed();
+
+        let mut health = DependencyHealth {
+            status: HealthStatus::Healthy,
+            latency: Some(latency),
+            role: None,
+            pool: None,
+            reasons: Vec::new(),
+        };
+
+        if latency > Duration::from_millis(config.degraded_latency_ms) {
+            health.degrade(format!(
+                "ping took {} ms, more than {} ms",
+                latency.as_millis(),
+                config.degraded_latency_ms
+            ));
+        }
+
+        match try_probe(config, self.database.try_hello()).await {
+            Ok(hello) => health.role = Some(mongo_role(&hello)),
+            Err(err) => health.degrade(format!("hello failed: {}", err)),
+        }
+
+        //Only reachable with a direct connection, the driver sends writes to the primary
+        if health.role == Some(ReplicationRole::Secondary) {
+            health.degrade("connected to a secondary, writes will be rejected".to_owned());
+        }
+
+        health
+    }
+
+    async fn redis_health(&self) -> DependencyHealth {
+        let config = &self.health;
+
+        let state = self.cache.pool.state().await;
+        let pool = PoolUsage {
+            max_open: state.max_open,
+            connections: state.connections,
+            in_use: state.in_use,
+            idle: state.idle,
+            wait_count: state.wait_count,
+            wait_duration: state.wait_duration,
+        };
+
+        let started = Instant::now();
+        if let Err(err) = try_probe(config, self.cache.try_ping()).await {
+            return DependencyHealth {
+                pool: Some(pool),
+                ..DependencyHealth::unreachable(err)
+            };
+        }
+        let latency = started.elapsed();
+
+        let mut health = DependencyHealth {
+            status: HealthStatus::Healthy,
+            latency: Some(latency),
+            role: None,
+            pool: None,
+            reasons: Vec::new(),
+        };
+
+        if latency > Duration::from_millis(config.degraded_latency_ms) {
+            health.degrade(format!(
+                "PING took {} ms, more than {} ms",
+                latency.as_millis(),
+                config.degraded_latency_ms
+            ));
+        }
+
+        match try_probe(config, self.cache.try_info("replication")).await {
+            Ok(info) => health.role = info.get("role").map(|role| redis_role(role)),
+            Err(err) => health.degrade(format!("INFO replication failed: {}", err)),
+        }
+
+        if health.role == Some(ReplicationRole::Secondary) {
+            health.degrade("connected to a replica, cache writes will be rejected".to_owned());
+        }
+
+        if pool.max_open > 0 && pool.in_use >= pool.max_open {
+            health.degrade(format!("all {} pool connections are in use", pool.max_open));
+        }
+        health.pool = Some(pool);
+
+        //Redis may already be back, the breaker lets a probe through once open_seconds are up
+        let breaker = self.cache_status();
+        if breaker.state != BreakerState::Closed {
+            health.degrade(format!(
+                "circuit breaker is {:?}, the cache is skipped",
+                breaker.state
+            ));
+        }
+
+        health
+    }
+}
@@ -0,0 +1,335 @@
+use bson::{doc, from_document, Bson, Document};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::{
+    options::{FindOneOptions, FindOptions},
+    ClientSession,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::book_types::MongoStorable;
+use crate::error::{DatastoreError, Result};
+use crate::migrations::try_upgrade;
+use crate::mongodb::atlas::{is_duplicate_key, Atlas};
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::timestamps::CREATED_AT_FIELD;
+use crate::Datastore;
+
+//Two writers replacing the same record at once both want the next version number
+const MAX_VERSION_ATTEMPTS: usize = 5;
+
+//A replaced version of a record. recorded_at and actor tell when and by whom it was
+//replaced, see Datastore::with_actor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version<T> {
+    pub version: u64,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: Option<String>,
+    pub record: T,
+}
+
+pub(crate) fn history_collection<T>() -> String
+where
+    T: MongoStorable,
+{
+    format!("{}_history", T::COLLECTION)
+}
+
+//Stored documents of the given records, read before a write replaces them for the history
+//and the audit log
+pub(crate) async fn try_read_prior(
+    atlas: &Atlas,
+    session: Option<&mut ClientSession>,
+    table: &str,
+    record_ids: &[String],
+) -> Result<Vec<Document>> {
+    if record_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let collection = atlas.db.collection::<Document>(table);
+    let filter = doc! { "_id": { "$in": record_ids } };
+    let mut documents = Vec::new();
+
+    match session {
+        Some(session) => {
+            let mut cursor = collection
+                .find_with_session(filter, None, &mut *session)
+                .await?;
+            while let Some(document) = cursor.next(&mut *session).await {
+                documents.push(document?);
+            }
+        }
+        None => {
+            let mut cursor = collection.find(filter, None).await?;
+            while let Some(document) = cursor.next().await {
+                documents.push(document?);
+            }
+        }
+    }
+
+    Ok(documents)
+}
+
+async fn try_last_version(
+    atlas: &Atlas,
+    table: &str,
+    session: Option<&mut ClientSession>,
+    record_id: &str,
+) -> Result<u64> {
+    let collection = atlas.db.collection::<Document>(table);
+    let filter = doc! { "record_id": record_id };
+    let options = FindOneOptions::builder()
+        .sort(doc! { "version": -1 })
+        .build();
+
+    let last = match session {
+        Some(session) => {
+            collection
+                .find_one_with_session(filter, options, session)
+                .await?
+        }
+        None => collection.find_one(filter, options).await?,
+    };
+
+    Ok(last
+        .and_then(|entry| entry.get_i64("version").ok())
+        .unwrap_or(0) as u64)
+}
+
+//Keeps the prior documents as the next versions of their records, numbered from 1 per
+//record. Nothing is kept for types without history
+pub(crate) async fn try_write_history<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    prior: Vec<Document>,
+    now: bson::DateTime,
+    actor: Option<&str>,
+) -> Result<()>
+where
+    T: MongoStorable,
+{
+    if !T::HISTORY {
+        return Ok(());
+    }
+
+    let table = history_collection::<T>();
+    let collection = atlas.db.collection::<Document>(&table);
+
+    for document in prior {
+        let record_id = document.get_str("_id")?.to_owned();
+        let mut version =
+            try_last_version(atlas, &table, session.as_deref_mut(), &record_id).await? + 1;
+        let mut attempt = 1;
+
+        loop {
+            let entry = doc! {
+                "_id": format!("{}:{}", record_id, version),
+                "record_id": &record_id,
+                "version": version as i64,
+                "recorded_at": now,
+                "actor": actor,
+                "document": document.clone(),
+            };
+
+            let insert_result = match session.as_deref_mut() {
+                Some(session) => {
+                    collection
+                        .insert_one_with_session(entry, None, session)
+                        .await
+                }
+                None => collection.insert_one(entry, None).await,
+            };
+
+            match insert_result {
+                Ok(_) => break,
+                Err(err) if is_duplicate_key(&err) && attempt < MAX_VERSION_ATTEMPTS => {
+                    attempt += 1;
+                    version += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn version_of<T>(entry: Document) -> Result<Version<T>>
+where
+    T: MongoStorable + DeserializeOwned,
+{
+    let mut document = entry.get_document("document")?.clone();
+    let _ = try_upgrade::<T>(&mut document)?;
+
+    Ok(Version {
+        version: entry.get_i64("version")? as u64,
+        recorded_at: entry.get_datetime("recorded_at")?.to_chrono(),
+        actor: entry.get_str("actor").ok().map(str::to_owned),
+        record: from_document::<T>(document)?,
+    })
+}
+
+impl Datastore {
+    //Tags the history entries written through this handle, e.g.
+    //datastore.clone().with_actor("alice")
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    //Empty unless T keeps history or this handle is audited
+    pub(crate) async fn try_read_prior<T>(
+        &self,
+        table: &str,
+        record_ids: &[String],
+    ) -> Result<Vec<Document>>
+    where
+        T: MongoStorable,
+    {
+        if !T::HISTORY && !self.is_audited() {
+            return Ok(Vec::new());
+        }
+
+        try_read_prior(&self.database, None, table, record_ids).await
+    }
+
+    pub(crate) async fn try_write_history<T>(
+        &self,
+        prior: Vec<Document>,
+        now: bson::DateTime,
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        if prior.is_empty() {
+            return Ok(());
+        }
+
+        try_write_history::<T>(&self.database, None, prior, now, self.actor.as_deref()).await
+    }
+
+    async fn try_history_entries<T>(&self, record_id: &str) -> Result<Vec<Document>>
+    where
+        T: MongoStorable,
+    {
+        let table = history_collection::<T>();
+        let collection = self.database.db.collection::<Document>(&table);
+        let options = FindOptions::builder().sort(doc! { "version": 1 }).build();
+
+        let mut cursor = collection
+            .find(doc! { "record_id": record_id }, options)
+            .await?;
+        let mut entries = Vec::new();
+
+        while let Some(entry) = cursor.next().await {
+            entries.push(entry?);
+        }
+
+        Ok(entries)
+    }
+
+    //Every replaced version of the record, oldest first. The current one is not included
+    pub async fn try_list_versions<T>(&self, record_id: &str) -> Result<Vec<Version<T>>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        self.try_history_entries::<T>(record_id)
+            .await?
+            .into_iter()
+            .map(version_of)
+            .collect()
+    }
+
+    //The record as it was at the given time. None when it did not exist yet or was
+    //deleted at that time
+    pub async fn try_read_as_of<T>(&self, record_id: &str, at: DateTime<Utc>) -> Result<Option<T>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        let at = bson::DateTime::from_chrono(at);
+
+        //The version live at `at` is the first one replaced after it, or the current one
+        let mut valid_from = None;
+        let mut live = None;
+
+        for entry in self.try_history_entries::<T>(record_id).await? {
+            let recorded_at = *entry.get_datetime("recorded_at")?;
+            if recorded_at > at {
+                live = Some(entry.get_document("document")?.clone());
+                break;
+            }
+            valid_from = Some(recorded_at);
+        }
+
+        let mut document = match live {
+            Some(document) => document,
+            None => match self
+                .database
+                .try_find_matching(T::COLLECTION, doc! { "_id": record_id }, Some(1))
+                .await?
+                .pop()
+            {
+                Some(document) => document,
+                None => return Ok(None),
+            },
+        };
+
+        //A version written by a re-create starts at its own created_at
+        let created_at = document.get_datetime(CREATED_AT_FIELD).ok().copied();
+        let started_at = valid_from.max(created_at);
+        let deleted = matches!(document.get(DELETED_AT_FIELD), Some(Bson::DateTime(deleted_at)) if *deleted_at <= at);
+
+        if deleted
+            || started_at
+                .map(|started_at| started_at > at)
+                .unwrap_or(false)
+        {
+            return Ok(None);
+        }
+
+        let _ = try_upgrade::<T>(&mut document)?;
+        Ok(Some(from_document::<T>(document)?))
+    }
+
+    //Writes the given version back as the current one, brought back if it was deleted. The
+    //version it replaces is kept in the history like with any other write
+    pub async fn try_revert<T>(
+        &self,
+        record_id: &str,
+        version: u64,
+        cache_expiry: Option<usize>,
+    ) -> Result<T>
+    where
+        T: MongoStorable + Serialize + DeserializeOwned + Clone,
+    {
+        let table = history_collection::<T>();
+        let entry = self
+            .database
+            .try_find_one(
+                &table,
+                doc! { "record_id": record_id, "version": version as i64 },
+            )
+            .await
+            .map_err(|err| match err {
+                DatastoreError::NotFound { .. } => {
+                    DatastoreError::not_found(&table, &format!("{} version {}", record_id, version))
+                }
+                err => err,
+            })?;
+
+        let reverted = version_of::<T>(entry)?.record;
+
+        let upsert = self
+            .try_upsert_one(
+                T::COLLECTION,
+                T::CACHE_NAMESPACE,
+                reverted,
+                None,
+                cache_expiry,
+            )
+            .await?;
+
+        Ok(upsert.data)
+    }
+}
@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::time::Duration;
+
+use bson::{from_bson, to_bson};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::book_types::MongoStorable;
+use crate::error::{DatastoreError, Result};
+use crate::Datastore;
+
+pub const IDEMPOTENCY_TABLE: &str = "idempotency_keys";
+pub const IDEMPOTENCY_KEY_TTL_SECONDS: usize = 60 * 60 * 24;
+//How long a pending claim holds the key. A process that dies mid request frees it after
+//this instead of after the full TTL
+pub const IDEMPOTENCY_LEASE_SECONDS: usize = 60 * 5;
+//A running operation pushes its lease out this often, well before it runs out
+const IDEMPOTENCY_RENEW_SECONDS: u64 = (IDEMPOTENCY_LEASE_SECONDS / 3) as u64;
+
+//The cached outcome is keyed on the request as well, a reused key with a different
+//request misses it and is rejected by the stored claim
+fn idempotency_cache_key(idempotency_key: &str, request_hash: &str) -> String {
+    format!("idempotency:{}:{}", idempotency_key, request_hash)
+}
+
+//FNV-1a over the serialized request, stable across processes and releases unlike the std hasher
+fn request_hash<Q: Serialize>(request: &Q) -> Result<String> {
+    let hash = serde_json::to_vec(request)?
+        .into_iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+
+    Ok(format!("{:016x}", hash))
+}
+
+fn expires_in(seconds: usize) -> bson::DateTime {
+    bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + (seconds as i64) * 1000)
+}
+
+impl Datastore {
+    //Runs operation at most once per key. Redis holds the outcome for fast replays, the
+    //Mongo collection is the source of truth and doubles as the lock while a request is
+    //in flight. The lock is renewed for as long as the operation runs. A failed operation
+    //releases the key so the client can retry it. Reusing a key for a different request
+    //is a Conflict
+    pub async fn try_idempotent<Q, R, F, Fut>(
+        &self,
+        idempotency_key: &str,
+        request: &Q,
+        ttl: Option<usize>,
+        operation: F,
+    ) -> Result<R>
+    where
+        Q: Serialize,
+        R: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let request_hash = request_hash(request)?;
+        let cache_key = idempotency_cache_key(idempotency_key, &request_hash);
+        let ttl_seconds = ttl.unwrap_or(IDEMPOTENCY_KEY_TTL_SECONDS);
+
+        //A cache outage only costs us the fast path
+        if let Ok(Some(cached_result)) = self.cache.try_get(&cache_key).await {
+            return Ok(serde_json::from_str::<R>(&cached_result)?);
+        }
+
+        self.idempotency_index
+            .get_or_try_init(|| {
+                self.database
+                    .try_ensure_ttl_index(IDEMPOTENCY_TABLE, "expires_at")
+            })
+            .await?;
+
+        let existing = self
+            .database
+            .try_claim_idempotency_key(
+                IDEMPOTENCY_TABLE,
+                idempotency_key,
+                &request_hash,
+                expires_in(IDEMPOTENCY_LEASE_SECONDS),
+            )
+            .await?;
+
+        if let Some(existing) = existing {
+            if existing.get_str("request_hash").ok() != Some(request_hash.as_str()) {
+                return Err(DatastoreError::Conflict(anyhow::anyhow!(
+                    "Idempotency key {} was already used for a different request",
+                    idempotency_key
+                )));
+            }
+
+            return match (existing.get_str("status"), existing.get("result")) {
+                (Ok("completed"), Some(result)) => {
+                    let replayed = from_bson::<R>(result.clone())?;
+                    let _ = self
+                        .cache
+                        .try_set(&cache_key, serde_json::to_string(&replayed)?, Some(ttl_seconds))
+                        .await;
+                    Ok(replayed)
+                }
+                _ => Err(DatastoreError::Conflict(anyhow::anyhow!(
+                    "Request with idempotency key {} is already in progress",
+                    idempotency_key
+                ))),
+            };
+        }
+
+        let renew_lease = async {
+            loop {
+                tokio::time::sleep(Duration::from_secs(IDEMPOTENCY_RENEW_SECONDS)).await;
+                let _ = self
+                    .database
+                    .try_renew_idempotency_key(
+                        IDEMPOTENCY_TABLE,
+                        idempotency_key,
+                        expires_in(IDEMPOTENCY_LEASE_SECONDS),
+                    )
+                    .await;
+            }
+        };
+
+        let outcome = tokio::select! {
+            outcome = operation() => outcome,
+            _ = renew_lease => unreachable!("the lease is renewed until the operation is done"),
+        };
+
+        match outcome {
+            Ok(result) => {
+                let _ = self
+                    .cache
+                    .try_set(&cache_key, serde_json::to_string(&result)?, Some(ttl_seconds))
+                    .await;
+
+                //The operation went through, running it again on a retry would apply it twice.
+                //Without the stored result the claim is held for the full TTL instead
+                let completed = self
+                    .database
+                    .try_complete_idempotency_key(
+                        IDEMPOTENCY_TABLE,
+                        idempotency_key,
+                        to_bson(&result)?,
+                        expires_in(ttl_seconds),
+                    )
+                    .await;
+                if completed.is_err() {
+                    let _ = self
+                        .database
+                        .try_renew_idempotency_key(
+                            IDEMPOTENCY_TABLE,
+                            idempotency_key,
+                            expires_in(ttl_seconds),
+                        )
+                        .await;
+                }
+                Ok(result)
+            }
+            Err(err) => {
+                self.database
+                    .try_release_idempotency_key(IDEMPOTENCY_TABLE, idempotency_key)
+                    .await?;
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn try_create_one_idempotent<T>(
+        &self,
+        idempotency_key: &str,
+        table: &str,
+        hash_key: &str,
+        record: T,
+        cache_expiry: Option<usize>,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned + Clone + MongoStorable,
+    {
+        self.try_idempotent(idempotency_key, &(table, &record), None, || {
+            self.try_create_one(table, hash_key, record.clone(), cache_expiry)
+        })
+        .await
+    }
+
+    pub async fn try_update_one_idempotent<T>(
+        &self,
+        idempotency_key: &str,
+        table: &str,
+        hash_key: &str,
+        update_record: T,
+        cache_expiry: Option<usize>,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned + Clone + MongoStorable,
+    {
+        self.try_idempotent(idempotency_key, &(table, &update_record), None, || {
+            self.try_update_one(table, hash_key, update_record.clone(), cache_expiry)
+        })
+        .await
+    }
+}
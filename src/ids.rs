@@ -0,0 +1,144 @@
+use bson::{doc, oid::ObjectId};
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::book_types::MongoStorable;
+use crate::cache::redis::RedisCache;
+use crate::error::{DatastoreError, Result};
+use crate::mongodb::atlas::Atlas;
+use crate::Datastore;
+
+//How ids are made for records created without one, i.e. with an empty id
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdStrategy {
+    //24 hex characters, roughly ordered by creation time
+    ObjectId,
+    //32 hex characters without dashes, like the ids the records already use
+    UuidV4,
+    //Same format as UuidV4, but ordered by creation time
+    UuidV7,
+    //26 Crockford base32 characters, ordered by creation time
+    Ulid,
+    //1, 2, 3... per collection from a Redis counter, zero padded to 20 digits so the ids
+    //sort the same as strings and as numbers. A missing counter, e.g. after a flush, starts
+    //again after the highest sequence id stored in Mongo
+    Sequence,
+}
+
+impl IdStrategy {
+    //None for Sequence, which needs the counter in Redis
+    pub fn generate(&self) -> Option<String> {
+        match self {
+            IdStrategy::ObjectId => Some(ObjectId::new().to_hex()),
+            IdStrategy::UuidV4 => Some(Uuid::new_v4().simple().to_string()),
+            IdStrategy::UuidV7 => Some(Uuid::now_v7().simple().to_string()),
+            IdStrategy::Ulid => Some(Ulid::new().to_string()),
+            IdStrategy::Sequence => None,
+        }
+    }
+}
+
+fn sequence_key(collection: &str) -> String {
+    format!("id_seq:{}", collection)
+}
+
+//count fresh ids for T stored in table. A sequence reserves the whole block in one INCRBY
+pub(crate) async fn try_generate_ids<T>(
+    atlas: &Atlas,
+    cache: &RedisCache,
+    table: &str,
+    count: usize,
+) -> Result<Vec<String>>
+where
+    T: MongoStorable,
+{
+    if T::ID_STRATEGY != IdStrategy::Sequence {
+        return Ok((0..count)
+            .filter_map(|_| T::ID_STRATEGY.generate())
+            .collect());
+    }
+
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let key = sequence_key(table);
+    let last = match cache.try_increment(&key, count as u64).await? {
+        Some(last) => last,
+        None => {
+            cache
+                .try_seed_counter(&key, try_highest_sequence(atlas, table).await?)
+                .await?;
+            cache
+                .try_increment(&key, count as u64)
+                .await?
+                .ok_or_else(|| {
+                    DatastoreError::Internal(anyhow::anyhow!("Counter {} was not created", key))
+                })?
+        }
+    };
+
+    Ok((last + 1 - count as u64..=last)
+        .map(|sequence| format!("{:020}", sequence))
+        .collect())
+}
+
+//Highest id of table that looks like a sequence id, 0 when there is none
+async fn try_highest_sequence(atlas: &Atlas, table: &str) -> Result<u64> {
+    let highest = atlas
+        .try_find_sorted(
+            table,
+            doc! { "_id": { "$regex": "^[0-9]{20}$" } },
+            doc! { "_id": -1 },
+            Some(1),
+        )
+        .await?;
+
+    Ok(highest
+        .first()
+        .and_then(|document| document.get_str("_id").ok())
+        .and_then(|id| id.parse::<u64>().ok())
+        .unwrap_or(0))
+}
+
+//Gives every record without an id a fresh one, records that have one keep it
+pub(crate) async fn try_assign_ids<'r, T, I>(
+    atlas: &Atlas,
+    cache: &RedisCache,
+    table: &str,
+    records: I,
+) -> Result<()>
+where
+    T: MongoStorable + 'r,
+    I: IntoIterator<Item = &'r mut T>,
+{
+    let mut missing: Vec<&mut T> = records
+        .into_iter()
+        .filter(|record| record.get_id().is_empty())
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let ids = try_generate_ids::<T>(atlas, cache, table, missing.len()).await?;
+
+    for (record, id) in missing.iter_mut().zip(ids) {
+        record.set_id(id);
+    }
+
+    Ok(())
+}
+
+impl Datastore {
+    //A fresh id for T without creating anything, e.g. to reference a record before it exists
+    pub async fn try_generate_id<T>(&self) -> Result<String>
+    where
+        T: MongoStorable,
+    {
+        let mut ids = try_generate_ids::<T>(&self.database, &self.cache, T::COLLECTION, 1).await?;
+        ids.pop().ok_or_else(|| {
+            DatastoreError::Internal(anyhow::anyhow!("No id was generated for {}", T::COLLECTION))
+        })
+    }
+}
@@ -0,0 +1,341 @@
+use std::time::Duration;
+
+use bson::{doc, Bson, Document};
+use mongodb::{options::IndexOptions, IndexModel};
+
+use crate::book_types::{BookRecord, BookstoreRecord, MongoStorable};
+use crate::error::Result;
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::timestamps::UPDATED_AT_FIELD;
+use crate::Datastore;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexOrder {
+    Ascending,
+    Descending,
+    Text,
+}
+
+impl IndexOrder {
+    fn key_value(&self) -> Bson {
+        match self {
+            IndexOrder::Ascending => Bson::Int32(1),
+            IndexOrder::Descending => Bson::Int32(-1),
+            IndexOrder::Text => Bson::String("text".to_owned()),
+        }
+    }
+
+    fn name_part(&self) -> &'static str {
+        match self {
+            IndexOrder::Ascending => "1",
+            IndexOrder::Descending => "-1",
+            IndexOrder::Text => "text",
+        }
+    }
+}
+
+//An index a record type expects on its collection
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexSpec {
+    pub name: String,
+    pub keys: Vec<(&'static str, IndexOrder)>,
+    pub unique: bool,
+    pub expire_after: Option<Duration>,
+    //Only documents matching this filter are indexed
+    pub partial_filter: Option<Document>,
+}
+
+impl IndexSpec {
+    //Named the way Mongo names an index by default, e.g. data.bookstore_id_1
+    pub fn compound(keys: Vec<(&'static str, IndexOrder)>) -> Self {
+        let name = keys
+            .iter()
+            .map(|(field, order)| format!("{}_{}", field, order.name_part()))
+            .collect::<Vec<String>>()
+            .join("_");
+
+        Self {
+            name,
+            keys,
+            unique: false,
+            expire_after: None,
+            partial_filter: None,
+        }
+    }
+
+    pub fn single(field: &'static str) -> Self {
+        Self::compound(vec![(field, IndexOrder::Ascending)])
+    }
+
+    pub fn text(fields: Vec<&'static str>) -> Self {
+        Self::compound(
+            fields
+                .into_iter()
+                .map(|field| (field, IndexOrder::Text))
+                .collect(),
+        )
+    }
+
+    //Documents expire once the date stored in field is older than expire_after
+    pub fn ttl(field: &'static str, expire_after: Duration) -> Self {
+        let mut spec = Self::single(field);
+        spec.expire_after = Some(expire_after);
+        spec
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn partial(mut self, filter: Document) -> Self {
+        self.partial_filter = Some(filter);
+        self
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.keys.iter().any(|(_, order)| *order == IndexOrder::Text)
+    }
+
+    pub(crate) fn to_model(&self) -> IndexModel {
+        let mut keys = Document::new();
+        for (field, order) in self.keys.iter() {
+            keys.insert(*field, order.key_value());
+        }
+
+        let options = IndexOptions::builder()
+            .name(self.name.clone())
+            .unique(self.unique.then_some(true))
+            .expire_after(self.expire_after)
+            .partial_filter_expression(self.partial_filter.clone())
+            .build();
+
+        IndexModel::builder().keys(keys).options(options).build()
+    }
+
+    //Same fields in the same order. Text indexes are listed by Mongo as _fts/_ftsx
+    //with the fields moved to the weights option
+    fn same_keys(&self, existing: &IndexModel) -> bool {
+        if self.is_text() {
+            let weights = existing
+                .options
+                .as_ref()
+                .and_then(|options| options.weights.as_ref());
+
+            return match weights {
+                Some(weights) => {
+                    weights.len() == self.keys.len()
+                        && self.keys.iter().all(|(field, _)| weights.contains_key(field))
+                }
+                None => false,
+            };
+        }
+
+        existing.keys.len() == self.keys.len()
+            && existing
+                .keys
+                .iter()
+                .zip(self.keys.iter())
+                .all(|((existing_field, existing_value), (field, order))| {
+                    existing_field == field && same_key_value(existing_value, &order.key_value())
+                })
+    }
+
+    fn differences(&self, existing: &IndexModel) -> Vec<String> {
+        let mut differences = Vec::new();
+        let existing_options = existing.options.clone().unwrap_or_default();
+
+        if !self.same_keys(existing) {
+            differences.push(format!("keys are {}", existing.keys));
+        }
+
+        if existing_options.unique.unwrap_or(false) != self.unique {
+            differences.push(format!("unique is {}, declared {}", !self.unique, self.unique));
+        }
+
+        if existing_options.expire_after != self.expire_after {
+            differences.push(format!(
+                "expire_after is {:?}, declared {:?}",
+                existing_options.expire_after, self.expire_after
+            ));
+        }
+
+        if existing_options.partial_filter_expression != self.partial_filter {
+            differences.push(format!(
+                "partial_filter is {:?}, declared {:?}",
+                existing_options.partial_filter_expression, self.partial_filter
+            ));
+        }
+
+        differences
+    }
+}
+
+//listIndexes may hand back 1.0 or 1i64 for an index created by another driver
+fn same_key_value(existing: &Bson, declared: &Bson) -> bool {
+    let as_number = |value: &Bson| match value {
+        Bson::Int32(number) => Some(*number as f64),
+        Bson::Int64(number) => Some(*number as f64),
+        Bson::Double(number) => Some(*number),
+        _ => None,
+    };
+
+    match (as_number(existing), as_number(declared)) {
+        (Some(existing), Some(declared)) => existing == declared,
+        _ => existing == declared,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexDrift {
+    pub name: String,
+    pub differences: Vec<String>,
+}
+
+//What ensure_indexes did and what it left alone. Drifted indexes are never rebuilt
+//automatically, dropping an index on a live collection is left to the operator
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexReport {
+    pub collection: String,
+    pub created: Vec<String>,
+    pub drifted: Vec<IndexDrift>,
+    //Present on the collection but declared nowhere, _id_ excluded
+    pub undeclared: Vec<String>,
+}
+
+impl IndexReport {
+    pub fn is_in_sync(&self) -> bool {
+        self.drifted.is_empty() && self.undeclared.is_empty()
+    }
+}
+
+//Everything T asks for: its own indexes, the ones backing its unique keys and the
+//updated_at index behind try_read_modified_since. Unique keys of a soft deleted type
+//only cover live documents so a deleted record doesn't hold on to its values
+pub fn declared_indexes<T>() -> Vec<IndexSpec>
+where
+    T: MongoStorable,
+{
+    let mut declared = T::indexes();
+    declared.push(IndexSpec::single(UPDATED_AT_FIELD));
+    declared.extend(T::unique_keys().iter().map(|unique_key| {
+        let spec = unique_key.index_spec();
+        match T::SOFT_DELETE {
+            //Partial filters don't accept $exists: false, equality with null also
+            //matches a missing deleted_at
+            true => spec.partial(doc! { DELETED_AT_FIELD: Bson::Null }),
+            false => spec,
+        }
+    }));
+    declared
+}
+
+impl Datastore {
+    //Creates the declared indexes missing from T's collection and reports drift from
+    //the declared set. Safe to call repeatedly, e.g. at startup or from an admin command
+    pub async fn ensure_indexes<T>(&self) -> Result<IndexReport>
+    where
+        T: MongoStorable,
+    {
+        self.ensure_indexes_in::<T>(T::COLLECTION).await
+    }
+
+    //Same as ensure_indexes for T stored under another table
+    pub async fn ensure_indexes_in<T>(&self, table: &str) -> Result<IndexReport>
+    where
+        T: MongoStorable,
+    {
+        let declared = declared_indexes::<T>();
+        let existing = self.database.try_list_indexes(table).await?;
+
+        let mut report = IndexReport {
+            collection: table.to_owned(),
+            ..IndexReport::default()
+        };
+        let mut missing = Vec::new();
+
+        for spec in declared.iter() {
+            let by_name = existing
+                .iter()
+                .find(|index| index_name(index).as_deref() == Some(spec.name.as_str()));
+
+            match by_name {
+                Some(index) => {
+                    let differences = spec.differences(index);
+                    if !differences.is_empty() {
+                        report.drifted.push(IndexDrift {
+                            name: spec.name.clone(),
+                            differences,
+                        });
+                    }
+                }
+                //Mongo refuses a second index over the same keys, even under another name
+                None => match existing.iter().find(|index| spec.same_keys(index)) {
+                    Some(index) => report.drifted.push(IndexDrift {
+                        name: spec.name.clone(),
+                        differences: vec![format!(
+                            "exists as {}",
+                            index_name(index).unwrap_or_default()
+                        )],
+                    }),
+                    None => missing.push(spec.to_model()),
+                },
+            }
+        }
+
+        report.undeclared = existing
+            .iter()
+            .filter_map(index_name)
+            .filter(|name| name != "_id_" && !declared.iter().any(|spec| &spec.name == name))
+            .collect();
+
+        if !missing.is_empty() {
+            report.created = missing.iter().filter_map(index_name).collect();
+            self.database
+                .try_create_indexes(table, missing)
+                .await?;
+        }
+
+        if report.drifted.is_empty() {
+            if let Ok(mut collections) = self.ensured_indexes.lock() {
+                collections.insert(table.to_owned());
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub async fn ensure_all_indexes(&self) -> Result<Vec<IndexReport>> {
+        Ok(vec![
+            self.ensure_indexes::<BookRecord>().await?,
+            self.ensure_indexes::<BookstoreRecord>().await?,
+        ])
+    }
+
+    //Writes that rely on an index (unique keys) call this. Only the first write per
+    //collection in this process goes to Mongo, whatever came of it. A failed or drifted
+    //build does not hold writes back, the unique check reads Atlas without the index
+    //and ensure_indexes reports what is wrong
+    pub(crate) async fn ensure_indexes_once<T>(&self, table: &str)
+    where
+        T: MongoStorable,
+    {
+        let first_attempt = match self.ensured_indexes.lock() {
+            Ok(mut collections) => collections.insert(table.to_owned()),
+            Err(poisoned) => poisoned.into_inner().insert(table.to_owned()),
+        };
+
+        if first_attempt {
+            let _ = self.ensure_indexes_in::<T>(table).await;
+        }
+    }
+}
+
+fn index_name(index: &IndexModel) -> Option<String> {
+    index.options.as_ref().and_then(|options| options.name.clone())
+}
@@ -0,0 +1,243 @@
+use bson::{doc, to_document, Bson};
+use mongodb::ClientSession;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::book_types::MongoStorable;
+use crate::cache::redis::CacheOp;
+use crate::error::{DatastoreError, Result};
+use crate::mongodb::atlas::Atlas;
+use crate::relations::field_values;
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::timestamps::UPDATED_AT_FIELD;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnDelete {
+    //Refuse to delete while any target still points at the record
+    Restrict,
+    //Delete the targets along with the record
+    Cascade,
+    //Keep the targets but unset their foreign key, see Relation::on_delete_set_null
+    SetNull,
+}
+
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("{collection}.{field} points at {key}, which does not exist in {target}")]
+    DanglingReference {
+        collection: String,
+        field: String,
+        target: String,
+        key: String,
+    },
+    #[error("cannot delete from {collection}: {count} record(s) in {target} still reference {record_ids}")]
+    Restricted {
+        collection: String,
+        target: String,
+        record_ids: String,
+        count: u64,
+    },
+}
+
+//Every required key of the record has to exist in the relation's target collection.
+//Missing or null keys are not references and pass, soft-deleted targets do not count
+pub(crate) async fn try_check_references<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    record: &T,
+) -> Result<()>
+where
+    T: MongoStorable + Serialize,
+{
+    let document = to_document(record)?;
+
+    for relation in T::relations() {
+        if !relation.required || !relation.holds_keys() {
+            continue;
+        }
+
+        let mut keys: Vec<Bson> = Vec::new();
+        for key in field_values(&document, relation.local_field) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        if keys.is_empty() {
+            continue;
+        }
+
+        let filter = doc! {
+            relation.foreign_field: { "$in": keys.clone() },
+            DELETED_AT_FIELD: Bson::Null,
+        };
+        let found = atlas
+            .try_distinct(
+                relation.target,
+                relation.foreign_field,
+                filter,
+                session.as_deref_mut(),
+            )
+            .await?;
+
+        if let Some(missing) = keys.into_iter().find(|key| !found.contains(key)) {
+            return Err(IntegrityError::DanglingReference {
+                collection: T::COLLECTION.to_owned(),
+                field: relation.local_field.to_owned(),
+                target: relation.target.to_owned(),
+                key: missing
+                    .as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| missing.to_string()),
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+//A set-null rule over a key the targets cannot go without would leave them unreadable
+fn try_check_rules<T>() -> Result<()>
+where
+    T: MongoStorable,
+{
+    for relation in T::relations() {
+        if relation.on_delete == Some(OnDelete::SetNull) && !relation.nullable_key {
+            return Err(DatastoreError::Configuration(anyhow::anyhow!(
+                "{} cannot unset {}.{} on delete, declare the rule with \
+                 Relation::on_delete_set_null over an Option field",
+                T::COLLECTION,
+                relation.target,
+                relation.foreign_field
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+//Checked for every relation before anything is touched, so a restricted delete
+//never leaves a cascade half done. Soft-deleted targets do not hold a record back
+pub(crate) async fn try_check_restrict<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    record_ids: &[String],
+) -> Result<()>
+where
+    T: MongoStorable,
+{
+    try_check_rules::<T>()?;
+
+    for relation in T::relations() {
+        if relation.on_delete != Some(OnDelete::Restrict) {
+            continue;
+        }
+
+        let filter = doc! {
+            relation.foreign_field: { "$in": record_ids },
+            DELETED_AT_FIELD: Bson::Null,
+        };
+        let count = atlas
+            .try_count_matching(relation.target, filter, session.as_deref_mut())
+            .await?;
+
+        if count > 0 {
+            return Err(IntegrityError::Restricted {
+                collection: T::COLLECTION.to_owned(),
+                target: relation.target.to_owned(),
+                record_ids: record_ids.join(", "),
+                count,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+//Applies the cascade and set-null rules for records being deleted and returns the cache
+//ops that evict the affected targets and drop them from the relation indexes.
+//Cascades go one level deep, the target type's own rules are not followed.
+//Set-null targets get now as their updated_at
+pub(crate) async fn try_cascade<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    record_ids: &[String],
+    now: bson::DateTime,
+) -> Result<Vec<CacheOp>>
+where
+    T: MongoStorable,
+{
+    try_check_rules::<T>()?;
+
+    let mut cache_ops = Vec::new();
+
+    for relation in T::relations() {
+        let rule = match relation.on_delete {
+            Some(OnDelete::Cascade) => OnDelete::Cascade,
+            Some(OnDelete::SetNull) => OnDelete::SetNull,
+            _ => continue,
+        };
+
+        let filter = doc! { relation.foreign_field: { "$in": record_ids } };
+        let target_ids = atlas
+            .try_distinct(relation.target, "_id", filter.clone(), session.as_deref_mut())
+            .await?;
+
+        if target_ids.is_empty() {
+            continue;
+        }
+
+        if rule == OnDelete::Cascade {
+            atlas
+                .try_delete_matching(relation.target, filter, session.as_deref_mut())
+                .await?;
+        } else {
+            let update = doc! {
+                "$unset": { relation.foreign_field: "" },
+                "$set": { UPDATED_AT_FIELD: now },
+            };
+            atlas
+                .try_update_matching(relation.target, filter, update, session.as_deref_mut())
+                .await?;
+        }
+
+        //Set-null targets are evicted too, their cached copy still holds the old key
+        for target_id in target_ids.iter().filter_map(Bson::as_str) {
+            cache_ops.push(CacheOp::Delete {
+                hash_key: relation.target_namespace.to_owned(),
+                field: target_id.to_owned(),
+            });
+
+            if let Some(index) = relation.index {
+                cache_ops.push(CacheOp::Relate {
+                    index,
+                    record_id: target_id.to_owned(),
+                    target_ids: Vec::new(),
+                });
+                //A deleted target has no forward set left to keep
+                if rule == OnDelete::Cascade {
+                    cache_ops.push(CacheOp::Evict {
+                        keys: vec![index.forward_key(target_id)],
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(cache_ops)
+}
+
+pub(crate) async fn try_apply_delete_rules<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    record_ids: &[String],
+    now: bson::DateTime,
+) -> Result<Vec<CacheOp>>
+where
+    T: MongoStorable,
+{
+    try_check_restrict::<T>(atlas, session.as_deref_mut(), record_ids).await?;
+    try_cascade::<T>(atlas, session, record_ids, now).await
+}
@@ -1,22 +1,102 @@
+//Lets #[derive(Storable)] refer to ::datastore from inside this crate as well
+extern crate self as datastore;
+
+mod audit;
 mod book_types;
 mod cache;
+mod collection;
+mod composite;
+mod config;
+mod error;
+mod health;
+mod history;
+mod ids;
+mod idempotency;
+mod indexes;
+mod integrity;
+mod migrations;
 mod mongodb;
+mod relations;
+mod retry;
+mod schema;
+mod soft_delete;
+mod tenant;
 mod test;
+mod timestamps;
+mod transaction;
+mod unique;
 
-use std::{borrow::Borrow, collections::HashMap};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-pub use crate::book_types::{Book, BookRecord, MongoStorable};
-use anyhow::Result;
-use bson::{from_document, to_document, Document};
+use crate::audit::{merged, prior_of, AuditDraft, CACHE_AUDIT_COLLECTION};
+pub use crate::audit::{
+    AuditConfig, AuditEntry, AuditFilter, AuditOperation, AuditSink, AuditStatus, FieldChange,
+};
+pub use crate::book_types::{Book, BookRecord, Bookstore, BookstoreRecord, MongoStorable};
+pub use crate::cache::breaker::{BreakerState, BreakerStatus};
+pub use datastore_derive::{BsonSchema, Storable};
+pub use crate::collection::Collection;
+pub use crate::composite::Composite;
+pub use crate::config::{
+    CircuitBreakerConfig, ConfigError, DatastoreConfig, HealthConfig, MongoPoolConfig,
+    ReadConcernLevel, RedisPoolConfig, RetryPolicy, WriteConcernConfig,
+};
+pub use crate::error::{DatastoreError, Result};
+pub use crate::health::{DependencyHealth, HealthReport, HealthStatus, PoolUsage, ReplicationRole};
+pub use crate::history::Version;
+pub use crate::ids::IdStrategy;
+pub use crate::indexes::{IndexDrift, IndexOrder, IndexReport, IndexSpec};
+pub use crate::integrity::{IntegrityError, OnDelete};
+use crate::migrations::{stamp_version, to_stored_document, try_upgrade};
+pub use crate::migrations::{Migration, MigrationError, MigrationReport};
+use crate::relations::relation_clear_ops;
+pub use crate::relations::{Cardinality, Relation, RelationIndex};
+pub use crate::schema::{BsonSchema, ValidationAction, ValidationLevel, ValidationReport};
+use crate::soft_delete::{visible, DELETED_AT_FIELD};
+pub use crate::soft_delete::ReadOptions;
+pub use crate::tenant::TenantError;
+use crate::timestamps::{
+    cache_value, stamp_created, stamp_updated, CREATED_AT_FIELD, UPDATED_AT_FIELD,
+};
+pub use crate::timestamps::{Clock, MockClock, ModifiedCursor, SystemClock, Timestamped};
+use bson::{doc, from_document, to_document, Document};
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use crate::cache::redis::{check_hash_key, record_cache_ops, CacheOp, RedisCache};
+use crate::mongodb::atlas::{outcome_error, Atlas};
+use crate::transaction::{is_transient, MAX_TRANSACTION_ATTEMPTS};
+pub use crate::transaction::Transaction;
+use crate::unique::{map_unique_violation, map_unique_violations, unique_clear_ops};
+pub use crate::unique::{UniqueKey, UniqueViolation};
 
-use crate::cache::redis::RedisCache;
-use crate::mongodb::atlas::Atlas;
+//Paths the Storable derive expands to, not part of the public API
+#[doc(hidden)]
+pub mod __private {
+    pub use bson;
+    pub use serde_json;
+}
 
+//Clones share the connections and the once-per-process bookkeeping, e.g. to hand each
+//request a copy tagged with_actor
+#[derive(Clone)]
 pub struct Datastore {
     pub database: Atlas,
     pub cache: RedisCache,
+    idempotency_index: Arc<OnceCell<()>>,
+    ensured_indexes: Arc<Mutex<HashSet<String>>>,
+    synced_cache_formats: Arc<Mutex<HashSet<&'static str>>>,
+    clock: Arc<dyn Clock>,
+    actor: Option<String>,
+    audit: Option<AuditConfig>,
+    audit_index: Arc<OnceCell<()>>,
+    audit_status: Arc<Mutex<AuditStatus>>,
+    tenant: Option<String>,
+    health: HealthConfig,
 }
 
 #[derive(Debug)]
@@ -31,34 +111,141 @@ enum CacheState {
     Miss,
 }
 
+impl<T> Cache<T> {
+    //Whether the record came from Redis rather than Atlas
+    pub fn is_hit(&self) -> bool {
+        self.state == CacheState::Hit
+    }
+
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpsertOutcome {
+    Inserted,
+    Updated,
+}
+
+#[derive(Debug)]
+pub struct Upsert<T> {
+    pub outcome: UpsertOutcome,
+    pub data: T,
+}
+
+#[derive(Clone, Debug)]
+pub enum WriteOp<T> {
+    Insert(T),
+    Update(T),
+    Upsert(T),
+    Delete(String),
+}
+
+//Per operation result of a bulk write, in the same order as the submitted operations
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteOutcome {
+    Inserted,
+    Updated,
+    Upserted(UpsertOutcome),
+    Deleted,
+    //The update or delete matched no record
+    NotFound,
+    Failed(String),
+    //Ordered batches stop at the first failure, everything after it is never sent
+    Skipped,
+}
+
+impl WriteOutcome {
+    pub fn is_applied(&self) -> bool {
+        !matches!(
+            self,
+            WriteOutcome::NotFound | WriteOutcome::Failed(_) | WriteOutcome::Skipped
+        )
+    }
+}
+
 impl Datastore {
+    //Connection settings come from the environment, see DatastoreConfig::from_env
     pub async fn try_new(db_name: &str) -> Result<Self> {
-        let atlas_connection = Atlas::try_new(db_name).await?;
-        let redis_connection = RedisCache::try_new().await?;
+        let config = DatastoreConfig::from_env()?.with_database(db_name);
+
+        Self::try_with_config(config).await
+    }
+
+    pub async fn try_with_config(config: DatastoreConfig) -> Result<Self> {
+        let atlas_connection = Atlas::try_with_config(&config).await?;
+        let redis_connection = RedisCache::try_with_config(&config).await?;
 
         Ok(Self {
             database: atlas_connection,
             cache: redis_connection,
+            idempotency_index: Arc::new(OnceCell::new()),
+            ensured_indexes: Arc::new(Mutex::new(HashSet::new())),
+            synced_cache_formats: Arc::new(Mutex::new(HashSet::new())),
+            clock: Arc::new(SystemClock),
+            actor: None,
+            audit: None,
+            audit_index: Arc::new(OnceCell::new()),
+            audit_status: Arc::new(Mutex::new(AuditStatus::default())),
+            tenant: None,
+            health: config.health.clone(),
         })
     }
 
+    //While the breaker is open reads are served from Atlas and writes skip the cache
+    pub fn cache_status(&self) -> BreakerStatus {
+        self.cache.breaker_status()
+    }
+
+    //Records with an empty id get one from T::ID_STRATEGY, the returned record carries it
     pub async fn try_create_one<T>(
         &self,
         table: &str,
         hash_key: &str,
-        record: T,
+        mut record: T,
         cache_expiry: Option<usize>,
     ) -> Result<T>
     //Borrow<Document>
     where
         T: Serialize + Clone + MongoStorable,
     {
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        ids::try_assign_ids(
+            &self.database,
+            &self.cache,
+            table,
+            std::slice::from_mut(&mut record),
+        )
+        .await?;
+
+        integrity::try_check_references(&self.database, None, &record).await?;
+        self.try_check_unique(table, &record).await?;
+
+        let now = self.now();
+        let mut document = to_stored_document(&record)?;
+        stamp_created(&mut document, now);
+        let audit_drafts = vec![AuditDraft::new(
+            AuditOperation::Create,
+            table,
+            record.get_id(),
+            None,
+            Some(document.clone()),
+        )];
+
+        //Atlas goes first, a rejected insert must not leave the record in the cache
         let _ = self
-            .cache
-            .try_cache_one(hash_key, record.clone(), cache_expiry)
-            .await?;
+            .database
+            .try_insert_one(table, document)
+            .await
+            .map_err(|err| map_unique_violation(err, &record))?;
+
+        let value = cache_value(&record, Some(now), now)?;
+        let cache_ops = record_cache_ops(table, hash_key, &record, value, cache_expiry)?;
+        self.cache.try_apply(cache_ops).await?;
 
-        let _ = self.database.try_insert_one(table, record.clone()).await?;
+        self.record_audit(audit_drafts, started).await;
 
         Ok(record)
     }
@@ -67,62 +254,134 @@ impl Datastore {
         &self,
         table: &str,
         hash_key: &str,
-        records: Vec<T>,
+        mut records: Vec<T>,
         cache_expiry: Option<usize>,
     ) -> Result<Vec<T>>
     where
-        T: Serialize + Borrow<Document> + MongoStorable + Clone,
+        T: Serialize + MongoStorable + Clone,
     {
-        let new_records = records.clone();
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        ids::try_assign_ids(&self.database, &self.cache, table, &mut records).await?;
 
-        for record in records.into_iter() {
-            self.cache
-                .try_cache_one(hash_key, record, cache_expiry)
-                .await?;
+        for record in records.iter() {
+            integrity::try_check_references(&self.database, None, record).await?;
+            self.try_check_unique(table, record).await?;
         }
 
+        let now = self.now();
+        let documents = records
+            .iter()
+            .map(|record| {
+                let mut document = to_stored_document(record)?;
+                stamp_created(&mut document, now);
+                Ok(document)
+            })
+            .collect::<Result<Vec<Document>>>()?;
+        let audit_drafts = records
+            .iter()
+            .zip(documents.iter())
+            .map(|(record, document)| {
+                AuditDraft::new(
+                    AuditOperation::Create,
+                    table,
+                    record.get_id(),
+                    None,
+                    Some(document.clone()),
+                )
+            })
+            .collect();
+
         let _ = self
             .database
-            .try_insert_many(table, new_records.clone())
-            .await?;
-        Ok(new_records)
+            .try_insert_many(table, documents)
+            .await
+            .map_err(|err| map_unique_violations(err, &records))?;
+
+        let mut cache_ops = Vec::new();
+        for record in records.iter() {
+            let value = cache_value(record, Some(now), now)?;
+            cache_ops.extend(record_cache_ops(table, hash_key, record, value, cache_expiry)?);
+        }
+        self.cache.try_apply(cache_ops).await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        Ok(records)
     }
 
+    //Cache entries that no longer parse are treated as misses. Documents written at an older
+    //schema version are upgraded on the way out and written back
     pub async fn try_read<T>(&self, table: &str, record_id: &str) -> Result<Cache<T>>
     where
-        T: for<'de> Deserialize<'de>,
+        T: for<'de> Deserialize<'de> + MongoStorable,
+    {
+        self.try_read_with(table, record_id, ReadOptions::default())
+            .await
+    }
+
+    //Soft-deleted records are never cached, including them only changes what Atlas returns
+    pub async fn try_read_with<T>(
+        &self,
+        table: &str,
+        record_id: &str,
+        options: ReadOptions,
+    ) -> Result<Cache<T>>
+    where
+        T: for<'de> Deserialize<'de> + MongoStorable,
     {
-        let cache_read_res = self.cache.try_read::<BookRecord>(record_id).await;
+        self.try_sync_cache_format_once::<T>().await;
+
+        //A miss, an unreadable entry or an unavailable cache all fall through to Atlas
+        let cache_read_res = self.cache.try_read(T::CACHE_NAMESPACE, record_id).await;
 
-        if let Ok(cache_value) = cache_read_res {
-            let json_value: serde_json::Value =
-                serde_json::from_str(&cache_value).expect("Failed to parse JSON string");
-            let cache_doc = bson::to_document(&json_value)?;
-            let cache_res = from_document::<T>(cache_doc)?;
+        if let Some(cache_res) = cache_read_res.ok().and_then(|value| cached_record::<T>(&value)) {
             let cache_struct = Cache {
                 state: CacheState::Hit,
                 data: cache_res,
             };
-            Ok(cache_struct)
-        } else {
-            let atlas_res = self.database.try_read_one::<T>(table, record_id).await?;
-            let db_res = from_document::<T>(atlas_res)?;
-            let cache_struct = Cache {
-                state: CacheState::Miss,
-                data: db_res,
-            };
-            Ok(cache_struct)
+            return Ok(cache_struct);
+        }
+
+        let mut atlas_res = self
+            .database
+            .try_find_one(table, visible(doc! { "_id": record_id }, options))
+            .await?;
+
+        let read_updated_at = atlas_res.get(UPDATED_AT_FIELD).cloned();
+        if let Some(from_version) = try_upgrade::<T>(&mut atlas_res)? {
+            let _ = self
+                .database
+                .try_replace_outdated(table, atlas_res.clone(), from_version, read_updated_at)
+                .await;
         }
+
+        let db_res = from_document::<T>(atlas_res)?;
+        let cache_struct = Cache {
+            state: CacheState::Miss,
+            data: db_res,
+        };
+        Ok(cache_struct)
     }
 
     pub async fn try_read_all(&self, table: &str) -> Result<Vec<Document>> {
-        let redis_value = self.cache.try_read_all(table).await;
+        self.try_read_all_with(table, ReadOptions::default()).await
+    }
 
+    pub async fn try_read_all_with(
+        &self,
+        table: &str,
+        options: ReadOptions,
+    ) -> Result<Vec<Document>> {
         //Set numerical limit of 100. Implement pagination
-        let res = self.database.try_read_all(table).await?;
+        let res = self
+            .database
+            .try_find_matching(table, visible(doc! {}, options), None)
+            .await?;
         Ok(res)
     }
 
+    //Atlas goes first so a missing record never leaves a stale entry in the cache
     pub async fn try_update_one<T>(
         &self,
         table: &str,
@@ -133,67 +392,772 @@ impl Datastore {
     where
         T: Serialize + MongoStorable + Clone,
     {
-        let _ = self
-            .cache
-            .try_cache_one(hash_key, update_record.clone(), cache_expiry)
-            .await?;
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        integrity::try_check_references(&self.database, None, &update_record).await?;
+        self.try_check_unique(table, &update_record).await?;
 
+        let now = self.now();
         let update_record_id = &update_record.get_id().to_owned();
-        let update_document = to_document(&update_record)?;
+        let mut update_document = to_stored_document(&update_record)?;
+        stamp_updated(&mut update_document, now);
+        //The prior comes back from the update itself, a concurrent write can't slip in between
+        let prior = self
+            .database
+            .try_update_one_prior(table, update_record_id, update_document.clone())
+            .await
+            .map_err(|err| map_unique_violation(err, &update_record))?;
+        let after = merged(Some(&prior), &update_document);
+        let audit_drafts = vec![AuditDraft::new(
+            AuditOperation::Update,
+            table,
+            update_record_id,
+            Some(prior.clone()),
+            Some(after),
+        )];
 
-        let _ = self
+        //Atlas holds the write, the cache follows it before anything else can fail
+        let value = cache_value(&update_record, None, now)?;
+        let cache_ops = record_cache_ops(table, hash_key, &update_record, value, cache_expiry)?;
+        self.cache.try_apply(cache_ops).await?;
+
+        self.try_write_history::<T>(vec![prior], now).await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        Ok(update_record)
+    }
+
+    //Create or replace. set_on_insert fields are only written when the record is new
+    //Returns the record as stored. On insert that includes the set_on_insert fields, the
+    //inserted document is read back for them
+    pub async fn try_upsert_one<T>(
+        &self,
+        table: &str,
+        hash_key: &str,
+        mut record: T,
+        set_on_insert: Option<Document>,
+        cache_expiry: Option<usize>,
+    ) -> Result<Upsert<T>>
+    where
+        T: for<'de> Deserialize<'de> + Serialize + MongoStorable + Clone,
+    {
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        //Without an id there is nothing to update, the upsert inserts under a fresh one
+        ids::try_assign_ids(
+            &self.database,
+            &self.cache,
+            table,
+            std::slice::from_mut(&mut record),
+        )
+        .await?;
+        integrity::try_check_references(&self.database, None, &record).await?;
+        self.try_check_unique(table, &record).await?;
+
+        let now = self.now();
+        let record_id = record.get_id().to_owned();
+        let mut record_document = to_stored_document(&record)?;
+        stamp_created(&mut record_document, now);
+        let sets_on_insert = set_on_insert.is_some();
+        //Same as for updates, the replaced document comes back from the upsert itself
+        let before = self
             .database
-            .try_update_one(table, update_record_id, update_document)
+            .try_upsert_one_prior(table, &record_id, record_document.clone(), set_on_insert)
+            .await
+            .map_err(|err| map_unique_violation(err, &record))?;
+
+        let outcome = match before {
+            None => UpsertOutcome::Inserted,
+            Some(_) => UpsertOutcome::Updated,
+        };
+        let after = match outcome {
+            UpsertOutcome::Inserted if sets_on_insert => {
+                let inserted = self.database.try_read_one(table, &record_id).await?;
+                record = from_document(inserted.clone())?;
+                inserted
+            }
+            _ => upserted(before.as_ref(), &record_document),
+        };
+
+        let audit_operation = match outcome {
+            UpsertOutcome::Inserted => AuditOperation::Create,
+            UpsertOutcome::Updated => AuditOperation::Update,
+        };
+        let prior: Vec<Document> = before.iter().cloned().collect();
+        let audit_drafts = vec![AuditDraft::new(
+            audit_operation,
+            table,
+            &record_id,
+            before,
+            Some(after),
+        )];
+
+        //An update keeps the created_at it had, which this write does not know
+        let created_at = match outcome {
+            UpsertOutcome::Inserted => Some(now),
+            UpsertOutcome::Updated => None,
+        };
+        let value = cache_value(&record, created_at, now)?;
+        let cache_ops = record_cache_ops(table, hash_key, &record, value, cache_expiry)?;
+        self.cache.try_apply(cache_ops).await?;
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        Ok(Upsert {
+            outcome,
+            data: record,
+        })
+    }
+
+    //One ordered bulk upsert. Records written before a failed one stay written and are
+    //cached, recorded in the history and audited before the failure is returned
+    pub async fn try_upsert_many<T>(
+        &self,
+        table: &str,
+        hash_key: &str,
+        mut records: Vec<T>,
+        set_on_insert: Option<Document>,
+        cache_expiry: Option<usize>,
+    ) -> Result<Vec<Upsert<T>>>
+    where
+        T: for<'de> Deserialize<'de> + Serialize + MongoStorable + Clone,
+    {
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        ids::try_assign_ids(&self.database, &self.cache, table, records.iter_mut()).await?;
+
+        for record in records.iter() {
+            integrity::try_check_references(&self.database, None, record).await?;
+            self.try_check_unique(table, record).await?;
+        }
+
+        let now = self.now();
+        let mut record_documents = Vec::new();
+        for record in records.iter() {
+            let mut record_document = to_stored_document(record)?;
+            stamp_created(&mut record_document, now);
+            record_documents.push(record_document);
+        }
+        let record_ids: Vec<String> = records
+            .iter()
+            .map(|record| record.get_id().to_owned())
+            .collect();
+        let prior = self.try_read_prior::<T>(table, &record_ids).await?;
+        let sets_on_insert = set_on_insert.is_some();
+
+        let outcomes = self
+            .database
+            .try_upsert_many(table, record_documents.clone(), set_on_insert)
             .await?;
-        Ok(update_record)
+
+        let failure = records
+            .iter()
+            .zip(outcomes.iter())
+            .find_map(|(record, outcome)| {
+                outcome_error(table, record.get_id(), outcome)
+                    .map(|err| map_unique_violation(err, record))
+            });
+
+        let inserted_ids: Vec<String> = records
+            .iter()
+            .zip(outcomes.iter())
+            .filter(|(_, outcome)| **outcome == WriteOutcome::Upserted(UpsertOutcome::Inserted))
+            .map(|(record, _)| record.get_id().to_owned())
+            .collect();
+        //The set_on_insert fields only exist in what the inserts left behind
+        let inserted = match sets_on_insert && !inserted_ids.is_empty() {
+            true => {
+                self.database
+                    .try_read_documents_by_ids(table, inserted_ids)
+                    .await?
+            }
+            false => Vec::new(),
+        };
+
+        let mut upserts = Vec::new();
+        let mut cache_ops = Vec::new();
+        let mut audit_drafts = Vec::new();
+        let mut replaced_prior = Vec::new();
+
+        for ((record, record_document), outcome) in records
+            .into_iter()
+            .zip(record_documents.iter())
+            .zip(outcomes)
+        {
+            let outcome = match outcome {
+                WriteOutcome::Upserted(outcome) => outcome,
+                _ => continue,
+            };
+
+            let before = prior_of(&prior, record.get_id());
+            let (record, after, created_at) = match outcome {
+                UpsertOutcome::Inserted => match prior_of(&inserted, record.get_id()) {
+                    Some(document) => (from_document::<T>(document.clone())?, document, Some(now)),
+                    None => (record, upserted(None, record_document), Some(now)),
+                },
+                UpsertOutcome::Updated => {
+                    (record, upserted(before.as_ref(), record_document), None)
+                }
+            };
+
+            let audit_operation = match outcome {
+                UpsertOutcome::Inserted => AuditOperation::Create,
+                UpsertOutcome::Updated => AuditOperation::Update,
+            };
+            if let Some(before) = &before {
+                replaced_prior.push(before.clone());
+            }
+            audit_drafts.push(AuditDraft::new(
+                audit_operation,
+                table,
+                record.get_id(),
+                before,
+                Some(after),
+            ));
+
+            let value = cache_value(&record, created_at, now)?;
+            cache_ops.extend(record_cache_ops(
+                table,
+                hash_key,
+                &record,
+                value,
+                cache_expiry,
+            )?);
+            upserts.push(Upsert {
+                outcome,
+                data: record,
+            });
+        }
+
+        //Atlas holds the upserts, the cache follows them before the failure is returned
+        self.cache.try_apply(cache_ops).await?;
+
+        self.try_write_history::<T>(replaced_prior, now).await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(upserts),
+        }
     }
 
     pub async fn try_update_many<T>(
         &self,
         table: &str,
+        hash_key: &str,
         update_map: HashMap<String, Document>,
     ) -> Result<Vec<Document>>
     where
         T: for<'de> Deserialize<'de> + Serialize + MongoStorable,
     {
-        let records_vec: Vec<T> = update_map
-            .clone()
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+
+        let now = self.now();
+        let mut updates = Vec::new();
+        for (record_id, mut document) in update_map {
+            let record: T = from_document(document.clone())?;
+            stamp_version::<T>(&mut document);
+            stamp_updated(&mut document, now);
+            document.insert("_id", record_id.clone());
+            updates.push((record_id, document, record));
+        }
+
+        //Atlas skips soft-deleted records without telling, they would end up in the cache
+        let record_ids: Vec<String> = updates
+            .iter()
+            .map(|(record_id, _, _)| record_id.clone())
+            .collect();
+        if let Some(deleted_id) = self.try_deleted_ids::<T>(record_ids.clone()).await?.first() {
+            return Err(DatastoreError::not_found(table, deleted_id));
+        }
+
+        for (_, _, record) in updates.iter() {
+            integrity::try_check_references(&self.database, None, record).await?;
+            self.try_check_unique(table, record).await?;
+        }
+
+        let prior = self.try_read_prior::<T>(table, &record_ids).await?;
+
+        let operations = updates
+            .iter()
+            .map(|(_, document, _)| WriteOp::Update(document.clone()))
+            .collect();
+        let outcomes = self
+            .database
+            .try_bulk_write(table, operations, false, None)
+            .await?;
+
+        //Updates that went through are cached, kept in the history and audited even when
+        //others in the batch failed. The first failure is returned after that
+        let mut failure = None;
+        let mut response = Vec::new();
+        let mut cache_ops = Vec::new();
+        let mut audit_drafts = Vec::new();
+        let mut replaced_prior = Vec::new();
+
+        for ((record_id, document, record), outcome) in updates.into_iter().zip(outcomes.iter()) {
+            if let Some(err) = outcome_error(table, &record_id, outcome) {
+                if failure.is_none() {
+                    failure = Some(map_unique_violation(err, &record));
+                }
+                continue;
+            }
+
+            let before = prior_of(&prior, &record_id);
+            let after = merged(before.as_ref(), &document);
+            if let Some(before) = &before {
+                replaced_prior.push(before.clone());
+            }
+            audit_drafts.push(AuditDraft::new(
+                AuditOperation::Update,
+                table,
+                &record_id,
+                before,
+                Some(after),
+            ));
+
+            let value = cache_value(&record, None, now)?;
+            cache_ops.extend(record_cache_ops(table, hash_key, &record, value, None)?);
+            response.push(document);
+        }
+
+        //Atlas holds the updates, the cache follows them before anything else can fail
+        self.cache.try_apply(cache_ops).await?;
+
+        self.try_write_history::<T>(replaced_prior, now).await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(response),
+        }
+    }
+
+    //Mixed batch of writes sent as Mongo bulk commands, followed by a single
+    //MULTI/EXEC pipeline that mirrors every operation Atlas accepted into the cache.
+    //Operations breaking an integrity rule are reported as Failed without being sent, same
+    //as updates of soft-deleted records. Deletes of a soft-delete type only set deleted_at.
+    //References are checked against what Atlas held before the batch, a record can not point
+    //at one inserted earlier in the same batch. Cascades only run for deletes that matched,
+    //a batch deleting records of a type with on-delete rules is sent in one transaction
+    pub async fn bulk_write<T>(
+        &self,
+        table: &str,
+        hash_key: &str,
+        mut operations: Vec<WriteOp<T>>,
+        ordered: bool,
+        cache_expiry: Option<usize>,
+    ) -> Result<Vec<WriteOutcome>>
+    where
+        T: Serialize + MongoStorable,
+    {
+        check_hash_key::<T>(hash_key)?;
+        let started = Instant::now();
+        let new_records = operations.iter_mut().filter_map(|operation| match operation {
+            WriteOp::Insert(record) | WriteOp::Upsert(record) => Some(record),
+            _ => None,
+        });
+        ids::try_assign_ids(&self.database, &self.cache, table, new_records).await?;
+
+        let update_ids = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                WriteOp::Update(record) => Some(record.get_id().to_owned()),
+                _ => None,
+            })
+            .collect();
+        let soft_deleted_ids = self.try_deleted_ids::<T>(update_ids).await?;
+
+        let mut rejections = Vec::new();
+
+        for operation in operations.iter() {
+            let check = match operation {
+                WriteOp::Update(record)
+                    if soft_deleted_ids.iter().any(|id| id == record.get_id()) =>
+                {
+                    rejections.push(Some(WriteOutcome::NotFound));
+                    continue;
+                }
+                WriteOp::Insert(record) | WriteOp::Update(record) | WriteOp::Upsert(record) => {
+                    match integrity::try_check_references(&self.database, None, record).await {
+                        Ok(()) => self.try_check_unique(table, record).await,
+                        Err(err) => Err(err),
+                    }
+                }
+                WriteOp::Delete(record_id) => {
+                    integrity::try_check_restrict::<T>(&self.database, None, &[record_id.to_owned()])
+                        .await
+                }
+            };
+
+            let rejection = match check {
+                Ok(()) => None,
+                Err(err) if err.is::<IntegrityError>() || err.is::<UniqueViolation>() => {
+                    Some(WriteOutcome::Failed(err.to_string()))
+                }
+                Err(err) => return Err(err),
+            };
+            rejections.push(rejection);
+        }
+
+        //An ordered batch never sends anything past its first rejected operation
+        let cutoff = match ordered {
+            true => rejections
+                .iter()
+                .position(Option::is_some)
+                .unwrap_or(rejections.len()),
+            false => rejections.len(),
+        };
+
+        let now = self.now();
+        let mut document_ops = Vec::new();
+
+        for (operation, rejection) in operations.iter().zip(rejections.iter()).take(cutoff) {
+            if rejection.is_some() {
+                continue;
+            }
+
+            let document_op = match operation {
+                WriteOp::Insert(record) => {
+                    let mut document = to_stored_document(record)?;
+                    stamp_created(&mut document, now);
+                    WriteOp::Insert(document)
+                }
+                WriteOp::Update(record) => {
+                    let mut document = to_stored_document(record)?;
+                    stamp_updated(&mut document, now);
+                    WriteOp::Update(document)
+                }
+                //created_at is moved to $setOnInsert, see Atlas::try_bulk_write
+                WriteOp::Upsert(record) => {
+                    let mut document = to_stored_document(record)?;
+                    stamp_created(&mut document, now);
+                    WriteOp::Upsert(document)
+                }
+                WriteOp::Delete(record_id) if T::SOFT_DELETE => WriteOp::Update(doc! {
+                    "_id": record_id,
+                    DELETED_AT_FIELD: now,
+                    UPDATED_AT_FIELD: now,
+                }),
+                WriteOp::Delete(record_id) => WriteOp::Delete(record_id.to_owned()),
+            };
+            document_ops.push(document_op);
+        }
+
+        let replaced_ids: Vec<String> = operations
+            .iter()
+            .zip(rejections.iter())
+            .take(cutoff)
+            .filter(|(_, rejection)| rejection.is_none())
+            .filter_map(|(operation, _)| match operation {
+                WriteOp::Update(record) | WriteOp::Upsert(record) => {
+                    Some(record.get_id().to_owned())
+                }
+                WriteOp::Delete(record_id) => Some(record_id.to_owned()),
+                WriteOp::Insert(_) => None,
+            })
+            .collect();
+        //Hard deletes with on-delete rules commit or roll back together with the rules
+        //they trigger, and the restrict check is repeated inside the transaction
+        let with_delete_rules = !T::SOFT_DELETE
+            && document_ops
+                .iter()
+                .any(|operation| matches!(operation, WriteOp::Delete(_)))
+            && T::relations()
+                .iter()
+                .any(|relation| relation.on_delete.is_some());
+
+        let (prior, sent_outcomes) = match with_delete_rules {
+            true => {
+                self.transaction(|tx| {
+                    let (table, document_ops, replaced_ids) =
+                        (table.to_owned(), document_ops.clone(), replaced_ids.clone());
+                    Box::pin(async move {
+                        tx.try_bulk_write::<T>(&table, document_ops, &replaced_ids, ordered, now)
+                            .await
+                    })
+                })
+                .await?
+            }
+            false => {
+                let prior = self.try_read_prior::<T>(table, &replaced_ids).await?;
+                let sent_outcomes = self
+                    .database
+                    .try_bulk_write(table, document_ops, ordered, None)
+                    .await?;
+                (prior, sent_outcomes)
+            }
+        };
+        let mut sent_outcomes = sent_outcomes.into_iter();
+
+        let mut outcomes = Vec::new();
+        let mut halted = false;
+
+        for (index, rejection) in rejections.into_iter().enumerate() {
+            let outcome = if halted || index >= cutoff {
+                WriteOutcome::Skipped
+            } else if let Some(rejected) = rejection {
+                rejected
+            } else {
+                match (&operations[index], sent_outcomes.next()) {
+                    //Sent as an update of deleted_at
+                    (WriteOp::Delete(_), Some(WriteOutcome::Updated)) => WriteOutcome::Deleted,
+                    (_, sent_outcome) => sent_outcome.unwrap_or(WriteOutcome::Skipped),
+                }
+            };
+
+            halted |= ordered && !outcome.is_applied();
+            outcomes.push(outcome);
+        }
+
+        let mut audit_drafts = Vec::new();
+        for (operation, outcome) in operations.iter().zip(outcomes.iter()) {
+            if !outcome.is_applied() {
+                continue;
+            }
+
+            let audit_draft = match operation {
+                WriteOp::Insert(record) => AuditDraft::new(
+                    AuditOperation::Create,
+                    table,
+                    record.get_id(),
+                    None,
+                    Some(to_stored_document(record)?),
+                ),
+                WriteOp::Update(record) | WriteOp::Upsert(record) => {
+                    let before = prior_of(&prior, record.get_id());
+                    let mut after = merged(before.as_ref(), &to_stored_document(record)?);
+                    after.remove(DELETED_AT_FIELD);
+                    let audit_operation = match outcome {
+                        WriteOutcome::Upserted(UpsertOutcome::Inserted) => AuditOperation::Create,
+                        _ => AuditOperation::Update,
+                    };
+                    AuditDraft::new(audit_operation, table, record.get_id(), before, Some(after))
+                }
+                WriteOp::Delete(record_id) => {
+                    let before = prior_of(&prior, record_id);
+                    let after = match T::SOFT_DELETE {
+                        true => Some(merged(before.as_ref(), &doc! { DELETED_AT_FIELD: now })),
+                        false => None,
+                    };
+                    AuditDraft::new(AuditOperation::Delete, table, record_id, before, after)
+                }
+            };
+            audit_drafts.push(audit_draft);
+        }
+
+        let mut cache_ops = Vec::new();
+
+        for (operation, outcome) in operations.iter().zip(outcomes.iter()) {
+            if !outcome.is_applied() {
+                continue;
+            }
+
+            match operation {
+                WriteOp::Insert(record) | WriteOp::Update(record) | WriteOp::Upsert(record) => {
+                    let created_at = match (operation, outcome) {
+                        (WriteOp::Insert(_), _)
+                        | (_, WriteOutcome::Upserted(UpsertOutcome::Inserted)) => Some(now),
+                        _ => None,
+                    };
+                    let value = cache_value(record, created_at, now)?;
+                    cache_ops.extend(record_cache_ops(table, hash_key, record, value, cache_expiry)?);
+                }
+                WriteOp::Delete(record_id) => {
+                    cache_ops.push(CacheOp::Delete {
+                        hash_key: hash_key.to_owned(),
+                        field: record_id.to_owned(),
+                    });
+                    cache_ops.extend(relation_clear_ops::<T>(record_id));
+                    cache_ops.extend(unique_clear_ops::<T>(table, record_id));
+                }
+            }
+        }
+
+        //Atlas holds the batch, the cache follows it before anything else can fail
+        self.cache.try_apply(cache_ops).await?;
+
+        //Only the versions an applied operation replaced go to the history
+        let replaced_ids: Vec<&str> = operations
+            .iter()
+            .zip(outcomes.iter())
+            .filter(|(_, outcome)| outcome.is_applied())
+            .filter_map(|(operation, _)| match operation {
+                WriteOp::Update(record) | WriteOp::Upsert(record) => Some(record.get_id()),
+                WriteOp::Delete(record_id) => Some(record_id.as_str()),
+                WriteOp::Insert(_) => None,
+            })
+            .collect();
+        let prior = prior
             .into_iter()
-            .map(|(_, document)| from_document(document).unwrap())
+            .filter(|document| {
+                document
+                    .get_str("_id")
+                    .map(|id| replaced_ids.contains(&id))
+                    .unwrap_or(false)
+            })
             .collect();
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.record_audit(audit_drafts, started).await;
 
-        let _ = self.cache.try_update_many(records_vec).await?;
+        Ok(outcomes)
+    }
 
-        let response = self.database.try_update_many(table, update_map).await?;
-        Ok(response)
+    pub async fn try_delete<T>(&self, table: &str, record_id: &str) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        //The delete rules, or for soft-delete types the history, commit or roll back
+        //together with the delete itself
+        let (table, record_id) = (table.to_owned(), record_id.to_owned());
+        self.transaction(|tx| {
+            let (table, record_id) = (table.clone(), record_id.clone());
+            Box::pin(async move {
+                tx.try_delete::<T>(&table, T::CACHE_NAMESPACE, &record_id)
+                    .await
+            })
+        })
+        .await
+    }
+    pub async fn try_delete_many<T>(&self, table: &str, delete_ids: Vec<String>) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        let table = table.to_owned();
+        self.transaction(|tx| {
+            let (table, delete_ids) = (table.clone(), delete_ids.clone());
+            Box::pin(async move {
+                match T::SOFT_DELETE {
+                    true => tx
+                        .try_soft_delete_many::<T>(&table, T::CACHE_NAMESPACE, &delete_ids)
+                        .await
+                        .map(|_| ()),
+                    false => {
+                        tx.try_delete_many::<T>(&table, T::CACHE_NAMESPACE, &delete_ids)
+                            .await
+                    }
+                }
+            })
+        })
+        .await
     }
 
-    pub async fn try_delete(&self, table: &str, record_id: &str) -> Result<()> {
-        let _ = self.database.try_delete_one(table, record_id).await?;
+    //Runs the closure inside a Mongo transaction, retrying the whole thing on
+    //TransientTransactionError. Cache writes made through the handle only reach
+    //Redis after the commit succeeded, an aborted attempt leaves the cache untouched.
+    //The same goes for the audit log
+    //
+    //let book = data_store
+    //    .transaction(|tx| Box::pin(async move { tx.try_read::<BookRecord>("books", id).await }))
+    //    .await?;
+    pub async fn transaction<R, F>(&self, mut operation: F) -> Result<R>
+    where
+        F: for<'t> FnMut(&'t mut Transaction) -> BoxFuture<'t, Result<R>>,
+    {
+        let started = Instant::now();
+        let session = self.database.client.start_session(None).await?;
+        let mut transaction = Transaction::new(
+            self.database.clone(),
+            self.cache.clone(),
+            session,
+            self.clock.clone(),
+            self.actor.clone(),
+            self.is_audited(),
+        );
+        let mut attempt = 1;
 
-        let _ = self.cache.try_delete("books", &record_id).await?;
+        loop {
+            transaction.try_begin().await?;
 
-        Ok(())
+            let result = match operation(&mut transaction).await {
+                Ok(value) => transaction
+                    .try_commit()
+                    .await
+                    .map(|committed| (value, committed)),
+                Err(err) => {
+                    transaction.abort().await;
+                    Err(err)
+                }
+            };
+
+            match result {
+                Ok((value, (cache_ops, audit_drafts))) => {
+                    self.cache.try_apply(cache_ops).await?;
+                    self.record_audit(audit_drafts, started).await;
+                    return Ok(value);
+                }
+                Err(err) if is_transient(&err) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
     }
-    pub async fn try_delete_many(&self, table: &str, delete_ids: Vec<String>) -> Result<()> {
-        let _ = self
-            .database
-            .try_delete_many(table, delete_ids.clone())
-            .await?;
 
-        let _ = self.cache.try_delete_many(delete_ids);
+    //Empties table and the cache, for tests and admin tooling. On the root handle the
+    //tenants' cache keys are kept, see RedisCache::try_clear_cache
+    pub async fn try_clear_datastore(&self, table: &str) -> Result<()> {
+        let started = Instant::now();
+        self.database.try_delete_all(table).await?;
+        self.cache.try_clear_cache().await?;
+
+        self.record_audit(vec![AuditDraft::clear(table)], started).await;
+
         Ok(())
     }
 
-    async fn clear_datastore(&self, table: &str) -> Result<()> {
-        let _ = self.database.try_delete_all(table).await?;
-        let _ = self.cache.try_clear_cache().await?;
+    //Empties the cache, every collection is read from Atlas again. Same as for
+    //try_clear_datastore, the root handle leaves the tenants' keys alone
+    pub async fn try_clear_cache(&self) -> Result<()> {
+        let started = Instant::now();
+        self.cache.try_clear_cache().await?;
+
+        self.record_audit(vec![AuditDraft::clear(CACHE_AUDIT_COLLECTION)], started)
+            .await;
 
         Ok(())
     }
     //Interface for Redis search
     pub async fn try_read_many(&self, table: &str, ids: Vec<String>) -> Result<Vec<Document>> {
-        let res = self.database.try_read_documents_by_ids(table, ids).await?;
+        self.try_read_many_with(table, ids, ReadOptions::default())
+            .await
+    }
+
+    pub async fn try_read_many_with(
+        &self,
+        table: &str,
+        ids: Vec<String>,
+        options: ReadOptions,
+    ) -> Result<Vec<Document>> {
+        let filter = visible(doc! { "_id": { "$in": ids } }, options);
+        let res = self.database.try_find_matching(table, filter, None).await?;
         Ok(res)
     }
 }
+
+//What an upsert of the record leaves in Atlas. created_at only comes from the insert and a
+//soft-deleted record is brought back
+fn upserted(before: Option<&Document>, record_document: &Document) -> Document {
+    let mut after = merged(before, record_document);
+    if let Some(created_at) = before.and_then(|before| before.get(CREATED_AT_FIELD)) {
+        after.insert(CREATED_AT_FIELD, created_at.clone());
+    }
+    after.remove(DELETED_AT_FIELD);
+    after
+}
+
+fn cached_record<T>(cache_value: &str) -> Option<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let json_value: Value = serde_json::from_str(cache_value).ok()?;
+    let cache_doc = to_document(&json_value).ok()?;
+    from_document::<T>(cache_doc).ok()
+}
@@ -0,0 +1,301 @@
+use bson::{doc, to_document, Bson, DateTime, Document};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::book_types::MongoStorable;
+use crate::cache::redis::CacheOp;
+use crate::error::Result;
+use crate::timestamps::UPDATED_AT_FIELD;
+use crate::Datastore;
+
+//Field every document of a versioned type carries. Documents without it predate the
+//first migration and count as version 0
+pub const SCHEMA_VERSION_FIELD: &str = "_schema_version";
+
+//One row per applied migration, keyed "{collection}:{version}"
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+
+//Documents are upgraded this many at a time by try_migrate
+const MIGRATION_BATCH_SIZE: i64 = 500;
+
+//Upgrades a stored document from version - 1 to version. Migrations only ever see the raw
+//document, the record type may no longer be able to deserialize what they start from.
+//Whatever they fail with is reported as MigrationError::Failed
+#[derive(Clone, Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub up: fn(&mut Document) -> anyhow::Result<()>,
+}
+
+impl Migration {
+    pub fn new(
+        version: u32,
+        description: &'static str,
+        up: fn(&mut Document) -> anyhow::Result<()>,
+    ) -> Self {
+        Self {
+            version,
+            description,
+            up,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("migrations of {collection} must run 1 to {schema_version} without gaps, {reason}")]
+    InvalidPlan {
+        collection: String,
+        schema_version: u32,
+        reason: String,
+    },
+    #[error("migration {version} of {collection} failed on {record_id}: {reason}")]
+    Failed {
+        collection: String,
+        record_id: String,
+        version: u32,
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    pub collection: String,
+    pub schema_version: u32,
+    pub migrated: u64,
+    //The cache held entries written in an older format and was cleared
+    pub cache_invalidated: bool,
+}
+
+//The declared migrations in order, checked to lead exactly up to T::SCHEMA_VERSION
+fn migration_plan<T>() -> Result<Vec<Migration>>
+where
+    T: MongoStorable,
+{
+    let mut migrations = T::migrations();
+    migrations.sort_by_key(|migration| migration.version);
+
+    let versions: Vec<u32> = migrations
+        .iter()
+        .map(|migration| migration.version)
+        .collect();
+    let expected: Vec<u32> = (1..=T::SCHEMA_VERSION).collect();
+
+    if versions != expected {
+        return Err(MigrationError::InvalidPlan {
+            collection: T::COLLECTION.to_owned(),
+            schema_version: T::SCHEMA_VERSION,
+            reason: format!("declared {:?}", versions),
+        }
+        .into());
+    }
+
+    Ok(migrations)
+}
+
+pub(crate) fn stored_version(document: &Document) -> u32 {
+    match document.get(SCHEMA_VERSION_FIELD) {
+        Some(Bson::Int32(version)) => *version as u32,
+        Some(Bson::Int64(version)) => *version as u32,
+        Some(Bson::Double(version)) => *version as u32,
+        _ => 0,
+    }
+}
+
+//Unversioned types are left exactly as serde produces them
+pub(crate) fn stamp_version<T>(document: &mut Document)
+where
+    T: MongoStorable,
+{
+    if T::SCHEMA_VERSION > 0 {
+        document.insert(SCHEMA_VERSION_FIELD, T::SCHEMA_VERSION as i64);
+    }
+}
+
+//The document a record is written to Atlas as, stamped with the current schema version
+pub(crate) fn to_stored_document<T>(record: &T) -> Result<Document>
+where
+    T: MongoStorable + Serialize,
+{
+    let mut document = to_document(record)?;
+    stamp_version::<T>(&mut document);
+    Ok(document)
+}
+
+//Runs every migration the document has not seen yet. Returns the version it started at
+//when anything changed. Documents written by a newer deployment are passed through as is
+pub(crate) fn try_upgrade<T>(document: &mut Document) -> Result<Option<u32>>
+where
+    T: MongoStorable,
+{
+    let from_version = stored_version(document);
+
+    if from_version >= T::SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    for migration in migration_plan::<T>()? {
+        if migration.version <= from_version {
+            continue;
+        }
+
+        (migration.up)(document).map_err(|err| MigrationError::Failed {
+            collection: T::COLLECTION.to_owned(),
+            record_id: document
+                .get("_id")
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            version: migration.version,
+            reason: err.to_string(),
+        })?;
+    }
+
+    document.insert(SCHEMA_VERSION_FIELD, T::SCHEMA_VERSION as i64);
+    Ok(Some(from_version))
+}
+
+fn cache_format_key(namespace: &str) -> String {
+    format!("cache_format:{}", namespace)
+}
+
+impl Datastore {
+    //Eagerly upgrades every outdated document of T's collection, a batch at a time, and
+    //records the applied migrations. Reads upgrade lazily as well, so this can run while
+    //the new version is already serving
+    pub async fn try_migrate<T>(&self) -> Result<MigrationReport>
+    where
+        T: MongoStorable,
+    {
+        let migrations = migration_plan::<T>()?;
+
+        let mut report = MigrationReport {
+            collection: T::COLLECTION.to_owned(),
+            schema_version: T::SCHEMA_VERSION,
+            ..MigrationReport::default()
+        };
+
+        if T::SCHEMA_VERSION > 0 {
+            report.migrated = self.try_migrate_documents::<T>().await?;
+        }
+
+        for migration in migrations.iter() {
+            let _ = self
+                .database
+                .try_upsert_one(
+                    MIGRATIONS_COLLECTION,
+                    &format!("{}:{}", T::COLLECTION, migration.version),
+                    doc! {
+                        "collection": T::COLLECTION,
+                        "version": migration.version as i64,
+                        "description": migration.description,
+                    },
+                    Some(doc! { "applied_at": DateTime::now() }),
+                )
+                .await?;
+        }
+
+        report.cache_invalidated = self.try_sync_cache_format::<T>().await?;
+
+        Ok(report)
+    }
+
+    //Upgraded documents drop out of the filter, so every pass picks up the next batch
+    async fn try_migrate_documents<T>(&self) -> Result<u64>
+    where
+        T: MongoStorable,
+    {
+        let outdated = doc! {
+            "$or": [
+                { SCHEMA_VERSION_FIELD: { "$lt": T::SCHEMA_VERSION as i64 } },
+                { SCHEMA_VERSION_FIELD: { "$exists": false } },
+            ]
+        };
+        let mut migrated = 0;
+
+        loop {
+            let batch = self
+                .database
+                .try_find_matching(T::COLLECTION, outdated.clone(), Some(MIGRATION_BATCH_SIZE))
+                .await?;
+
+            let mut replaced_in_batch = 0;
+
+            for mut document in batch {
+                let read_updated_at = document.get(UPDATED_AT_FIELD).cloned();
+
+                if let Some(from_version) = try_upgrade::<T>(&mut document)? {
+                    let replaced = self
+                        .database
+                        .try_replace_outdated(
+                            T::COLLECTION,
+                            document,
+                            from_version,
+                            read_updated_at,
+                        )
+                        .await?;
+                    replaced_in_batch += replaced as u64;
+                }
+            }
+
+            //Nothing left, or nothing that can be replaced right now
+            if replaced_in_batch == 0 {
+                return Ok(migrated);
+            }
+            migrated += replaced_in_batch;
+        }
+    }
+
+    //Cached JSON is written in the format of T::SCHEMA_VERSION. A namespace whose entries
+    //were written at another version is cleared and restamped. Returns whether it was
+    pub async fn try_sync_cache_format<T>(&self) -> Result<bool>
+    where
+        T: MongoStorable,
+    {
+        let format_key = cache_format_key(T::CACHE_NAMESPACE);
+
+        let cached_version = self
+            .cache
+            .try_get(&format_key)
+            .await?
+            .and_then(|version| version.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        let outdated = cached_version != T::SCHEMA_VERSION;
+
+        if outdated {
+            self.cache
+                .try_apply(vec![CacheOp::Evict {
+                    keys: vec![T::CACHE_NAMESPACE.to_owned()],
+                }])
+                .await?;
+        }
+
+        self.cache
+            .try_set(&format_key, T::SCHEMA_VERSION.to_string(), None)
+            .await?;
+
+        if let Ok(mut namespaces) = self.synced_cache_formats.lock() {
+            namespaces.insert(T::CACHE_NAMESPACE);
+        }
+
+        Ok(outdated)
+    }
+
+    //Reads call this before trusting the cache, it only goes to Redis the first time per
+    //namespace and process. A cache outage is left for the read itself to deal with
+    pub(crate) async fn try_sync_cache_format_once<T>(&self)
+    where
+        T: MongoStorable,
+    {
+        let synced = self
+            .synced_cache_formats
+            .lock()
+            .map(|namespaces| namespaces.contains(T::CACHE_NAMESPACE))
+            .unwrap_or(false);
+
+        if !synced {
+            let _ = self.try_sync_cache_format::<T>().await;
+        }
+    }
+}
@@ -1,34 +1,259 @@
-use anyhow::{anyhow, Result};
-use bson::{doc, from_document, to_document, Document};
+use bson::{doc, from_document, to_document, Bson, Document};
 
-use dotenv::dotenv;
 use futures::StreamExt;
 use mongodb::{
+    error::{Error as MongoError, ErrorKind, WriteFailure},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument,
+        UpdateOptions,
+    },
     results::{InsertManyResult, InsertOneResult},
-    Client, Database,
+    Client, ClientSession, Database, IndexModel,
 };
 
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{collections::HashMap, env};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, time::Duration};
 
-use crate::book_types::MongoStorable;
+use crate::config::{DatastoreConfig, RetryPolicy};
+use crate::error::{DatastoreError, Result};
+use crate::retry::{try_with_retry, Idempotency};
 
-#[derive(Debug)]
+use crate::migrations::SCHEMA_VERSION_FIELD;
+use crate::relations::Relation;
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::timestamps::{CREATED_AT_FIELD, UPDATED_AT_FIELD};
+use crate::{UpsertOutcome, WriteOp, WriteOutcome};
+
+//Mongo bulk commands only take one kind of write at a time
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BulkKind {
+    Insert,
+    Update,
+    Upsert,
+    Delete,
+}
+
+//A write with its position in the caller's batch
+type IndexedOp = (usize, WriteOp<Document>);
+
+//How every command of one bulk write is sent
+#[derive(Clone, Copy)]
+struct BulkOptions<'a> {
+    ordered: bool,
+    //Only read by upserts, see Atlas::upsert_statement
+    set_on_insert: Option<&'a Document>,
+}
+
+impl BulkKind {
+    fn of(operation: &WriteOp<Document>) -> Self {
+        match operation {
+            WriteOp::Insert(_) => BulkKind::Insert,
+            WriteOp::Update(_) => BulkKind::Update,
+            WriteOp::Upsert(_) => BulkKind::Upsert,
+            WriteOp::Delete(_) => BulkKind::Delete,
+        }
+    }
+
+    //Update and delete commands only report how many documents matched in total, which
+    //records they match is read before they are sent
+    fn is_counted(&self) -> bool {
+        matches!(self, BulkKind::Update | BulkKind::Delete)
+    }
+
+    //Live records for updates, any record for deletes
+    fn matched_filter(&self, record_ids: Vec<Bson>) -> Document {
+        match self {
+            BulkKind::Update => doc! { "_id": { "$in": record_ids }, DELETED_AT_FIELD: Bson::Null },
+            _ => doc! { "_id": { "$in": record_ids } },
+        }
+    }
+}
+
+//The _id an update or delete goes by
+fn write_id(operation: &WriteOp<Document>) -> Option<Bson> {
+    match operation {
+        WriteOp::Update(document) => document.get("_id").cloned(),
+        WriteOp::Delete(record_id) => Some(Bson::String(record_id.to_owned())),
+        WriteOp::Insert(_) | WriteOp::Upsert(_) => None,
+    }
+}
+
+//The error behind a bulk write outcome, None for operations that were applied or skipped
+pub(crate) fn outcome_error(
+    table: &str,
+    record_id: &str,
+    outcome: &WriteOutcome,
+) -> Option<DatastoreError> {
+    match outcome {
+        WriteOutcome::NotFound => Some(DatastoreError::not_found(table, record_id)),
+        WriteOutcome::Failed(message) if message.starts_with(DUPLICATE_KEY_PREFIX) => Some(
+            DatastoreError::with_code(DUPLICATE_KEY_CODE, anyhow::anyhow!(message.to_owned())),
+        ),
+        WriteOutcome::Failed(message) => Some(DatastoreError::Internal(anyhow::anyhow!(
+            "Could not write {}: {}",
+            record_id,
+            message
+        ))),
+        _ => None,
+    }
+}
+
+//The update behind an upsert of a whole record. _id comes from the query on insert and
+//is immutable on update
+fn upsert_update(mut upsert_record: Document, set_on_insert: Option<Document>) -> Document {
+    upsert_record.remove("_id");
+
+    //Same as for bulk upserts, created_at is only written by the insert
+    let mut insert_fields = set_on_insert.unwrap_or_default();
+    if let Some(created_at) = upsert_record.remove(CREATED_AT_FIELD) {
+        insert_fields.insert(CREATED_AT_FIELD, created_at);
+    }
+
+    //Upserting a soft-deleted record brings it back
+    let mut update = doc! {
+        "$set": upsert_record,
+        "$unset": { DELETED_AT_FIELD: "" },
+    };
+
+    if !insert_fields.is_empty() {
+        update.insert("$setOnInsert", insert_fields);
+    }
+
+    update
+}
+
+pub(crate) const DUPLICATE_KEY_CODE: i32 = 11000;
+
+pub(crate) fn is_duplicate_key(err: &MongoError) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY_CODE
+    )
+}
+
+//Every duplicate key errmsg starts with this, bulk commands only hand back the errmsg
+const DUPLICATE_KEY_PREFIX: &str = "E11000";
+
+//The errmsg of a duplicate key error and, for insert_many, the position of the document
+//that caused it
+pub(crate) fn duplicate_key_error(err: &DatastoreError) -> Option<(Option<usize>, String)> {
+    if let Some(mongo_err) = err.downcast_ref::<MongoError>() {
+        return match mongo_err.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(write_error))
+                if write_error.code == DUPLICATE_KEY_CODE =>
+            {
+                Some((None, write_error.message.clone()))
+            }
+            ErrorKind::BulkWrite(failure) => failure
+                .write_errors
+                .iter()
+                .flatten()
+                .find(|write_error| write_error.code == DUPLICATE_KEY_CODE)
+                .map(|write_error| (Some(write_error.index), write_error.message.clone())),
+            _ => None,
+        };
+    }
+
+    match err {
+        DatastoreError::Conflict(inner) if inner.to_string().starts_with(DUPLICATE_KEY_PREFIX) => {
+            Some((None, inner.to_string()))
+        }
+        _ => None,
+    }
+}
+
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+fn is_namespace_not_found(err: &MongoError) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Command(command_error) if command_error.code == NAMESPACE_NOT_FOUND_CODE
+    )
+}
+
+#[derive(Clone, Debug)]
 pub struct Atlas {
     pub client: Client,
     pub db: Database,
+    //Calls made inside a caller's transaction are not retried on their own,
+    //Datastore::transaction runs the whole transaction again instead
+    retry_policy: RetryPolicy,
 }
 
 impl Atlas {
     pub async fn try_new(db_name: &str) -> Result<Self> {
-        dotenv().ok();
-        let mongo_uri =
-            env::var("MONGODB_URI").expect("Please set the MONGODB_URI environment var");
+        let config = DatastoreConfig::from_env()?.with_database(db_name);
+
+        Self::try_with_config(&config).await
+    }
 
-        let client = Client::with_uri_str(mongo_uri).await?;
-        let db = client.database(db_name);
+    pub async fn try_with_config(config: &DatastoreConfig) -> Result<Self> {
+        let mut options = ClientOptions::parse(config.try_mongodb_uri()?)
+            .await
+            .map_err(|err| DatastoreError::Configuration(err.into()))?;
 
-        Ok(Self { client, db })
+        let pool = &config.mongo_pool;
+        if pool.max_pool_size.is_some() {
+            options.max_pool_size = pool.max_pool_size;
+        }
+        if pool.min_pool_size.is_some() {
+            options.min_pool_size = pool.min_pool_size;
+        }
+        if let Some(seconds) = pool.connect_timeout_seconds {
+            options.connect_timeout = Some(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = pool.server_selection_timeout_seconds {
+            options.server_selection_timeout = Some(Duration::from_secs(seconds));
+        }
+        if let Some(read_concern) = config.read_concern {
+            options.read_concern = Some(read_concern.read_concern());
+        }
+        if let Some(write_concern) = &config.write_concern {
+            options.write_concern = Some(write_concern.write_concern());
+        }
+
+        let client = Client::with_options(options)?;
+        let db = client.database(config.try_database()?);
+
+        Ok(Self {
+            client,
+            db,
+            retry_policy: config.mongo_retry.clone(),
+        })
+    }
+
+    //Same client and connection pool, another database
+    pub fn with_database(&self, db_name: &str) -> Self {
+        Self {
+            client: self.client.clone(),
+            db: self.client.database(db_name),
+            retry_policy: self.retry_policy.clone(),
+        }
+    }
+
+    //Health probes, sent once without retries
+    pub async fn try_ping(&self) -> Result<()> {
+        self.db.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    //The server's view of the deployment: setName, isWritablePrimary, secondary, msg
+    pub async fn try_hello(&self) -> Result<Document> {
+        Ok(self.db.run_command(doc! { "hello": 1 }, None).await?)
+    }
+
+    pub async fn try_list_collections(&self) -> Result<Vec<String>> {
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            self.db.list_collection_names(None)
+        })
+        .await
+    }
+
+    pub async fn try_drop_database(&self) -> Result<()> {
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            self.db.drop(None)
+        })
+        .await
     }
 
     pub async fn try_insert_one<T>(&self, table: &str, record: T) -> Result<InsertOneResult>
@@ -37,85 +262,111 @@ impl Atlas {
     {
         let doc_record = to_document::<T>(&record)?;
         let collection = self.db.collection::<Document>(table);
-        let insert_result = collection.insert_one(doc_record, None).await?;
 
-        Ok(insert_result)
+        //Tried again only if the insert never reached the server
+        try_with_retry(&self.retry_policy, Idempotency::NonIdempotent, || {
+            collection.insert_one(&doc_record, None)
+        })
+        .await
     }
 
     pub async fn try_insert_many<T>(&self, table: &str, records: Vec<T>) -> Result<InsertManyResult>
     where
         T: Sized + Serialize,
     {
-        let collection = self.db.collection::<Document>(table);
-        let mut session = self.client.start_session(None).await?;
+        let collection = &self.db.collection::<Document>(table);
 
-        let doc_records: Vec<Document> = records
+        let doc_records = &records
             .iter()
-            .map(|record| to_document(record).unwrap())
-            .collect();
+            .map(to_document)
+            .collect::<Result<Vec<Document>, _>>()?;
 
-        let insert_many_result = collection
-            .insert_many_with_session(doc_records, None, &mut session)
-            .await?;
+        //All or nothing, a failure halfway through must not leave part of the batch behind.
+        //A transient transaction error means nothing was inserted, the whole batch is sent again
+        try_with_retry(
+            &self.retry_policy,
+            Idempotency::NonIdempotent,
+            || async move {
+                let mut session = self.client.start_session(None).await?;
+                session.start_transaction(None).await?;
+
+                let insert_many_result = match collection
+                    .insert_many_with_session(doc_records, None, &mut session)
+                    .await
+                {
+                    Ok(insert_many_result) => insert_many_result,
+                    Err(err) => {
+                        let _ = session.abort_transaction().await;
+                        return Err(err);
+                    }
+                };
+
+                session.commit_transaction().await?;
 
-        Ok(insert_many_result)
+                Ok(insert_many_result)
+            },
+        )
+        .await
     }
 
-    pub async fn try_read_one<T>(
-        &self,
-        table: &str,
-        record_id: &str,
-    ) -> Result<Document, anyhow::Error>
-    where
-        T: for<'de> Deserialize<'de>,
-    {
+    pub async fn try_read_one(&self, table: &str, record_id: &str) -> Result<Document> {
         let collection = self.db.collection::<Document>(table);
 
         let query = doc! {
             "_id": record_id
         };
-        let find_result = collection
-            .find_one(query, None)
-            .await?
-            .ok_or(anyhow!("Could not find record"));
-        find_result
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            collection.find_one(query.clone(), None)
+        })
+        .await?
+        .ok_or_else(|| DatastoreError::not_found(table, record_id))
     }
     pub async fn try_read_documents_by_ids(
         &self,
         table: &str,
         ids: Vec<String>,
     ) -> Result<Vec<Document>> {
-        let table = self.db.collection::<Document>(table);
-        let mut session = self.client.start_session(None).await?;
+        let table = &self.db.collection::<Document>(table);
+        let filter = &doc! { "_id": { "$in": ids } };
 
-        let filter = doc! { "_id": { "$in": ids } };
-        let mut cursor = table.find_with_session(filter, None, &mut session).await?;
-        let mut documents = Vec::new();
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut session = self.client.start_session(None).await?;
 
-        while let Some(result) = cursor.next(&mut session).await {
-            if let Ok(document) = result {
-                documents.push(document);
+            let mut cursor = table
+                .find_with_session(filter.clone(), None, &mut session)
+                .await?;
+            let mut documents = Vec::new();
+
+            while let Some(result) = cursor.next(&mut session).await {
+                if let Ok(document) = result {
+                    documents.push(document);
+                }
             }
-        }
-        Ok(documents)
+            Ok::<_, MongoError>(documents)
+        })
+        .await
     }
 
     pub async fn try_read_all(&self, table: &str) -> Result<Vec<Document>> {
-        let table_handle = self.db.collection::<Document>(table);
-        let mut session = self.client.start_session(None).await?;
+        let table_handle = &self.db.collection::<Document>(table);
 
-        let mut cursor = table_handle
-            .find_with_session(doc! {}, None, &mut session)
-            .await?;
-        let mut read_res = Vec::new();
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut session = self.client.start_session(None).await?;
+
+            let mut cursor = table_handle
+                .find_with_session(doc! {}, None, &mut session)
+                .await?;
+            let mut read_res = Vec::new();
 
-        while let Some(result) = cursor.next(&mut session).await {
-            if let Ok(document) = result {
-                read_res.push(document);
+            while let Some(result) = cursor.next(&mut session).await {
+                if let Ok(document) = result {
+                    read_res.push(document);
+                }
             }
-        }
 
-        Ok(read_res)
+            Ok::<_, MongoError>(read_res)
+        })
+        .await
     }
 
     pub async fn try_update_one(
@@ -124,109 +375,983 @@ impl Atlas {
         update_record_id: &str,
         updated_record: Document,
     ) -> Result<Document> {
-        let table = self.db.collection::<Document>(table);
+        let collection = self.db.collection::<Document>(table);
 
+        //A soft-deleted record has to be restored before it can be updated
         let query = doc! {
-            "_id": update_record_id
+            "_id": update_record_id,
+            DELETED_AT_FIELD: Bson::Null,
         };
 
         let update = doc! {
             "$set": &updated_record
         };
 
-        let update_result = table.update_one(query, update, None).await?;
+        let update_result = try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            collection.update_one(query.clone(), update.clone(), None)
+        })
+        .await?;
 
-        match &update_result.upserted_id {
-            Some(upserted_id) => upserted_id.as_str().unwrap(),
-            None => update_record_id,
-        };
+        if update_result.matched_count == 0 {
+            return Err(DatastoreError::not_found(table, update_record_id));
+        }
 
         Ok(updated_record)
     }
 
+    //Same as try_update_one, handing back the document as it was before the update for
+    //the history and the audit log. Not retried, a retry of an update that went through
+    //would hand back the update itself
+    pub async fn try_update_one_prior(
+        &self,
+        table: &str,
+        update_record_id: &str,
+        updated_record: Document,
+    ) -> Result<Document> {
+        let collection = self.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": update_record_id,
+            DELETED_AT_FIELD: Bson::Null,
+        };
+
+        let update = doc! {
+            "$set": updated_record
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::Before)
+            .build();
+        let prior = try_with_retry(&self.retry_policy, Idempotency::NonIdempotent, || {
+            collection.find_one_and_update(query.clone(), update.clone(), options.clone())
+        })
+        .await?;
+
+        prior.ok_or_else(|| DatastoreError::not_found(table, update_record_id))
+    }
+
+    //set_on_insert paths must not overlap with the fields of the record itself,
+    //Mongo rejects an update that touches the same path in $set and $setOnInsert
+    pub async fn try_upsert_one(
+        &self,
+        table: &str,
+        upsert_record_id: &str,
+        upsert_record: Document,
+        set_on_insert: Option<Document>,
+    ) -> Result<UpsertOutcome> {
+        let table = self.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": upsert_record_id
+        };
+        let update = upsert_update(upsert_record, set_on_insert);
+
+        //A retried upsert whose first attempt went through reports an update
+        let options = UpdateOptions::builder().upsert(true).build();
+        let update_result = try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.update_one(query.clone(), update.clone(), options.clone())
+        })
+        .await?;
+
+        let outcome = match update_result.upserted_id {
+            Some(_) => UpsertOutcome::Inserted,
+            None => UpsertOutcome::Updated,
+        };
+
+        Ok(outcome)
+    }
+
+    //Same as try_upsert_one, handing back the document the upsert replaced. None when it
+    //inserted. Not retried for the same reason as try_update_one_prior
+    pub async fn try_upsert_one_prior(
+        &self,
+        table: &str,
+        upsert_record_id: &str,
+        upsert_record: Document,
+        set_on_insert: Option<Document>,
+    ) -> Result<Option<Document>> {
+        let table = self.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": upsert_record_id
+        };
+        let update = upsert_update(upsert_record, set_on_insert);
+
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .build();
+        let prior = try_with_retry(&self.retry_policy, Idempotency::NonIdempotent, || {
+            table.find_one_and_update(query.clone(), update.clone(), options.clone())
+        })
+        .await?;
+
+        Ok(prior)
+    }
+
     pub async fn try_update_many(
         &self,
         table: &str,
         update_map: HashMap<String, Document>, //Type alias, Document to become T later
     ) -> Result<Vec<Document>> {
-        let table = self.db.collection::<Document>(table);
-        let mut session = self.client.start_session(None).await?;
-
         let mut updated_records: Vec<Document> = Vec::new();
+        let mut operations = Vec::new();
 
-        for (record_id, document) in update_map {
-            let filter = doc! { "_id": record_id };
-            let update_doc = doc! { "$set": &document };
-            updated_records.push(document);
-            table
-                .update_one_with_session(filter, update_doc, None, &mut session)
-                .await?;
+        for (record_id, mut document) in update_map {
+            document.insert("_id", record_id);
+            updated_records.push(document.clone());
+            operations.push(WriteOp::Update(document));
+        }
+
+        let outcomes = self.try_bulk_write(table, operations, false, None).await?;
+
+        for (outcome, record) in outcomes.iter().zip(&updated_records) {
+            let record_id = record.get_str("_id").unwrap_or_default();
+            if let Some(err) = outcome_error(table, record_id, outcome) {
+                return Err(err);
+            }
         }
 
         Ok(updated_records)
     }
 
+    //Ordered batches keep their runs of same-kind writes in submission order and stop
+    //at the first failure. Unordered batches are regrouped into at most one command per kind.
+    //Runs inside the session's transaction when one is given, see try_distinct
+    pub async fn try_bulk_write(
+        &self,
+        table: &str,
+        operations: Vec<WriteOp<Document>>,
+        ordered: bool,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<Vec<WriteOutcome>> {
+        let mut outcomes: Vec<Option<WriteOutcome>> = vec![None; operations.len()];
+        let mut batches: Vec<(BulkKind, Vec<IndexedOp>)> = Vec::new();
+
+        for (index, operation) in operations.into_iter().enumerate() {
+            let kind = BulkKind::of(&operation);
+
+            let batch = if ordered {
+                match batches.last_mut() {
+                    Some((last_kind, batch)) if *last_kind == kind => Some(batch),
+                    _ => None,
+                }
+            } else {
+                batches
+                    .iter_mut()
+                    .find(|(batch_kind, _)| *batch_kind == kind)
+                    .map(|(_, batch)| batch)
+            };
+
+            match batch {
+                Some(batch) => batch.push((index, operation)),
+                None => batches.push((kind, vec![(index, operation)])),
+            }
+        }
+
+        let options = BulkOptions {
+            ordered,
+            set_on_insert: None,
+        };
+
+        for (kind, batch) in batches {
+            let failed = self
+                .try_bulk_command(
+                    table,
+                    kind,
+                    batch,
+                    options,
+                    &mut outcomes,
+                    session.as_deref_mut(),
+                )
+                .await?;
+
+            if ordered && failed {
+                break;
+            }
+        }
+
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| outcome.unwrap_or(WriteOutcome::Skipped))
+            .collect())
+    }
+
+    //Upserts every record in a single ordered update command, see try_upsert_one.
+    //The outcome of each record comes back in the order they were given
+    pub async fn try_upsert_many(
+        &self,
+        table: &str,
+        upsert_records: Vec<Document>,
+        set_on_insert: Option<Document>,
+    ) -> Result<Vec<WriteOutcome>> {
+        let mut outcomes: Vec<Option<WriteOutcome>> = vec![None; upsert_records.len()];
+        let batch = upsert_records
+            .into_iter()
+            .map(WriteOp::Upsert)
+            .enumerate()
+            .collect();
+
+        let options = BulkOptions {
+            ordered: true,
+            set_on_insert: set_on_insert.as_ref(),
+        };
+
+        self.try_bulk_command(table, BulkKind::Upsert, batch, options, &mut outcomes, None)
+            .await?;
+
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| outcome.unwrap_or(WriteOutcome::Skipped))
+            .collect())
+    }
+
+    //Sends one insert/update/delete command and fills in the outcome of every operation
+    //it carried. Returns whether any of them failed or matched nothing
+    async fn try_bulk_command(
+        &self,
+        table: &str,
+        kind: BulkKind,
+        mut batch: Vec<IndexedOp>,
+        options: BulkOptions<'_>,
+        outcomes: &mut [Option<WriteOutcome>],
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<bool> {
+        let BulkOptions {
+            ordered,
+            set_on_insert,
+        } = options;
+        let mut first_missing = None;
+
+        //Writes of records that are not there are reported as NotFound without being sent.
+        //A record deleted between the read and the command still counts as written
+        if kind.is_counted() {
+            let record_ids = batch
+                .iter()
+                .filter_map(|(_, operation)| write_id(operation))
+                .collect();
+            let matched = self
+                .try_distinct(
+                    table,
+                    "_id",
+                    kind.matched_filter(record_ids),
+                    session.as_deref_mut(),
+                )
+                .await?;
+
+            let mut found = Vec::new();
+            for (index, operation) in batch {
+                if write_id(&operation).is_some_and(|record_id| matched.contains(&record_id)) {
+                    found.push((index, operation));
+                    continue;
+                }
+
+                outcomes[index] = Some(WriteOutcome::NotFound);
+                first_missing.get_or_insert(index);
+                //Ordered commands never run anything past their first failure
+                if ordered {
+                    break;
+                }
+            }
+            batch = found;
+        }
+
+        if batch.is_empty() {
+            return Ok(first_missing.is_some());
+        }
+
+        let mut indexes = Vec::new();
+        let mut statements = Vec::new();
+
+        for (index, operation) in batch {
+            let statement = match operation {
+                WriteOp::Insert(document) => document,
+                WriteOp::Update(document) => Atlas::update_statement(document)?,
+                WriteOp::Upsert(document) => Atlas::upsert_statement(document, set_on_insert)?,
+                WriteOp::Delete(record_id) => doc! {
+                    "q": { "_id": record_id },
+                    "limit": 1,
+                },
+            };
+
+            indexes.push(index);
+            statements.push(statement);
+        }
+
+        let command = match kind {
+            BulkKind::Insert => doc! {
+                "insert": table,
+                "documents": statements,
+                "ordered": ordered,
+            },
+            BulkKind::Update | BulkKind::Upsert => doc! {
+                "update": table,
+                "updates": statements,
+                "ordered": ordered,
+            },
+            BulkKind::Delete => doc! {
+                "delete": table,
+                "deletes": statements,
+                "ordered": ordered,
+            },
+        };
+
+        //Updates go by _id and set absolute values. A delete sent again after it went through
+        //would match nothing, so deletes and inserts are only sent again if they never reached
+        //the server
+        let idempotency = match kind {
+            BulkKind::Insert | BulkKind::Delete => Idempotency::NonIdempotent,
+            BulkKind::Update | BulkKind::Upsert => Idempotency::Idempotent,
+        };
+        let response = match session {
+            Some(session) => {
+                self.db
+                    .run_command_with_session(command, None, session)
+                    .await?
+            }
+            None => {
+                try_with_retry(&self.retry_policy, idempotency, || {
+                    self.db.run_command(command.clone(), None)
+                })
+                .await?
+            }
+        };
+
+        if let Ok(write_concern_error) = response.get_document("writeConcernError") {
+            let code = write_concern_error.get_i32("code").unwrap_or_default();
+            let err = anyhow::anyhow!(
+                "Bulk write concern error: {}",
+                write_concern_error.get_str("errmsg").unwrap_or_default()
+            );
+            return Err(DatastoreError::with_code(code, err));
+        }
+
+        let mut first_failure = indexes.len();
+
+        if let Ok(write_errors) = response.get_array("writeErrors") {
+            for write_error in write_errors {
+                if let Bson::Document(write_error) = write_error {
+                    let position = write_error.get_i32("index")? as usize;
+                    let message = write_error.get_str("errmsg").unwrap_or_default();
+
+                    outcomes[indexes[position]] = Some(WriteOutcome::Failed(message.to_owned()));
+                    first_failure = first_failure.min(position);
+                }
+            }
+        }
+
+        let mut inserted_by_upsert = vec![false; indexes.len()];
+
+        if let Ok(upserted) = response.get_array("upserted") {
+            for entry in upserted {
+                if let Bson::Document(entry) = entry {
+                    inserted_by_upsert[entry.get_i32("index")? as usize] = true;
+                }
+            }
+        }
+
+        let failed = first_missing.is_some() || first_failure < indexes.len();
+
+        //Ordered commands never run anything past their first failure, a missing record
+        //after it was never reached
+        let executed = if ordered {
+            if let Some(index) = first_missing.filter(|_| first_failure < indexes.len()) {
+                outcomes[index] = None;
+            }
+            first_failure
+        } else {
+            indexes.len()
+        };
+
+        for (position, index) in indexes.into_iter().enumerate().take(executed) {
+            if outcomes[index].is_some() {
+                continue;
+            }
+
+            let outcome = match kind {
+                BulkKind::Insert => WriteOutcome::Inserted,
+                BulkKind::Upsert if inserted_by_upsert[position] => {
+                    WriteOutcome::Upserted(UpsertOutcome::Inserted)
+                }
+                BulkKind::Upsert => WriteOutcome::Upserted(UpsertOutcome::Updated),
+                BulkKind::Update => WriteOutcome::Updated,
+                BulkKind::Delete => WriteOutcome::Deleted,
+            };
+            outcomes[index] = Some(outcome);
+        }
+
+        Ok(failed)
+    }
+
+    //Same as for single writes, updates skip soft-deleted records
+    fn update_statement(mut document: Document) -> Result<Document> {
+        let record_id = document
+            .remove("_id")
+            .ok_or_else(|| DatastoreError::validation("Update is missing an _id"))?;
+
+        Ok(doc! {
+            "q": { "_id": record_id, DELETED_AT_FIELD: Bson::Null },
+            "u": { "$set": document },
+            "upsert": false,
+        })
+    }
+
+    //Same update as try_upsert_one sends, created_at and set_on_insert only apply to the insert
+    fn upsert_statement(document: Document, set_on_insert: Option<&Document>) -> Result<Document> {
+        let record_id = document
+            .get("_id")
+            .cloned()
+            .ok_or_else(|| DatastoreError::validation("Upsert is missing an _id"))?;
+
+        Ok(doc! {
+            "q": { "_id": record_id },
+            "u": upsert_update(document, set_on_insert.cloned()),
+            "upsert": true,
+        })
+    }
+
     pub async fn try_delete_one(&self, table: &str, record_id: &str) -> Result<Document> {
-        let table = self.db.collection::<Document>(table);
+        let collection = self.db.collection::<Document>(table);
 
         let query = doc! {
             "_id": record_id
         };
 
-        let delete_result = table.find_one_and_delete(query, None).await?.unwrap();
+        //A retry after the delete went through would find nothing and report NotFound
+        let delete_result = try_with_retry(&self.retry_policy, Idempotency::NonIdempotent, || {
+            collection.find_one_and_delete(query.clone(), None)
+        })
+        .await?
+        .ok_or_else(|| DatastoreError::not_found(table, record_id))?;
         Ok(delete_result)
     }
 
     pub async fn try_delete_many(&self, table: &str, delete_ids: Vec<String>) -> Result<()> {
-        let table = self.db.collection::<Document>(table);
-        let mut session = self.client.start_session(None).await?;
+        let table = &self.db.collection::<Document>(table);
 
-        let filter = doc! {
+        let filter = &doc! {
             "_id": { "$in": delete_ids },
         };
-        let res = table
-            .delete_many_with_session(filter, None, &mut session)
-            .await?;
-        Ok(())
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut session = self.client.start_session(None).await?;
+            session.start_transaction(None).await?;
+
+            if let Err(err) = table
+                .delete_many_with_session(filter.clone(), None, &mut session)
+                .await
+            {
+                let _ = session.abort_transaction().await;
+                return Err(err);
+            }
+
+            session.commit_transaction().await
+        })
+        .await
     }
 
     pub async fn try_delete_all(&self, table: &str) -> Result<()> {
         let table = self.db.collection::<Document>(table);
-        table.delete_many(doc! {}, None).await?;
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.delete_many(doc! {}, None)
+        })
+        .await?;
         Ok(())
     }
 
-    pub async fn try_find_one_bookstore<T>(&self, record: T) -> Result<Document>
-    where
-        T: MongoStorable,
-    {
-        let bookstore_collection = self.db.collection::<Document>("bookstore");
+    //Filter based helpers below run inside the session's transaction when one is given.
+    //Only calls made without one are retried here
 
-        let record_bookstore_id = record.get_bookstore_id();
+    pub async fn try_distinct(
+        &self,
+        table: &str,
+        field: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> Result<Vec<Bson>> {
+        let table = self.db.collection::<Document>(table);
+
+        let values = match session {
+            Some(session) => {
+                table
+                    .distinct_with_session(field, filter, None, session)
+                    .await?
+            }
+            None => {
+                try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+                    table.distinct(field, filter.clone(), None)
+                })
+                .await?
+            }
+        };
+
+        Ok(values)
+    }
+
+    pub async fn try_count_matching(
+        &self,
+        table: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> Result<u64> {
+        let table = self.db.collection::<Document>(table);
 
-        let query = doc! { "_id": record_bookstore_id };
+        let count = match session {
+            Some(session) => {
+                table
+                    .count_documents_with_session(filter, None, session)
+                    .await?
+            }
+            None => {
+                try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+                    table.count_documents(filter.clone(), None)
+                })
+                .await?
+            }
+        };
 
-        let res = bookstore_collection
-            .find_one(query, None)
-            .await?
-            .ok_or(anyhow!("Could not find bookstore"));
-        res
+        Ok(count)
     }
 
+    pub async fn try_update_matching(
+        &self,
+        table: &str,
+        filter: Document,
+        update: Document,
+        session: Option<&mut ClientSession>,
+    ) -> Result<u64> {
+        let table = self.db.collection::<Document>(table);
+
+        let update_result = match session {
+            Some(session) => {
+                table
+                    .update_many_with_session(filter, update, None, session)
+                    .await?
+            }
+            //The update may use operators such as $inc that must not be applied twice
+            None => {
+                try_with_retry(&self.retry_policy, Idempotency::NonIdempotent, || {
+                    table.update_many(filter.clone(), update.clone(), None)
+                })
+                .await?
+            }
+        };
+
+        Ok(update_result.modified_count)
+    }
+
+    pub async fn try_delete_matching(
+        &self,
+        table: &str,
+        filter: Document,
+        session: Option<&mut ClientSession>,
+    ) -> Result<u64> {
+        let table = self.db.collection::<Document>(table);
+
+        let delete_result = match session {
+            Some(session) => {
+                table
+                    .delete_many_with_session(filter, None, session)
+                    .await?
+            }
+            None => {
+                try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+                    table.delete_many(filter.clone(), None)
+                })
+                .await?
+            }
+        };
+
+        Ok(delete_result.deleted_count)
+    }
+
+    pub async fn try_find_one(&self, table: &str, filter: Document) -> Result<Document> {
+        let collection = self.db.collection::<Document>(table);
+
+        //Reported by _id when the filter has one, otherwise by the whole filter
+        let missing = match filter.get_str("_id") {
+            Ok(record_id) => record_id.to_owned(),
+            Err(_) => filter.to_string(),
+        };
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            collection.find_one(filter.clone(), None)
+        })
+        .await?
+        .ok_or_else(|| DatastoreError::not_found(table, &missing))
+    }
+
+    pub async fn try_find_matching(
+        &self,
+        table: &str,
+        filter: Document,
+        limit: Option<i64>,
+    ) -> Result<Vec<Document>> {
+        let options = FindOptions::builder().limit(limit).build();
+
+        self.try_find(table, filter, options).await
+    }
+
+    pub async fn try_find_sorted(
+        &self,
+        table: &str,
+        filter: Document,
+        sort: Document,
+        limit: Option<i64>,
+    ) -> Result<Vec<Document>> {
+        let options = FindOptions::builder().sort(sort).limit(limit).build();
+
+        self.try_find(table, filter, options).await
+    }
+
+    //A failure while reading the cursor starts the whole query over
+    async fn try_find(
+        &self,
+        table: &str,
+        filter: Document,
+        options: FindOptions,
+    ) -> Result<Vec<Document>> {
+        let table = &self.db.collection::<Document>(table);
+        let (filter, options) = (&filter, &options);
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut cursor = table.find(filter.clone(), options.clone()).await?;
+            let mut documents = Vec::new();
+
+            while let Some(document) = cursor.next().await {
+                documents.push(document?);
+            }
+
+            Ok::<_, MongoError>(documents)
+        })
+        .await
+    }
+
+    //Ids of the matching documents in _id order, nothing else is sent back
+    pub async fn try_find_ids(
+        &self,
+        table: &str,
+        filter: Document,
+        limit: Option<i64>,
+    ) -> Result<Vec<Bson>> {
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .sort(doc! { "_id": 1 })
+            .limit(limit)
+            .build();
+
+        let documents = self.try_find(table, filter, options).await?;
+
+        Ok(documents
+            .into_iter()
+            .filter_map(|mut document| document.remove("_id"))
+            .collect())
+    }
+
+    //Replaces a document with its upgraded version, unless it was changed or upgraded by
+    //someone else since it was read at from_version. read_updated_at is the updated_at it
+    //had when read, every write moves it on
+    pub async fn try_replace_outdated(
+        &self,
+        table: &str,
+        document: Document,
+        from_version: u32,
+        read_updated_at: Option<Bson>,
+    ) -> Result<bool> {
+        let table = self.db.collection::<Document>(table);
+
+        let version_filter = match from_version {
+            //Matches a missing field as well
+            0 => doc! { SCHEMA_VERSION_FIELD: { "$in": [0_i64, Bson::Null] } },
+            _ => doc! { SCHEMA_VERSION_FIELD: from_version as i64 },
+        };
+
+        let mut filter = doc! {
+            "_id": document.get("_id").cloned().unwrap_or(Bson::Null),
+            //Null matches a document that never had one as well
+            UPDATED_AT_FIELD: read_updated_at.unwrap_or(Bson::Null),
+        };
+        filter.extend(version_filter);
+
+        let replace_result = try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.replace_one(filter.clone(), &document, None)
+        })
+        .await?;
+
+        Ok(replace_result.modified_count > 0)
+    }
+
+    //A collection that does not exist yet simply has no indexes
+    pub async fn try_list_indexes(&self, table: &str) -> Result<Vec<IndexModel>> {
+        let table = &self.db.collection::<Document>(table);
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut cursor = match table.list_indexes(None).await {
+                Ok(cursor) => cursor,
+                Err(err) if is_namespace_not_found(&err) => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            };
+
+            let mut indexes = Vec::new();
+            while let Some(index) = cursor.next().await {
+                indexes.push(index?);
+            }
+
+            Ok(indexes)
+        })
+        .await
+    }
+
+    pub async fn try_create_indexes(&self, table: &str, indexes: Vec<IndexModel>) -> Result<()> {
+        let table = self.db.collection::<Document>(table);
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.create_indexes(indexes.clone(), None)
+        })
+        .await?;
+        Ok(())
+    }
+
+    //collMod on an existing collection, create on a missing one
+    pub async fn try_set_validator(
+        &self,
+        table: &str,
+        validator: Document,
+        validation_level: &str,
+        validation_action: &str,
+    ) -> Result<()> {
+        let options = doc! {
+            "validator": validator,
+            "validationLevel": validation_level,
+            "validationAction": validation_action,
+        };
+
+        let mut coll_mod = doc! { "collMod": table };
+        coll_mod.extend(options.clone());
+        let mut create = doc! { "create": table };
+        create.extend(options);
+        let (coll_mod, create) = (&coll_mod, &create);
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            match self.db.run_command(coll_mod.clone(), None).await {
+                Ok(_) => Ok(()),
+                Err(err) if is_namespace_not_found(&err) => {
+                    self.db.run_command(create.clone(), None).await?;
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            }
+        })
+        .await
+    }
+
+    //Documents are reaped by Mongo once the date stored in field has passed
+    pub async fn try_ensure_ttl_index(&self, table: &str, field: &str) -> Result<()> {
+        let table = self.db.collection::<Document>(table);
+
+        let options = IndexOptions::builder()
+            .expire_after(Duration::from_secs(0))
+            .build();
+        let index = IndexModel::builder()
+            .keys(doc! { field: 1 })
+            .options(options)
+            .build();
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.create_index(index.clone(), None)
+        })
+        .await?;
+        Ok(())
+    }
+
+    //Inserting the pending marker is the lock: only one caller can own a key.
+    //Returns the existing entry when someone else got there first
+    pub async fn try_claim_idempotency_key(
+        &self,
+        table: &str,
+        key: &str,
+        request_hash: &str,
+        expires_at: bson::DateTime,
+    ) -> Result<Option<Document>> {
+        let collection = &self.db.collection::<Document>(table);
+
+        let claim = &doc! {
+            "_id": key,
+            "status": "pending",
+            "request_hash": request_hash,
+            "expires_at": expires_at,
+        };
+
+        //A claim that went through would look taken by someone else on a second attempt.
+        //The TTL monitor only runs every minute, so an expired entry may still be around
+        try_with_retry(
+            &self.retry_policy,
+            Idempotency::NonIdempotent,
+            || async move {
+                match collection.insert_one(claim, None).await {
+                    Ok(_) => return Ok(None),
+                    Err(err) if is_duplicate_key(&err) => (),
+                    Err(err) => return Err(DatastoreError::from(err)),
+                }
+
+                let expired = doc! {
+                    "_id": key,
+                    "expires_at": { "$lte": bson::DateTime::now() },
+                };
+                let reclaim = collection.replace_one(expired, claim, None).await?;
+
+                if reclaim.modified_count == 1 {
+                    return Ok(None);
+                }
+
+                let existing = collection
+                    .find_one(doc! { "_id": key }, None)
+                    .await?
+                    .ok_or_else(|| DatastoreError::not_found(table, key))?;
+                Ok(Some(existing))
+            },
+        )
+        .await
+    }
+
+    pub async fn try_complete_idempotency_key(
+        &self,
+        table: &str,
+        key: &str,
+        result: Bson,
+        expires_at: bson::DateTime,
+    ) -> Result<()> {
+        let table = self.db.collection::<Document>(table);
+
+        let update = doc! {
+            "$set": {
+                "status": "completed",
+                "result": result,
+                "expires_at": expires_at,
+            }
+        };
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.update_one(doc! { "_id": key }, update.clone(), None)
+        })
+        .await?;
+        Ok(())
+    }
+
+    //Moves the expiry of a pending claim, a completed key keeps its own
+    pub async fn try_renew_idempotency_key(
+        &self,
+        table: &str,
+        key: &str,
+        expires_at: bson::DateTime,
+    ) -> Result<()> {
+        let table = self.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": key,
+            "status": "pending",
+        };
+        let update = doc! {
+            "$set": { "expires_at": expires_at }
+        };
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.update_one(query.clone(), update.clone(), None)
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn try_release_idempotency_key(&self, table: &str, key: &str) -> Result<()> {
+        let table = self.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": key,
+            "status": "pending",
+        };
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || {
+            table.delete_one(query.clone(), None)
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn try_find_related(
+        &self,
+        relation: &Relation,
+        keys: Vec<Bson>,
+    ) -> Result<Vec<Document>> {
+        let filter = doc! {
+            relation.foreign_field: { "$in": keys },
+            DELETED_AT_FIELD: Bson::Null,
+        };
+
+        self.try_find(relation.target, filter, FindOptions::default())
+            .await
+    }
+
+    //The source record with every related target embedded under relation.name,
+    //resolved in one aggregation. None when the source record does not exist
+    pub async fn try_lookup_composite(
+        &self,
+        source_table: &str,
+        relation: &Relation,
+        source_id: &str,
+    ) -> Result<Option<Document>> {
+        let table = self.db.collection::<Document>(source_table);
+
+        let pipeline = vec![
+            doc! { "$match": { "_id": source_id, DELETED_AT_FIELD: Bson::Null } },
+            doc! {
+                "$lookup": {
+                    "from": relation.target,
+                    "localField": relation.local_field,
+                    "foreignField": relation.foreign_field,
+                    "pipeline": [{ "$match": { DELETED_AT_FIELD: Bson::Null } }],
+                    "as": relation.name
+                }
+            },
+            doc! { "$limit": 1 },
+        ];
+
+        let (table, pipeline) = (&table, &pipeline);
+
+        try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+            let mut cursor = table.aggregate(pipeline.clone(), None).await?;
+
+            match cursor.next().await {
+                Some(result) => Ok(Some(result?)),
+                None => Ok::<_, MongoError>(None),
+            }
+        })
+        .await
+    }
+
+    //Every record of the relation's target collection that is related to at least one
+    //of the given source records, resolved with a $lookup from the target side
     //Implement pagination for any read method including below
     //Chagne Vec to Arr with limit
-    pub async fn find_bookstores<T>(&self, records: Vec<&str>) -> Result<Vec<T>>
+    pub async fn try_lookup_related<T>(
+        &self,
+        source_table: &str,
+        relation: &Relation,
+        source_ids: Vec<&str>,
+    ) -> Result<Vec<T>>
     where
         T: DeserializeOwned,
     {
-        let table = self.db.collection::<Document>("bookstores");
+        let table = self.db.collection::<Document>(relation.target);
 
+        //Soft-deleted records are left out on both sides
         let pipeline = vec![
+            doc! { "$match": { DELETED_AT_FIELD: Bson::Null } },
             doc! {
                 "$lookup": {
-                    "from": "books",
-                    "localField": "_id",
-                    "foreignField": "data.bookstore_id",
+                    "from": source_table,
+                    "localField": relation.foreign_field,
+                    "foreignField": relation.local_field,
+                    "pipeline": [{ "$match": { DELETED_AT_FIELD: Bson::Null } }],
                     "as": "res"
                 }
             },
@@ -235,7 +1360,7 @@ impl Atlas {
                     "$and": [
                         {
                             "res._id": {
-                                "$in": records
+                                "$in": source_ids
                             }
                         },
                         {
@@ -249,14 +1374,27 @@ impl Atlas {
             doc! { "$project": { "res": 0 } },
         ];
 
-        let mut bookstores = Vec::new();
-        let mut cursor = table.aggregate(pipeline, None).await?;
+        let (table, pipeline) = (&table, &pipeline);
+
+        let documents =
+            try_with_retry(&self.retry_policy, Idempotency::Idempotent, || async move {
+                let mut documents = Vec::new();
+                let mut cursor = table.aggregate(pipeline.clone(), None).await?;
+
+                while let Some(result) = cursor.next().await {
+                    documents.push(result?);
+                }
+
+                Ok::<_, MongoError>(documents)
+            })
+            .await?;
 
-        while let Some(result) = cursor.next().await {
-            let record = from_document::<T>(result?)?;
-            bookstores.push(record)
+        let mut related = Vec::new();
+        for document in documents {
+            let record = from_document::<T>(document)?;
+            related.push(record)
         }
 
-        Ok(bookstores)
+        Ok(related)
     }
 }
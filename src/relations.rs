@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+
+use bson::{doc, from_document, to_document, Bson, Document};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::book_types::MongoStorable;
+use crate::cache::redis::CacheOp;
+use crate::error::{DatastoreError, Result};
+pub use crate::integrity::OnDelete;
+use crate::schema::{is_optional_field, BsonSchema};
+use crate::soft_delete::{visible, ReadOptions};
+use crate::Datastore;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cardinality {
+    //The record holds a single key pointing at the target, e.g. book -> bookstore
+    ManyToOne,
+    //The targets hold the key pointing back at the record, e.g. bookstore -> books
+    OneToMany,
+    //The record holds an array of keys pointing at the targets
+    ManyToMany,
+}
+
+//Member every relation set gets once it is known to hold all of its members. A set
+//rebuilt member by member, e.g. after it was invalidated, lacks it until it is rebuilt
+//from Atlas
+pub(crate) const INDEX_POPULATED_MEMBER: &str = "~populated";
+
+//Pair of Redis set families mirroring a relation, e.g. book_stores:<book_id> holds
+//the bookstore ids of a book and store_books:<store_id> the book ids of a bookstore
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelationIndex {
+    pub forward: &'static str,
+    pub reverse: &'static str,
+}
+
+impl RelationIndex {
+    pub fn forward_key(&self, record_id: &str) -> String {
+        format!("{}:{}", self.forward, record_id)
+    }
+
+    pub fn reverse_key(&self, target_id: &str) -> String {
+        format!("{}:{}", self.reverse, target_id)
+    }
+
+    //Cached composite of a target with every record in its reverse set,
+    //e.g. composite:store_books:<store_id> holds a bookstore with its books
+    pub fn composite_key(&self, target_id: &str) -> String {
+        format!("{}:{}", self.composite_prefix(), target_id)
+    }
+
+    pub fn composite_prefix(&self) -> String {
+        format!("composite:{}", self.reverse)
+    }
+}
+
+//Declares how records of one collection point at records of another.
+//Targets match when target.foreign_field equals any value found at record.local_field
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relation {
+    pub name: &'static str,
+    pub local_field: &'static str,
+    pub target: &'static str,
+    //Redis hash the target records are cached under, see MongoStorable::CACHE_NAMESPACE
+    pub target_namespace: &'static str,
+    pub foreign_field: &'static str,
+    pub cardinality: Cardinality,
+    pub index: Option<RelationIndex>,
+    //Key-holding side: writes pointing at a missing target are rejected
+    pub required: bool,
+    //Target-side (one-to-many): what happens to the targets when this record is deleted
+    pub on_delete: Option<OnDelete>,
+    //Set by on_delete_set_null when the target schema lets foreign_field be missing
+    pub nullable_key: bool,
+}
+
+impl Relation {
+    pub fn many_to_one(name: &'static str, local_field: &'static str, target: &'static str) -> Self {
+        Self {
+            name,
+            local_field,
+            target,
+            target_namespace: target,
+            foreign_field: "_id",
+            cardinality: Cardinality::ManyToOne,
+            index: None,
+            required: false,
+            on_delete: None,
+            nullable_key: false,
+        }
+    }
+
+    pub fn one_to_many(name: &'static str, target: &'static str, foreign_field: &'static str) -> Self {
+        Self {
+            name,
+            local_field: "_id",
+            target,
+            target_namespace: target,
+            foreign_field,
+            cardinality: Cardinality::OneToMany,
+            index: None,
+            required: false,
+            on_delete: None,
+            nullable_key: false,
+        }
+    }
+
+    pub fn many_to_many(name: &'static str, local_field: &'static str, target: &'static str) -> Self {
+        Self {
+            name,
+            local_field,
+            target,
+            target_namespace: target,
+            foreign_field: "_id",
+            cardinality: Cardinality::ManyToMany,
+            index: None,
+            required: false,
+            on_delete: None,
+            nullable_key: false,
+        }
+    }
+
+    //Only relations whose record holds the keys (many-to-one, many-to-many) keep the
+    //index in sync. A one-to-many relation names the same index to read its reverse sets
+    pub fn indexed(mut self, forward: &'static str, reverse: &'static str) -> Self {
+        self.index = Some(RelationIndex { forward, reverse });
+        self
+    }
+
+    //Needed when the target type caches under something other than its collection name
+    pub fn cached_in(mut self, target_namespace: &'static str) -> Self {
+        self.target_namespace = target_namespace;
+        self
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    //Set-null goes through on_delete_set_null, a rule set here cannot check the key
+    pub fn on_delete(mut self, rule: OnDelete) -> Self {
+        self.on_delete = Some(rule);
+        self
+    }
+
+    //Target is the record type stored in the target collection. Its schema has to leave
+    //foreign_field optional, otherwise the rule is rejected and deletes fail with a
+    //configuration error instead of leaving targets that no longer deserialize
+    pub fn on_delete_set_null<Target>(mut self) -> Self
+    where
+        Target: BsonSchema,
+    {
+        self.on_delete = Some(OnDelete::SetNull);
+        self.nullable_key = is_optional_field(&Target::bson_schema(), self.foreign_field);
+        self
+    }
+
+    pub fn holds_keys(&self) -> bool {
+        self.cardinality != Cardinality::OneToMany
+    }
+}
+
+//Resolves a dotted path like "data.bookstore_id", flattening arrays along the way
+pub fn field_values(document: &Document, path: &str) -> Vec<Bson> {
+    let mut current = vec![Bson::Document(document.clone())];
+
+    for segment in path.split('.') {
+        let mut next = Vec::new();
+
+        for value in current {
+            if let Bson::Document(inner) = value {
+                match inner.get(segment) {
+                    Some(Bson::Array(items)) => next.extend(items.iter().cloned()),
+                    Some(Bson::Null) | None => (),
+                    Some(found) => next.push(found.clone()),
+                }
+            }
+        }
+
+        current = next;
+    }
+
+    current
+}
+
+//The relation a record type declares towards the target collection
+pub fn relation_to<T>(target: &str) -> Result<Relation>
+where
+    T: MongoStorable,
+{
+    T::relations()
+        .into_iter()
+        .find(|relation| relation.target == target)
+        .ok_or_else(|| {
+            DatastoreError::validation(format!(
+                "No relation declared from {} to {}",
+                T::COLLECTION,
+                target
+            ))
+        })
+}
+
+//The Redis set family holding the Target ids of a Source record, if either side keeps one
+pub fn cached_index<Source, Target>() -> Option<String>
+where
+    Source: MongoStorable,
+    Target: MongoStorable,
+{
+    let own = relation_to::<Source>(Target::COLLECTION)
+        .ok()
+        .and_then(|relation| relation.index.map(|index| (index, relation.holds_keys())));
+
+    //The target's own declaration describes the same sets seen from the other side
+    let (index, forward) = own.or_else(|| {
+        relation_to::<Target>(Source::COLLECTION)
+            .ok()
+            .and_then(|relation| relation.index.map(|index| (index, !relation.holds_keys())))
+    })?;
+
+    if forward {
+        Some(index.forward.to_owned())
+    } else {
+        Some(index.reverse.to_owned())
+    }
+}
+
+//Cache ops bringing every relation index of the record in line with its current keys
+pub fn relation_cache_ops<T>(record: &T) -> Result<Vec<CacheOp>>
+where
+    T: MongoStorable + Serialize,
+{
+    let document = to_document(record)?;
+    let mut cache_ops = Vec::new();
+
+    for relation in T::relations() {
+        match (relation.index, relation.holds_keys()) {
+            (Some(index), true) => {
+                let target_ids = field_values(&document, relation.local_field)
+                    .iter()
+                    .filter_map(|key| key.as_str().map(str::to_owned))
+                    .collect();
+
+                cache_ops.push(CacheOp::Relate {
+                    index,
+                    record_id: record.get_id().to_owned(),
+                    target_ids,
+                });
+            }
+            //The record is the target side, its cached composite embeds the old copy
+            (Some(index), false) => cache_ops.push(CacheOp::Evict {
+                keys: vec![index.composite_key(record.get_id())],
+            }),
+            (None, _) => (),
+        }
+    }
+
+    Ok(cache_ops)
+}
+
+//Cache ops dropping a deleted record from every relation index and composite it appears in
+pub fn relation_clear_ops<T>(record_id: &str) -> Vec<CacheOp>
+where
+    T: MongoStorable,
+{
+    T::relations()
+        .into_iter()
+        .filter_map(|relation| relation.index.map(|index| (index, relation.holds_keys())))
+        .flat_map(|(index, holds_keys)| match holds_keys {
+            true => vec![
+                CacheOp::Relate {
+                    index,
+                    record_id: record_id.to_owned(),
+                    target_ids: Vec::new(),
+                },
+                //A deleted record has no forward set left to keep
+                CacheOp::Evict {
+                    keys: vec![index.forward_key(record_id)],
+                },
+            ],
+            false => vec![CacheOp::Evict {
+                keys: vec![index.composite_key(record_id)],
+            }],
+        })
+        .collect()
+}
+
+//Where the keys behind a relation index live, on the source records or on the targets
+enum IndexKeys {
+    Source(&'static str),
+    Target(&'static str),
+}
+
+//Mirrors cached_index: the Source side's declaration first, otherwise the Target's
+fn index_keys<Source, Target>() -> Option<IndexKeys>
+where
+    Source: MongoStorable,
+    Target: MongoStorable,
+{
+    if let Ok(relation) = relation_to::<Source>(Target::COLLECTION) {
+        if relation.index.is_some() {
+            return Some(match relation.holds_keys() {
+                true => IndexKeys::Source(relation.local_field),
+                false => IndexKeys::Target(relation.foreign_field),
+            });
+        }
+    }
+
+    let relation = relation_to::<Target>(Source::COLLECTION).ok()?;
+    relation.index?;
+
+    Some(match relation.holds_keys() {
+        true => IndexKeys::Target(relation.local_field),
+        false => IndexKeys::Source(relation.foreign_field),
+    })
+}
+
+impl Datastore {
+    //Forward lookup: the Target records this record points at (or that point back at it)
+    pub async fn related<Target>(
+        &self,
+        record: &(impl MongoStorable + Serialize),
+    ) -> Result<Vec<Target>>
+    where
+        Target: MongoStorable + DeserializeOwned,
+    {
+        self.try_related_of::<_, Target>(record).await
+    }
+
+    async fn try_related_of<Source, Target>(&self, record: &Source) -> Result<Vec<Target>>
+    where
+        Source: MongoStorable + Serialize,
+        Target: MongoStorable + DeserializeOwned,
+    {
+        if let Some(cached) = self
+            .try_related_from_cache::<Source, Target>(vec![record.get_id()])
+            .await
+        {
+            return Ok(cached);
+        }
+
+        let relation = relation_to::<Source>(Target::COLLECTION)?;
+        let keys = field_values(&to_document(record)?, relation.local_field);
+
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let documents = self.database.try_find_related(&relation, keys).await?;
+
+        let mut related = Vec::new();
+        for document in documents {
+            related.push(from_document::<Target>(document)?);
+        }
+
+        Ok(related)
+    }
+
+    //Reverse lookup: every Target related to any of the given Source records, e.g. the
+    //bookstores carrying a list of books. Served from the relation index sets when they
+    //are complete, otherwise resolved with one $lookup aggregation
+    pub async fn related_many<Target, Source>(&self, source_ids: Vec<&str>) -> Result<Vec<Target>>
+    where
+        Target: MongoStorable + DeserializeOwned,
+        Source: MongoStorable,
+    {
+        if let Some(cached) = self
+            .try_related_from_cache::<Source, Target>(source_ids.clone())
+            .await
+        {
+            return Ok(cached);
+        }
+
+        let relation = relation_to::<Source>(Target::COLLECTION)?;
+
+        self.database
+            .try_lookup_related::<Target>(Source::COLLECTION, &relation, source_ids)
+            .await
+    }
+
+    //None means the index could not answer (missing, incomplete or Redis unavailable)
+    //and the caller should go to Atlas instead. Incomplete sets are rebuilt on the way
+    async fn try_related_from_cache<Source, Target>(
+        &self,
+        source_ids: Vec<&str>,
+    ) -> Option<Vec<Target>>
+    where
+        Source: MongoStorable,
+        Target: MongoStorable + DeserializeOwned,
+    {
+        let index = cached_index::<Source, Target>()?;
+
+        let target_ids = match self
+            .cache
+            .try_populated_members(&index, source_ids.clone())
+            .await
+        {
+            Ok(Some(target_ids)) => target_ids,
+            Ok(None) => {
+                let _ = self
+                    .try_rebuild_related_index::<Source, Target>(&index, source_ids)
+                    .await;
+                return None;
+            }
+            Err(_) => return None,
+        };
+
+        //Targets are read from their cache hash first, Atlas only fills in the misses
+        let cached_values = self
+            .cache
+            .try_read_fields(Target::CACHE_NAMESPACE, target_ids.clone())
+            .await
+            .ok()?;
+
+        let mut related = Vec::new();
+        let mut missing_ids = Vec::new();
+
+        for (target_id, cached_value) in target_ids.into_iter().zip(cached_values) {
+            match cached_value.and_then(|value| serde_json::from_str::<Target>(&value).ok()) {
+                Some(target) => related.push(target),
+                None => missing_ids.push(target_id),
+            }
+        }
+
+        if !missing_ids.is_empty() {
+            let filter = visible(
+                doc! { "_id": { "$in": missing_ids } },
+                ReadOptions::default(),
+            );
+            let documents = self
+                .database
+                .try_find_matching(Target::COLLECTION, filter, None)
+                .await
+                .ok()?;
+
+            for document in documents {
+                related.push(from_document::<Target>(document).ok()?);
+            }
+        }
+
+        Some(related)
+    }
+
+    //Writes the relation sets of the source ids whole, the same members the writes put in:
+    //the keys a source holds, or the live targets holding a source's id
+    async fn try_rebuild_related_index<Source, Target>(
+        &self,
+        index: &str,
+        source_ids: Vec<&str>,
+    ) -> Result<()>
+    where
+        Source: MongoStorable,
+        Target: MongoStorable,
+    {
+        let index_keys = match index_keys::<Source, Target>() {
+            Some(index_keys) => index_keys,
+            None => return Ok(()),
+        };
+
+        let load = async {
+            let mut members: HashMap<String, Vec<String>> = HashMap::new();
+
+            match index_keys {
+                IndexKeys::Source(field) => {
+                    let filter = visible(
+                        doc! { "_id": { "$in": &source_ids } },
+                        ReadOptions::default(),
+                    );
+                    let sources = self
+                        .database
+                        .try_find_matching(Source::COLLECTION, filter, None)
+                        .await?;
+
+                    for source in sources {
+                        let target_ids = field_values(&source, field)
+                            .iter()
+                            .filter_map(|key| key.as_str().map(str::to_owned))
+                            .collect();
+                        members.insert(source.get_str("_id")?.to_owned(), target_ids);
+                    }
+                }
+                IndexKeys::Target(field) => {
+                    let filter = visible(
+                        doc! { field: { "$in": &source_ids } },
+                        ReadOptions::default(),
+                    );
+                    let targets = self
+                        .database
+                        .try_find_matching(Target::COLLECTION, filter, None)
+                        .await?;
+
+                    for target in targets {
+                        let target_id = target.get_str("_id")?;
+                        for key in field_values(&target, field) {
+                            if let Some(source_id) = key.as_str() {
+                                members
+                                    .entry(source_id.to_owned())
+                                    .or_default()
+                                    .push(target_id.to_owned());
+                            }
+                        }
+                    }
+                }
+            }
+
+            Ok(members)
+        };
+
+        self.cache
+            .try_rebuild_index(index, source_ids.clone(), load)
+            .await
+    }
+}
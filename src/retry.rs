@@ -0,0 +1,127 @@
+use std::future::Future;
+use std::time::Duration;
+
+use mobc_redis::redis::{ErrorKind as RedisErrorKind, RedisError};
+use mongodb::error::{
+    Error as MongoError, ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR,
+};
+use rand::Rng;
+
+use crate::cache::redis::MobcError;
+use crate::config::RetryPolicy;
+use crate::error::{DatastoreError, Result};
+
+//Whether running an operation twice leaves the same state as running it once. Reads,
+//$set updates, upserts and deletes by _id are. Inserts, increments and anything whose
+//result depends on the state it found are not, those are only tried again when the
+//failure shows the first attempt never got applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Idempotency {
+    Idempotent,
+    NonIdempotent,
+}
+
+impl RetryPolicy {
+    //How long to wait after the given failed attempt, counted from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(63);
+        let millis = self
+            .initial_backoff_ms
+            .saturating_mul(1_u64 << doublings)
+            .min(self.max_backoff_ms);
+
+        match self.jitter && millis > 0 {
+            true => Duration::from_millis(rand::thread_rng().gen_range(0..=millis)),
+            false => Duration::from_millis(millis),
+        }
+    }
+}
+
+//Runs operation until it succeeds, fails with an error that is not worth another try or
+//runs out of attempts. The last error is returned as is
+pub(crate) async fn try_with_retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    idempotency: Idempotency,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<DatastoreError>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match operation().await.map_err(Into::into) {
+            Err(err) if attempt < policy.max_attempts && is_retryable(&err, idempotency) => {
+                tokio::time::sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) fn is_retryable(err: &DatastoreError, idempotency: Idempotency) -> bool {
+    if let Some(mongo_err) = err.downcast_ref::<MongoError>() {
+        let unavailable = matches!(
+            err,
+            DatastoreError::DatabaseUnavailable(_) | DatastoreError::Timeout(_)
+        );
+        return is_mongo_retryable(mongo_err, unavailable, idempotency);
+    }
+    if let Some(pool_err) = err.downcast_ref::<mobc::Error<RedisError>>() {
+        return is_pool_retryable(pool_err);
+    }
+
+    //Errors without a Mongo or Redis cause, e.g. an open circuit breaker, are final
+    match err.downcast_ref::<MobcError>() {
+        Some(MobcError::RedisPoolError(pool_err)) => is_pool_retryable(pool_err),
+        Some(MobcError::RedisCMDError(redis_err)) => is_redis_retryable(redis_err, idempotency),
+        _ => false,
+    }
+}
+
+//unavailable is whether the error was classified as DatabaseUnavailable or Timeout
+fn is_mongo_retryable(err: &MongoError, unavailable: bool, idempotency: Idempotency) -> bool {
+    //The server aborted the whole transaction, nothing of it was applied
+    if err.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+        return true;
+    }
+
+    match err.kind.as_ref() {
+        //No server was reached
+        ErrorKind::ServerSelection { .. }
+        | ErrorKind::ConnectionPoolCleared { .. }
+        | ErrorKind::DnsResolve { .. } => true,
+        //The connection broke with the command in flight, it may have been applied
+        ErrorKind::Io(_) => idempotency == Idempotency::Idempotent,
+        //The server refused the write before applying it, e.g. a primary stepping down
+        _ if err.contains_label(RETRYABLE_WRITE_ERROR) => true,
+        _ => unavailable && idempotency == Idempotency::Idempotent,
+    }
+}
+
+//Nothing was sent while getting a connection
+fn is_pool_retryable(err: &mobc::Error<RedisError>) -> bool {
+    !matches!(err, mobc::Error::PoolClosed)
+}
+
+fn is_redis_retryable(err: &RedisError, idempotency: Idempotency) -> bool {
+    if err.is_connection_refusal() {
+        return true;
+    }
+    if err.is_timeout() || err.is_io_error() || err.is_connection_dropped() {
+        return idempotency == Idempotency::Idempotent;
+    }
+
+    //Redis refused the command without running it
+    matches!(
+        err.kind(),
+        RedisErrorKind::BusyLoadingError
+            | RedisErrorKind::TryAgain
+            | RedisErrorKind::ClusterDown
+            | RedisErrorKind::MasterDown
+            | RedisErrorKind::ReadOnly
+    )
+}
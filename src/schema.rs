@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+
+use crate::book_types::MongoStorable;
+use crate::error::{DatastoreError, Result};
+use crate::Datastore;
+
+//The $jsonSchema a type serializes to. Derived with #[derive(BsonSchema)], which follows
+//#[serde(rename, default, skip)] on fields
+pub trait BsonSchema {
+    fn bson_schema() -> Document;
+
+    //Optional fields are left out of the parent's required list
+    fn is_optional() -> bool {
+        false
+    }
+}
+
+fn bson_type(bson_type: &str) -> Document {
+    doc! { "bsonType": bson_type }
+}
+
+impl BsonSchema for String {
+    fn bson_schema() -> Document {
+        bson_type("string")
+    }
+}
+
+impl BsonSchema for bool {
+    fn bson_schema() -> Document {
+        bson_type("bool")
+    }
+}
+
+//Other drivers and the shell write small numbers as int whatever the Rust type is
+macro_rules! integer_schema {
+    ($($integer:ty),*) => {
+        $(impl BsonSchema for $integer {
+            fn bson_schema() -> Document {
+                doc! { "bsonType": ["int", "long"] }
+            }
+        })*
+    };
+}
+
+integer_schema!(i8, i16, i32, i64, u8, u16, u32, u64);
+
+impl BsonSchema for f32 {
+    fn bson_schema() -> Document {
+        bson_type("number")
+    }
+}
+
+impl BsonSchema for f64 {
+    fn bson_schema() -> Document {
+        bson_type("number")
+    }
+}
+
+impl BsonSchema for DateTime {
+    fn bson_schema() -> Document {
+        bson_type("date")
+    }
+}
+
+impl BsonSchema for ObjectId {
+    fn bson_schema() -> Document {
+        bson_type("objectId")
+    }
+}
+
+impl BsonSchema for Document {
+    fn bson_schema() -> Document {
+        bson_type("object")
+    }
+}
+
+//Anything goes
+impl BsonSchema for Bson {
+    fn bson_schema() -> Document {
+        Document::new()
+    }
+}
+
+impl<T> BsonSchema for Option<T>
+where
+    T: BsonSchema,
+{
+    fn bson_schema() -> Document {
+        let mut schema = T::bson_schema();
+
+        let nullable = match schema.remove("bsonType") {
+            Some(Bson::Array(mut bson_types)) => {
+                bson_types.push(Bson::from("null"));
+                Some(Bson::Array(bson_types))
+            }
+            Some(bson_type) => Some(Bson::Array(vec![bson_type, Bson::from("null")])),
+            None => None,
+        };
+
+        if let Some(nullable) = nullable {
+            schema.insert("bsonType", nullable);
+        }
+
+        schema
+    }
+
+    fn is_optional() -> bool {
+        true
+    }
+}
+
+impl<T> BsonSchema for Vec<T>
+where
+    T: BsonSchema,
+{
+    fn bson_schema() -> Document {
+        doc! { "bsonType": "array", "items": T::bson_schema() }
+    }
+}
+
+impl<T> BsonSchema for HashMap<String, T>
+where
+    T: BsonSchema,
+{
+    fn bson_schema() -> Document {
+        doc! { "bsonType": "object", "additionalProperties": T::bson_schema() }
+    }
+}
+
+impl<T> BsonSchema for BTreeMap<String, T>
+where
+    T: BsonSchema,
+{
+    fn bson_schema() -> Document {
+        doc! { "bsonType": "object", "additionalProperties": T::bson_schema() }
+    }
+}
+
+//Whether the field at a dotted path like "data.bookstore_id" may be missing from documents
+//matching the schema. Unknown fields and fields inside arrays count as required
+pub(crate) fn is_optional_field(schema: &Document, path: &str) -> bool {
+    let mut current = schema;
+    let mut segments = path.split('.').peekable();
+
+    while let Some(segment) = segments.next() {
+        let property = match current.get_document("properties") {
+            Ok(properties) => properties.get_document(segment),
+            Err(_) => return false,
+        };
+        let property = match property {
+            Ok(property) => property,
+            Err(_) => return false,
+        };
+
+        if segments.peek().is_none() {
+            return !current
+                .get_array("required")
+                .map(|required| required.contains(&Bson::from(segment)))
+                .unwrap_or(false);
+        }
+
+        current = property;
+    }
+
+    false
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationLevel {
+    //Every insert and update is checked
+    Strict,
+    //Updates to documents that already fail validation are let through
+    Moderate,
+    Off,
+}
+
+impl ValidationLevel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ValidationLevel::Strict => "strict",
+            ValidationLevel::Moderate => "moderate",
+            ValidationLevel::Off => "off",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationAction {
+    //Reject the write
+    Error,
+    //Accept the write and log it on the server
+    Warn,
+}
+
+impl ValidationAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ValidationAction::Error => "error",
+            ValidationAction::Warn => "warn",
+        }
+    }
+}
+
+//Most invalid ids a validation report lists
+pub const VALIDATION_REPORT_LIMIT: usize = 1000;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    pub collection: String,
+    //Ids of the stored documents the validator would reject
+    pub invalid_ids: Vec<String>,
+    //More documents are invalid than the VALIDATION_REPORT_LIMIT listed
+    pub truncated: bool,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.invalid_ids.is_empty()
+    }
+}
+
+fn json_schema_of<T>() -> Result<Document>
+where
+    T: MongoStorable,
+{
+    T::json_schema().ok_or_else(|| {
+        DatastoreError::validation(format!(
+            "{} declares no schema, add #[storable(validate)]",
+            T::COLLECTION
+        ))
+    })
+}
+
+impl Datastore {
+    //Installs T's schema as the validator of its collection, creating the collection if
+    //it does not exist yet. Documents already stored are not checked, see try_validation_report
+    pub async fn try_apply_validator<T>(
+        &self,
+        level: ValidationLevel,
+        action: ValidationAction,
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        let json_schema = json_schema_of::<T>()?;
+
+        self.database
+            .try_set_validator(
+                T::COLLECTION,
+                doc! { "$jsonSchema": json_schema },
+                level.as_str(),
+                action.as_str(),
+            )
+            .await
+    }
+
+    //Lists the stored documents that do not match T's schema, whether or not the
+    //validator has been applied. Only their ids are read, up to VALIDATION_REPORT_LIMIT
+    pub async fn try_validation_report<T>(&self) -> Result<ValidationReport>
+    where
+        T: MongoStorable,
+    {
+        let json_schema = json_schema_of::<T>()?;
+
+        let mut invalid_ids: Vec<String> = self
+            .database
+            .try_find_ids(
+                T::COLLECTION,
+                doc! { "$nor": [{ "$jsonSchema": json_schema }] },
+                Some(VALIDATION_REPORT_LIMIT as i64 + 1),
+            )
+            .await?
+            .iter()
+            .map(|id| {
+                id.as_str()
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| id.to_string())
+            })
+            .collect();
+
+        let truncated = invalid_ids.len() > VALIDATION_REPORT_LIMIT;
+        invalid_ids.truncate(VALIDATION_REPORT_LIMIT);
+
+        Ok(ValidationReport {
+            collection: T::COLLECTION.to_owned(),
+            invalid_ids,
+            truncated,
+        })
+    }
+}
@@ -0,0 +1,154 @@
+use bson::{doc, from_document, Bson, Document};
+use std::time::Instant;
+
+use chrono::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::audit::{AuditDraft, AuditOperation};
+use crate::book_types::MongoStorable;
+use crate::cache::redis::record_cache_ops;
+use crate::error::{DatastoreError, Result};
+use crate::integrity;
+use crate::migrations::try_upgrade;
+use crate::timestamps::{cache_value, CREATED_AT_FIELD, UPDATED_AT_FIELD};
+use crate::unique::map_unique_violation;
+use crate::Datastore;
+
+//Set on records of soft-delete collections instead of removing them. A missing or null
+//field means the record is live, every read filters on that by default
+pub const DELETED_AT_FIELD: &str = "deleted_at";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadOptions {
+    //Soft-deleted records are returned as well, for admin tooling
+    pub include_deleted: bool,
+}
+
+impl ReadOptions {
+    pub fn include_deleted() -> Self {
+        Self {
+            include_deleted: true,
+        }
+    }
+}
+
+//Narrows filter down to live records unless the options ask for deleted ones too
+pub(crate) fn visible(mut filter: Document, options: ReadOptions) -> Document {
+    if !options.include_deleted {
+        filter.insert(DELETED_AT_FIELD, Bson::Null);
+    }
+    filter
+}
+
+impl Datastore {
+    //The ids among record_ids that are soft-deleted, writes to them are refused
+    pub(crate) async fn try_deleted_ids<T>(&self, record_ids: Vec<String>) -> Result<Vec<String>>
+    where
+        T: MongoStorable,
+    {
+        if !T::SOFT_DELETE || record_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let deleted = self
+            .database
+            .try_distinct(
+                T::COLLECTION,
+                "_id",
+                doc! { "_id": { "$in": record_ids }, DELETED_AT_FIELD: { "$ne": Bson::Null } },
+                None,
+            )
+            .await?;
+
+        Ok(deleted
+            .into_iter()
+            .filter_map(|id| id.as_str().map(str::to_owned))
+            .collect())
+    }
+
+    //Brings a soft-deleted record back and caches it again. Its references and unique keys
+    //are checked first, a target may have been purged or a value taken while it was deleted
+    pub async fn try_restore<T>(&self, record_id: &str, cache_expiry: Option<usize>) -> Result<T>
+    where
+        T: MongoStorable + Serialize + DeserializeOwned,
+    {
+        let started = Instant::now();
+        let mut document = self
+            .database
+            .try_find_one(
+                T::COLLECTION,
+                doc! { "_id": record_id, DELETED_AT_FIELD: { "$ne": Bson::Null } },
+            )
+            .await?;
+
+        let created_at = document.get_datetime(CREATED_AT_FIELD).ok().copied();
+        let deleted_document = document.clone();
+        let _ = try_upgrade::<T>(&mut document)?;
+        let record = from_document::<T>(document)?;
+
+        integrity::try_check_references(&self.database, None, &record).await?;
+        self.try_check_unique(T::COLLECTION, &record).await?;
+
+        //The partial unique indexes still catch a value taken since the check
+        let now = self.now();
+        let restored = self
+            .database
+            .try_update_matching(
+                T::COLLECTION,
+                doc! { "_id": record_id, DELETED_AT_FIELD: { "$ne": Bson::Null } },
+                doc! {
+                    "$unset": { DELETED_AT_FIELD: "" },
+                    "$set": { UPDATED_AT_FIELD: now },
+                },
+                None,
+            )
+            .await
+            .map_err(|err| map_unique_violation(err, &record))?;
+
+        //Restored or purged by someone else in the meantime
+        if restored == 0 {
+            return Err(DatastoreError::not_found(T::COLLECTION, record_id));
+        }
+
+        let mut restored_document = deleted_document.clone();
+        restored_document.remove(DELETED_AT_FIELD);
+        let audit_drafts = vec![AuditDraft::new(
+            AuditOperation::Update,
+            T::COLLECTION,
+            record_id,
+            Some(deleted_document.clone()),
+            Some(restored_document),
+        )];
+        //The cache follows the restore before anything else can fail
+        let value = cache_value(&record, created_at, now)?;
+        let cache_ops = record_cache_ops(
+            T::COLLECTION,
+            T::CACHE_NAMESPACE,
+            &record,
+            value,
+            cache_expiry,
+        )?;
+        self.cache.try_apply(cache_ops).await?;
+
+        self.try_write_history::<T>(vec![deleted_document], now)
+            .await?;
+
+        self.record_audit(audit_drafts, started).await;
+
+        Ok(record)
+    }
+
+    //Removes the records soft-deleted more than older_than ago for good, applying the
+    //cascade and set-null rules that the soft delete held back. Meant to run as a periodic job
+    pub async fn try_purge_deleted<T>(&self, older_than: Duration) -> Result<u64>
+    where
+        T: MongoStorable,
+    {
+        let cutoff = bson::DateTime::from_chrono(self.clock.now() - older_than);
+
+        self.transaction(|tx| {
+            Box::pin(async move { tx.try_purge_deleted::<T>(T::COLLECTION, cutoff).await })
+        })
+        .await
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bson::{doc, Document};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::audit::AuditDraft;
+use crate::error::Result;
+use crate::Datastore;
+
+//Mongo caps database names at 64 bytes, the tenant id shares it with the base name
+pub const MAX_TENANT_ID_LENGTH: usize = 32;
+
+//Every tenant's cache keys sit under tenant:<tenant_id>:
+pub(crate) const TENANT_CACHE_PREFIX: &str = "tenant";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TenantError {
+    #[error("invalid tenant id {0:?}, use 1 to 32 ASCII letters, digits, '-' or '_'")]
+    InvalidId(String),
+    #[error("handle is scoped to tenant {current} already, cannot switch to {requested}")]
+    AlreadyScoped { current: String, requested: String },
+    #[error("{0} needs a tenant handle, see Datastore::tenant")]
+    NotScoped(&'static str),
+}
+
+fn is_valid_tenant_id(tenant_id: &str) -> bool {
+    !tenant_id.is_empty()
+        && tenant_id.len() <= MAX_TENANT_ID_LENGTH
+        && tenant_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub(crate) fn tenant_database(db_name: &str, tenant_id: &str) -> String {
+    format!("{}__{}", db_name, tenant_id)
+}
+
+pub(crate) fn tenant_cache_prefix(tenant_id: &str) -> String {
+    format!("{}:{}", TENANT_CACHE_PREFIX, tenant_id)
+}
+
+impl Datastore {
+    //A handle whose records live in a database of their own, <db>__<tenant_id>, and whose
+    //cache keys all sit under tenant:<tenant_id>:. It can't reach other tenants or the
+    //shared data. Indexes and cache formats are checked once per handle, so keep one per
+    //tenant around rather than scoping on every request
+    //
+    //let acme = datastore.tenant("acme")?;
+    //let books = acme.collection::<BookRecord>();
+    pub fn tenant(&self, tenant_id: &str) -> Result<Datastore> {
+        if let Some(current) = &self.tenant {
+            return Err(TenantError::AlreadyScoped {
+                current: current.to_owned(),
+                requested: tenant_id.to_owned(),
+            }
+            .into());
+        }
+
+        if !is_valid_tenant_id(tenant_id) {
+            return Err(TenantError::InvalidId(tenant_id.to_owned()).into());
+        }
+
+        let db_name = tenant_database(self.database.db.name(), tenant_id);
+
+        Ok(Datastore {
+            database: self.database.with_database(&db_name),
+            cache: self.cache.scoped(&tenant_cache_prefix(tenant_id)),
+            idempotency_index: Arc::new(OnceCell::new()),
+            ensured_indexes: Arc::new(Mutex::new(HashSet::new())),
+            synced_cache_formats: Arc::new(Mutex::new(HashSet::new())),
+            clock: self.clock.clone(),
+            actor: self.actor.clone(),
+            audit: self.audit.clone(),
+            audit_index: Arc::new(OnceCell::new()),
+            audit_status: self.audit_status.clone(),
+            tenant: Some(tenant_id.to_owned()),
+            health: self.health.clone(),
+        })
+    }
+
+    pub fn tenant_id(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    fn try_tenant_id(&self, operation: &'static str) -> Result<&str> {
+        self.tenant
+            .as_deref()
+            .ok_or_else(|| TenantError::NotScoped(operation).into())
+    }
+
+    //Every collection of the tenant by name, with all of its documents as stored.
+    //Soft-deleted records, history and the audit log are included
+    pub async fn try_export_tenant(&self) -> Result<HashMap<String, Vec<Document>>> {
+        let _ = self.try_tenant_id("try_export_tenant")?;
+
+        let mut export = HashMap::new();
+        for table in self.database.try_list_collections().await? {
+            let documents = self
+                .database
+                .try_find_matching(&table, doc! {}, None)
+                .await?;
+            export.insert(table, documents);
+        }
+
+        Ok(export)
+    }
+
+    //Drops the tenant's database and its cache keys, nobody else's. Take a new handle
+    //from Datastore::tenant to write to the tenant again, this one skips index setup
+    //The clear is audited per collection. With a Mongo sink the entries land in the
+    //emptied database, the only trace of it left
+    pub async fn try_clear_tenant(&self) -> Result<()> {
+        self.try_tenant_id("try_clear_tenant")?;
+
+        let started = Instant::now();
+        let tables = self.database.try_list_collections().await?;
+        self.database.try_drop_database().await?;
+        self.cache.try_clear_cache().await?;
+
+        let audit_drafts = tables
+            .iter()
+            .map(|table| AuditDraft::clear(table))
+            .collect();
+        self.record_audit(audit_drafts, started).await;
+
+        Ok(())
+    }
+}
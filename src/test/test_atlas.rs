@@ -5,10 +5,10 @@ mod atlas_tests {
 
     use crate::mongodb::atlas::Atlas;
 
-    use crate::book_types::{Book, BookRecord, Bookstore, BookstoreRecord};
+    use crate::book_types::{Book, BookRecord, Bookstore, BookstoreRecord, MongoStorable};
+    use crate::relations::relation_to;
 
     use bson::{doc, to_document, Document};
-    use futures_util::StreamExt;
     use odds_api::test_data::TestData;
 
     #[tokio::test]
@@ -74,7 +74,7 @@ mod atlas_tests {
         let record_id = "abe2c187d35b88402a28c99a113601e9".to_string();
 
         let read_result = atlas
-            .try_read_one::<Document>(table, &record_id)
+            .try_read_one(table, &record_id)
             .await
             .unwrap();
 
@@ -179,7 +179,7 @@ mod atlas_tests {
 
         let record_id = "e40d079e6db5293e7e0aa22e0c857a85";
 
-        let delete_result = atlas.try_delete_one(table, record_id).await.unwrap();
+        atlas.try_delete_one(table, record_id).await.unwrap();
     }
 
     #[tokio::test]
@@ -193,7 +193,7 @@ mod atlas_tests {
             "0aa7ba9d4ef9dfacd6c1d4e545b86e87".to_string(),
         ];
 
-        let _ = atlas.try_delete_many(table, delete_ids).await.unwrap();
+        atlas.try_delete_many(table, delete_ids).await.unwrap();
     }
 
     #[tokio::test]
@@ -225,9 +225,9 @@ mod atlas_tests {
             .map(|game| to_document(game).unwrap())
             .collect::<Vec<Document>>();
 
-        let res = atlas.try_insert_many(table, outcomes).await.unwrap();
+        atlas.try_insert_many(table, outcomes).await.unwrap();
 
-        atlas.try_delete_all(table).await;
+        atlas.try_delete_all(table).await.unwrap();
     }
 
     #[tokio::test]
@@ -247,32 +247,40 @@ mod atlas_tests {
         let test_bookstore = BookstoreRecord {
             _id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
             data: Bookstore {
-                name: "The Paper Hound".to_owned(),
-                address: "344 W Pender St, Vancouver, BC V6B 1T1".to_owned(),
-                number: "(604) 428-1344".to_owned(),
+                name: "Book Warehouse".to_owned(),
+                address: "632 W Broadway, Vancouver, BC V5Z 1G1".to_owned(),
+                number: "(604) 872-5711".to_owned(),
             },
         };
 
+        //Upserted, the datastore tests seed the same bookstore
         atlas
-            .try_insert_one("bookstore", &test_bookstore)
+            .try_upsert_one(
+                "bookstores",
+                &test_bookstore._id,
+                to_document(&test_bookstore).unwrap(),
+                None,
+            )
             .await
             .unwrap();
 
         let assertion_value = doc! {
             "_id": "2b7245f77b1866f1fd422944eca23609",
             "data": doc! {
-                "name":  "The Paper Hound",
-                "address": "344 W Pender St, Vancouver, BC V6B 1T1",
-                "number": "(604) 428-1344",
+                "name":  "Book Warehouse",
+                "address": "632 W Broadway, Vancouver, BC V5Z 1G1",
+                "number": "(604) 872-5711",
             }
         };
 
-        let res = atlas
-            .try_find_one_bookstore::<BookRecord>(test_record)
-            .await
-            .unwrap();
+        let relation = relation_to::<BookRecord>(BookstoreRecord::COLLECTION).unwrap();
+        let keys = vec![test_record.data.bookstore_id.clone().into()];
+
+        let res = atlas.try_find_related(&relation, keys).await.unwrap();
+
+        assert_eq!(res, vec![assertion_value]);
 
-        assert_eq!(res, assertion_value);
+        atlas.try_delete_all("bookstores").await.unwrap();
     }
 
     #[tokio::test]
@@ -317,20 +325,29 @@ mod atlas_tests {
         };
 
         let book_records = vec![book_record_1, book_record_2];
-        let bookstore_records = vec![bookstore_record_1, bookstore_record_2];
+        let bookstore_records = [bookstore_record_1, bookstore_record_2];
         let book_ids = vec![
             "03d15979ffd0df61cd6dd3d5a2fc4d04",
             "56420b74c402bfccb04db2542d901054",
         ];
 
         let _ = atlas.try_insert_many("books", book_records).await.unwrap();
-        let _ = atlas
-            .try_insert_many("bookstores", bookstore_records)
-            .await
-            .unwrap();
+        for bookstore_record in bookstore_records.iter() {
+            let _ = atlas
+                .try_upsert_one(
+                    "bookstores",
+                    bookstore_record.get_id(),
+                    to_document(bookstore_record).unwrap(),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let relation = relation_to::<BookRecord>(BookstoreRecord::COLLECTION).unwrap();
 
         let res = atlas
-            .find_bookstores::<BookstoreRecord>(book_ids)
+            .try_lookup_related::<BookstoreRecord>(BookRecord::COLLECTION, &relation, book_ids)
             .await
             .unwrap();
         // println!("{:#?}", res);
@@ -1,9 +1,105 @@
 #[cfg(test)]
 mod datastore_tests {
-    use crate::book_types::{Book, BookRecord, MongoStorable};
-    use crate::{Cache, CacheState, Datastore};
+    use crate::book_types::{Book, BookRecord, Bookstore, BookstoreRecord, MongoStorable};
+    use crate::cache::breaker::CircuitBreaker;
+    use crate::cache::redis::{CacheOp, RedisCache};
+    use crate::{
+        AuditConfig, AuditFilter, AuditOperation, BreakerState, CacheState, CircuitBreakerConfig,
+        Clock, ConfigError, Datastore, DatastoreConfig, DatastoreError, HealthConfig, HealthStatus,
+        IdStrategy, IntegrityError, Migration, MockClock, ModifiedCursor, ReadConcernLevel,
+        ReadOptions, RedisPoolConfig, Relation, ReplicationRole, RetryPolicy, Storable,
+        TenantError, UniqueViolation, UpsertOutcome, ValidationAction, ValidationLevel, WriteOp,
+        WriteOutcome,
+    };
     use bson::{doc, from_document, to_document, Document};
-    use std::{collections::HashMap, time::Duration};
+    use chrono::{TimeZone, Utc};
+    use mobc_redis::redis::RedisError;
+    use serde::{Deserialize, Serialize};
+    use std::{sync::Arc, time::Duration};
+
+    //Books are rejected unless their bookstore exists, upserting keeps this safe to run in parallel.
+    //Bookstore names are unique, so each seeded store gets its own
+    async fn seed_bookstore(data_store: &Datastore, bookstore_id: &str) {
+        let bookstore_record = BookstoreRecord {
+            _id: bookstore_id.to_owned(),
+            data: Bookstore {
+                name: format!("Book Warehouse {}", bookstore_id),
+                address: "632 W Broadway, Vancouver, BC V5Z 1G1".to_owned(),
+                number: "(604) 872-5711".to_owned(),
+            },
+        };
+
+        let _ = data_store
+            .database
+            .try_upsert_one(
+                BookstoreRecord::COLLECTION,
+                bookstore_id,
+                to_document(&bookstore_record).unwrap(),
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    //Stored as {"title"} at version 0, {"name"} at 1 and {"name", "author"} at 2
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+    #[storable(collection = "migrated_books", version = 2)]
+    #[storable(migration = Migration::new(1, "rename title to name", rename_title))]
+    #[storable(migration = Migration::new(2, "default missing authors", default_author))]
+    struct MigratedBookRecord {
+        _id: String,
+        data: MigratedBook,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct MigratedBook {
+        name: String,
+        author: String,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+    #[storable(collection = "sequenced_books", id_strategy = IdStrategy::Sequence)]
+    struct SequencedBookRecord {
+        _id: String,
+        data: MigratedBook,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+    #[storable(collection = "archived_books", soft_delete)]
+    struct ArchivedBookRecord {
+        _id: String,
+        data: MigratedBook,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+    #[storable(collection = "versioned_books", history)]
+    struct VersionedBookRecord {
+        _id: String,
+        data: MigratedBook,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Storable)]
+    #[storable(collection = "audited_books", soft_delete)]
+    struct AuditedBookRecord {
+        _id: String,
+        data: MigratedBook,
+    }
+
+    fn rename_title(document: &mut Document) -> anyhow::Result<()> {
+        let data = document.get_document_mut("data")?;
+        if let Some(title) = data.remove("title") {
+            data.insert("name", title);
+        }
+        Ok(())
+    }
+
+    fn default_author(document: &mut Document) -> anyhow::Result<()> {
+        let data = document.get_document_mut("data")?;
+        if !data.contains_key("author") {
+            data.insert("author", "Unknown");
+        }
+        Ok(())
+    }
 
     //create + delete (assert the deletion is successful)
 
@@ -14,6 +110,7 @@ mod datastore_tests {
     async fn test_01_try_create_read_one_from_redis() {
         let db_name = "fnchart";
         let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
         let table = "books1";
 
         let book_record = BookRecord {
@@ -42,7 +139,7 @@ mod datastore_tests {
         assert_eq!(CacheState::Hit, cache_state);
         assert_eq!(book_record, returned_book_record);
 
-        let _ = data_store.clear_datastore("books1").await.unwrap();
+        data_store.try_clear_datastore("books1").await.unwrap();
     }
 
     //Expiry time should be a property of the cache itself and set during constructor
@@ -51,6 +148,7 @@ mod datastore_tests {
     async fn test_02_try_create_read_one_from_atlas() {
         let db_name = "fnchart";
         let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
         let table = "books2";
 
         let book_record = BookRecord {
@@ -82,13 +180,14 @@ mod datastore_tests {
         assert_eq!(CacheState::Miss, cache_state);
         assert_eq!(book_record, returned_book_record);
 
-        let _ = data_store.clear_datastore("books2").await.unwrap();
+        data_store.try_clear_datastore("books2").await.unwrap();
     }
 
     #[tokio::test]
     async fn test_03_try_create_update_one() {
         let db_name = "fnchart";
         let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
         let table = "books";
 
         let book_record = BookRecord {
@@ -130,13 +229,14 @@ mod datastore_tests {
 
         assert_eq!(update_record, returned_updated_book_record);
 
-        let _ = data_store.clear_datastore("books").await.unwrap();
+        data_store.try_clear_datastore("books").await.unwrap();
     }
 
     #[tokio::test]
     async fn test_04_try_create_delete_one() {
         let db_name = "fnchart";
         let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
         let table = "books4";
 
         let book_record = BookRecord {
@@ -153,8 +253,8 @@ mod datastore_tests {
             .await
             .unwrap();
 
-        let _ = data_store
-            .try_delete(table, &book_record._id)
+        data_store
+            .try_delete::<BookRecord>(table, &book_record._id)
             .await
             .unwrap();
 
@@ -168,10 +268,1926 @@ mod datastore_tests {
     }
 
     #[tokio::test]
-    async fn test_10_clear_data_store() {
+    async fn test_05_try_upsert_one() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        let table = "books5";
+
+        let book_record = BookRecord {
+            _id: "7c1d0e5f3b2a49e8a6f4d2c1b0a98765".to_owned(),
+            data: Book {
+                name: "Cannery Row".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let insert_res = data_store
+            .try_upsert_one(
+                table,
+                "books",
+                book_record.clone(),
+                Some(doc! { "source": "ingest" }),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(UpsertOutcome::Inserted, insert_res.outcome);
+
+        let mut update_record = book_record.clone();
+        update_record.data.name = "Sweet Thursday".to_owned();
+
+        let update_res = data_store
+            .try_upsert_one(table, "books", update_record.clone(), None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(UpsertOutcome::Updated, update_res.outcome);
+
+        let atlas_res = data_store
+            .database
+            .try_read_one(table, &book_record._id)
+            .await
+            .unwrap();
+
+        assert_eq!("ingest", atlas_res.get_str("source").unwrap());
+        assert_eq!(update_record, from_document::<BookRecord>(atlas_res).unwrap());
+
+        //One bulk upsert, set_on_insert only reaches the record it inserts
+        let mut new_record = book_record.clone();
+        new_record._id = "0f9e8d7c6b5a49382716f5e4d3c2b1a0".to_owned();
+        new_record.data.name = "The Pearl".to_owned();
+
+        let upserts = data_store
+            .try_upsert_many(
+                table,
+                "books",
+                vec![update_record.clone(), new_record.clone()],
+                Some(doc! { "source": "bulk" }),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let outcomes: Vec<UpsertOutcome> =
+            upserts.into_iter().map(|upsert| upsert.outcome).collect();
+        assert_eq!(
+            vec![UpsertOutcome::Updated, UpsertOutcome::Inserted],
+            outcomes
+        );
+
+        let updated_res = data_store
+            .database
+            .try_read_one(table, &book_record._id)
+            .await
+            .unwrap();
+        let inserted_res = data_store
+            .database
+            .try_read_one(table, &new_record._id)
+            .await
+            .unwrap();
+        assert_eq!("ingest", updated_res.get_str("source").unwrap());
+        assert_eq!("bulk", inserted_res.get_str("source").unwrap());
+
+        data_store.try_clear_datastore(table).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_06_try_update_missing_one() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        let table = "books6";
+
+        let book_record = BookRecord {
+            _id: "e9b8c7d6a5f4e3d2c1b0a9f8e7d6c5b4".to_owned(),
+            data: Book {
+                name: "Tortilla Flat".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let update_res = data_store
+            .try_update_one(table, "books", book_record, None)
+            .await;
+
+        assert!(update_res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_07_bulk_write() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        let table = "books7";
+
+        let book_record_1 = BookRecord {
+            _id: "4f3e2d1c0b9a48776655443322110099".to_owned(),
+            data: Book {
+                name: "Foundation".to_owned(),
+                author: "Isaac Asimov".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let book_record_2 = BookRecord {
+            _id: "8899aabbccddeeff0011223344556677".to_owned(),
+            data: Book {
+                name: "Foundation and Empire".to_owned(),
+                author: "Isaac Asimov".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let mut update_record = book_record_1.clone();
+        update_record.data.name = "Second Foundation".to_owned();
+
+        let operations = vec![
+            WriteOp::Insert(book_record_1.clone()),
+            WriteOp::Update(update_record.clone()),
+            WriteOp::Upsert(book_record_2.clone()),
+            WriteOp::Insert(book_record_1.clone()),
+            WriteOp::Delete(book_record_2._id.clone()),
+        ];
+
+        let outcomes = data_store
+            .bulk_write(table, "books", operations, true, None)
+            .await
+            .unwrap();
+
+        assert_eq!(WriteOutcome::Inserted, outcomes[0]);
+        assert_eq!(WriteOutcome::Updated, outcomes[1]);
+        assert_eq!(
+            WriteOutcome::Upserted(UpsertOutcome::Inserted),
+            outcomes[2]
+        );
+        assert!(matches!(outcomes[3], WriteOutcome::Failed(_)));
+        assert_eq!(WriteOutcome::Skipped, outcomes[4]);
+
+        let read_res = data_store
+            .try_read::<BookRecord>(table, &book_record_2._id)
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Hit, read_res.state);
+        assert_eq!(book_record_2, read_res.data);
+
+        //Unordered, the updates and the deletes each go out as a single command
+        let mut missing_record = book_record_1.clone();
+        missing_record._id = "00112233445566778899aabbccddeeff".to_owned();
+
+        let operations = vec![
+            WriteOp::Update(update_record.clone()),
+            WriteOp::Update(missing_record.clone()),
+            WriteOp::Delete(missing_record._id.clone()),
+            WriteOp::Delete(book_record_2._id.clone()),
+        ];
+
+        let outcomes = data_store
+            .bulk_write(table, "books", operations, false, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                WriteOutcome::Updated,
+                WriteOutcome::NotFound,
+                WriteOutcome::NotFound,
+                WriteOutcome::Deleted,
+            ],
+            outcomes
+        );
+
+        data_store.try_clear_datastore(table).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_08_transaction_commit_and_abort() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        let table = "books8";
+
+        let book_record = BookRecord {
+            _id: "0f1e2d3c4b5a69788796a5b4c3d2e1f0".to_owned(),
+            data: Book {
+                name: "The Winter of Our Discontent".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let committed = book_record.clone();
+        let _ = data_store
+            .transaction(|tx| {
+                let record = committed.clone();
+                Box::pin(async move { tx.try_create_one(table, "books", record, None).await })
+            })
+            .await
+            .unwrap();
+
+        let mut aborted = book_record.clone();
+        aborted.data.name = "Travels with Charley".to_owned();
+
+        let abort_res = data_store
+            .transaction(|tx| {
+                let record = aborted.clone();
+                Box::pin(async move {
+                    tx.try_update_one(table, "books", record, None).await?;
+                    tx.try_delete::<BookRecord>(table, "books", "missing-book-id")
+                        .await
+                })
+            })
+            .await;
+
+        assert!(abort_res.is_err());
+
+        let read_res = data_store
+            .try_read::<BookRecord>(table, &book_record._id)
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Hit, read_res.state);
+        assert_eq!(book_record, read_res.data);
+
+        data_store.try_clear_datastore(table).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_09_try_create_one_idempotent() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        let table = "books9";
+        let idempotency_key = "create-book-9c8b7a6f5e4d";
+
+        let book_record = BookRecord {
+            _id: "1a2b3c4d5e6f708192a3b4c5d6e7f809".to_owned(),
+            data: Book {
+                name: "To a God Unknown".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let first_res = data_store
+            .try_create_one_idempotent(idempotency_key, table, "books", book_record.clone(), None)
+            .await
+            .unwrap();
+
+        //A plain retry would fail on the duplicate _id, the replay returns the first result
+        let retry_res = data_store
+            .try_create_one_idempotent(idempotency_key, table, "books", book_record.clone(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(first_res, retry_res);
+
+        data_store.try_clear_datastore(table).await.unwrap();
+        data_store
+            .database
+            .try_delete_all(crate::idempotency::IDEMPOTENCY_TABLE)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_11_related_and_related_many() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+
+        let bookstore_record = BookstoreRecord {
+            _id: "35fa3010596b8866ec0673550d287fad".to_owned(),
+            data: Bookstore {
+                name: "The Paper Hound".to_owned(),
+                address: "344 W Pender St, Vancouver, BC V6B 1T1".to_owned(),
+                number: "(604) 428-1344".to_owned(),
+            },
+        };
+
+        let book_record = BookRecord {
+            _id: "56420b74c402bfccb04db2542d901054".to_owned(),
+            data: Book {
+                name: "Down and Out In Paris and London".to_owned(),
+                author: "George Orwell".to_owned(),
+                bookstore_id: "35fa3010596b8866ec0673550d287fad".to_owned(),
+            },
+        };
+
+        let _ = data_store
+            .try_create_one("bookstores", "bookstores", bookstore_record.clone(), None)
+            .await
+            .unwrap();
+        let _ = data_store
+            .try_create_one("books", "books", book_record.clone(), None)
+            .await
+            .unwrap();
+
+        let bookstores = data_store
+            .related::<BookstoreRecord>(&book_record)
+            .await
+            .unwrap();
+        assert_eq!(vec![bookstore_record.clone()], bookstores);
+
+        let books = data_store
+            .related::<BookRecord>(&bookstore_record)
+            .await
+            .unwrap();
+        assert_eq!(vec![book_record.clone()], books);
+
+        let carrying_bookstores = data_store
+            .related_many::<BookstoreRecord, BookRecord>(vec![book_record.get_id()])
+            .await
+            .unwrap();
+        assert_eq!(vec![bookstore_record], carrying_bookstores);
+
+        data_store.try_clear_datastore("books").await.unwrap();
+        data_store.try_clear_datastore("bookstores").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_12_relation_index_follows_book_moves() {
         let db_name = "fnchart";
         let data_store = Datastore::try_new(db_name).await.unwrap();
+        seed_bookstore(&data_store, "2b7245f77b1866f1fd422944eca23609").await;
+        seed_bookstore(&data_store, "6e5d4c3b2a1908f7e6d5c4b3a2918070").await;
+
+        let book_record = BookRecord {
+            _id: "03d15979ffd0df61cd6dd3d5a2fc4d04".to_owned(),
+            data: Book {
+                name: "The Grapes of Wrath".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "2b7245f77b1866f1fd422944eca23609".to_owned(),
+            },
+        };
+
+        let _ = data_store
+            .try_create_one("books", "books", book_record.clone(), None)
+            .await
+            .unwrap();
+
+        let store_books = data_store
+            .cache
+            .try_index_members("store_books", vec!["2b7245f77b1866f1fd422944eca23609"])
+            .await
+            .unwrap();
+        assert_eq!(vec![book_record._id.clone()], store_books);
+
+        let mut moved_record = book_record.clone();
+        moved_record.data.bookstore_id = "6e5d4c3b2a1908f7e6d5c4b3a2918070".to_owned();
+
+        let _ = data_store
+            .try_update_one("books", "books", moved_record.clone(), None)
+            .await
+            .unwrap();
+
+        let old_store_books = data_store
+            .cache
+            .try_index_members("store_books", vec!["2b7245f77b1866f1fd422944eca23609"])
+            .await
+            .unwrap();
+        let new_store_books = data_store
+            .cache
+            .try_index_members("store_books", vec!["6e5d4c3b2a1908f7e6d5c4b3a2918070"])
+            .await
+            .unwrap();
+        let book_stores = data_store
+            .cache
+            .try_index_members("book_stores", vec![moved_record.get_id()])
+            .await
+            .unwrap();
+
+        assert!(old_store_books.is_empty());
+        assert_eq!(vec![moved_record._id.clone()], new_store_books);
+        assert_eq!(vec![moved_record.data.bookstore_id.clone()], book_stores);
+
+        data_store
+            .try_delete::<BookRecord>("books", &moved_record._id)
+            .await
+            .unwrap();
+
+        let deleted_store_books = data_store
+            .cache
+            .try_index_members("store_books", vec!["6e5d4c3b2a1908f7e6d5c4b3a2918070"])
+            .await
+            .unwrap();
+        assert!(deleted_store_books.is_empty());
+
+        data_store.try_clear_datastore("books").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_35_related_rebuilds_partial_index() {
+        let data_store = Datastore::try_new("fnchart").await.unwrap();
+        let bookstore_id = "5e7a9c1b3d5f47a9b1c3d5e7f9a1b3c5";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let first_record = BookRecord {
+            _id: "a1b2c3d4e5f60718293a4b5c6d7e8f90".to_owned(),
+            data: Book {
+                name: "East of Eden".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+        let mut second_record = first_record.clone();
+        second_record._id = "0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c".to_owned();
+        second_record.data.name = "Of Mice and Men".to_owned();
+
+        let _ = data_store
+            .try_create_one("books", "books", first_record.clone(), None)
+            .await
+            .unwrap();
+
+        //Lost along with the rest of the cache, the next write only adds itself back
+        data_store
+            .cache
+            .try_apply(vec![CacheOp::Evict {
+                keys: vec![format!("store_books:{}", bookstore_id)],
+            }])
+            .await
+            .unwrap();
+        let _ = data_store
+            .try_create_one("books", "books", second_record.clone(), None)
+            .await
+            .unwrap();
+
+        let partial = data_store
+            .cache
+            .try_populated_members("store_books", vec![bookstore_id])
+            .await
+            .unwrap();
+        assert_eq!(None, partial);
+
+        let bookstore_record = data_store
+            .try_read::<BookstoreRecord>(BookstoreRecord::COLLECTION, bookstore_id)
+            .await
+            .unwrap()
+            .into_data();
+        let mut books = data_store
+            .related::<BookRecord>(&bookstore_record)
+            .await
+            .unwrap();
+        books.sort_by(|a, b| a._id.cmp(&b._id));
+        assert_eq!(vec![second_record.clone(), first_record.clone()], books);
+
+        //Served from the rebuilt set from now on
+        let mut store_books = data_store
+            .cache
+            .try_populated_members("store_books", vec![bookstore_id])
+            .await
+            .unwrap()
+            .unwrap();
+        store_books.sort();
+        assert_eq!(
+            vec![second_record._id.clone(), first_record._id.clone()],
+            store_books
+        );
+
+        for record_id in [&first_record._id, &second_record._id] {
+            data_store
+                .try_delete::<BookRecord>("books", record_id)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_10_clear_data_store() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+
+        data_store.try_clear_datastore("books").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_13_reject_dangling_bookstore_reference() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let table = "books13";
+
+        let book_record = BookRecord {
+            _id: "5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d".to_owned(),
+            data: Book {
+                name: "The Pearl".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: "ffffffffffffffffffffffffffffffff".to_owned(),
+            },
+        };
+
+        let create_err = data_store
+            .try_create_one(table, "books", book_record.clone(), None)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            create_err.downcast_ref::<IntegrityError>(),
+            Some(IntegrityError::DanglingReference { .. })
+        ));
+
+        let outcomes = data_store
+            .bulk_write(table, "books", vec![WriteOp::Insert(book_record.clone())], true, None)
+            .await
+            .unwrap();
+
+        assert!(matches!(outcomes[0], WriteOutcome::Failed(_)));
+
+        let atlas_res = data_store
+            .database
+            .try_read_one(table, &book_record._id)
+            .await;
+
+        assert!(atlas_res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_14_delete_bookstore_cascades_to_books() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let bookstore_id = "c0ffee00c0ffee00c0ffee00c0ffee00";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let book_record = BookRecord {
+            _id: "d4c3b2a1f0e9d8c7b6a5f4e3d2c1b0a9".to_owned(),
+            data: Book {
+                name: "Burning Bright".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+
+        let _ = data_store
+            .try_create_one(BookRecord::COLLECTION, "books", book_record.clone(), None)
+            .await
+            .unwrap();
+
+        data_store
+            .try_delete::<BookstoreRecord>(BookstoreRecord::COLLECTION, bookstore_id)
+            .await
+            .unwrap();
+
+        let atlas_res = data_store
+            .database
+            .try_read_one(BookRecord::COLLECTION, &book_record._id)
+            .await;
+        let cached_books = data_store
+            .cache
+            .try_read_fields("books", vec![book_record._id.clone()])
+            .await
+            .unwrap();
+        let store_books = data_store
+            .cache
+            .try_index_members("store_books", vec![bookstore_id])
+            .await
+            .unwrap();
+
+        assert!(atlas_res.is_err());
+        assert_eq!(vec![None], cached_books);
+        assert!(store_books.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_15_read_bookstore_with_books() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let bookstore_id = "b00c5704eb00c5704eb00c5704eb00c5";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let book_record_1 = BookRecord {
+            _id: "1f2e3d4c5b6a79881f2e3d4c5b6a7988".to_owned(),
+            data: Book {
+                name: "Cup of Gold".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+
+        let book_record_2 = BookRecord {
+            _id: "9a8b7c6d5e4f30219a8b7c6d5e4f3021".to_owned(),
+            data: Book {
+                name: "In Dubious Battle".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+
+        let _ = data_store
+            .try_create_many(
+                BookRecord::COLLECTION,
+                "books",
+                vec![book_record_1.clone(), book_record_2.clone()],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let first_read = data_store
+            .try_read_bookstore_with_books(bookstore_id, None)
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Miss, first_read.state);
+        assert_eq!(bookstore_id, first_read.data.parent._id);
+        assert_eq!(2, first_read.data.children.len());
+
+        let second_read = data_store
+            .try_read_bookstore_with_books(bookstore_id, None)
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Hit, second_read.state);
+        assert_eq!(first_read.data, second_read.data);
+
+        let mut update_record = book_record_1.clone();
+        update_record.data.name = "The Pastures of Heaven".to_owned();
+
+        let _ = data_store
+            .try_update_one(BookRecord::COLLECTION, "books", update_record.clone(), None)
+            .await
+            .unwrap();
+
+        let updated_read = data_store
+            .try_read_bookstore_with_books(bookstore_id, None)
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Miss, updated_read.state);
+        assert!(updated_read.data.children.contains(&update_record));
+
+        data_store
+            .try_delete::<BookstoreRecord>(BookstoreRecord::COLLECTION, bookstore_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_16_unique_bookstore_name() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+
+        let bookstore_record = BookstoreRecord {
+            _id: "a1b2c3d4e5f60718293a4b5c6d7e8f90".to_owned(),
+            data: Bookstore {
+                name: "Pulpfiction Books".to_owned(),
+                address: "2422 Main St, Vancouver, BC V5T 3E2".to_owned(),
+                number: "(604) 876-4311".to_owned(),
+            },
+        };
+
+        let mut duplicate_record = bookstore_record.clone();
+        duplicate_record._id = "0f9e8d7c6b5a49382716f5e4d3c2b1a0".to_owned();
+
+        let _ = data_store
+            .try_create_one(BookstoreRecord::COLLECTION, "bookstores", bookstore_record.clone(), None)
+            .await
+            .unwrap();
+
+        let create_err = data_store
+            .try_create_one(BookstoreRecord::COLLECTION, "bookstores", duplicate_record.clone(), None)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            Some(&UniqueViolation {
+                field: "data.name".to_owned(),
+                value: "Pulpfiction Books".to_owned(),
+            }),
+            create_err.downcast_ref::<UniqueViolation>()
+        );
+
+        //Renaming the first store releases the name
+        let mut renamed_record = bookstore_record.clone();
+        renamed_record.data.name = "Pulpfiction Books East".to_owned();
+
+        let _ = data_store
+            .try_update_one(BookstoreRecord::COLLECTION, "bookstores", renamed_record, None)
+            .await
+            .unwrap();
+        let _ = data_store
+            .try_create_one(BookstoreRecord::COLLECTION, "bookstores", duplicate_record.clone(), None)
+            .await
+            .unwrap();
+
+        data_store
+            .try_delete_many::<BookstoreRecord>(
+                BookstoreRecord::COLLECTION,
+                vec![bookstore_record._id.clone(), duplicate_record._id.clone()],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_17_ensure_indexes() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+
+        let _ = data_store.ensure_all_indexes().await.unwrap();
+
+        //Everything declared exists now, a second run has nothing left to create
+        let book_report = data_store.ensure_indexes::<BookRecord>().await.unwrap();
+        let bookstore_report = data_store
+            .ensure_indexes::<BookstoreRecord>()
+            .await
+            .unwrap();
+
+        assert!(book_report.created.is_empty());
+        assert!(book_report.drifted.is_empty());
+        assert!(bookstore_report.created.is_empty());
+        assert!(bookstore_report.drifted.is_empty());
+
+        let index_names: Vec<String> = data_store
+            .database
+            .try_list_indexes(BookstoreRecord::COLLECTION)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|index| index.options.and_then(|options| options.name))
+            .collect();
+
+        assert!(index_names.contains(&"unique_data.name".to_owned()));
+    }
+
+    #[test]
+    fn test_18_storable_cache_keys() {
+        let bookstore_record = BookstoreRecord {
+            _id: "35fa3010596b8866ec0673550d287fad".to_owned(),
+            data: Bookstore {
+                name: "The Paper Hound".to_owned(),
+                address: "344 W Pender St, Vancouver, BC V6B 1T1".to_owned(),
+                number: "(604) 428-1344".to_owned(),
+            },
+        };
+
+        let (key, value) = bookstore_record.try_to_str().unwrap();
+
+        assert_eq!("bookstores", BookstoreRecord::COLLECTION);
+        assert_eq!("bookstores_35fa3010596b8866ec0673550d287fad", key);
+        assert_eq!(serde_json::to_string(&bookstore_record.data).unwrap(), value);
+        assert_eq!("books", BookRecord::CACHE_NAMESPACE);
+        assert_eq!(1, BookstoreRecord::unique_keys().len());
+    }
+
+    #[tokio::test]
+    async fn test_19_typed_collection() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let bookstore_id = "7c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5f";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let books = data_store.collection::<BookRecord>();
+        assert_eq!("books", books.name());
+
+        let book_record = BookRecord {
+            _id: "e4d3c2b1a09f8e7d6c5b4a3928170615".to_owned(),
+            data: Book {
+                name: "Cat's Cradle".to_owned(),
+                author: "Kurt Vonnegut".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+
+        let _ = books.try_create_one(book_record.clone(), None).await.unwrap();
+
+        let read_res = books.try_read(&book_record._id).await.unwrap();
+        assert_eq!(CacheState::Hit, read_res.state);
+        assert_eq!(book_record, read_res.data);
+
+        let mut update_record = book_record.clone();
+        update_record.data.name = "Slaughterhouse-Five".to_owned();
+        let _ = books.try_update_one(update_record.clone(), None).await.unwrap();
+
+        let read_many = books
+            .try_read_many(vec![book_record._id.clone()])
+            .await
+            .unwrap();
+        assert_eq!(vec![update_record.clone()], read_many);
+
+        //Deleting goes to the books hash, not whatever hash happened to be hard-coded
+        books.try_delete(&book_record._id).await.unwrap();
+
+        let cached_value = data_store
+            .cache
+            .try_read(BookRecord::CACHE_NAMESPACE, &book_record._id)
+            .await;
+        assert!(cached_value.is_err());
+        assert!(books.try_read(&book_record._id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_20_schema_migrations() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let table = MigratedBookRecord::COLLECTION;
+
+        data_store.database.try_delete_all(table).await.unwrap();
+
+        let _ = data_store
+            .database
+            .try_insert_one(table, doc! { "_id": "5f1e2d3c4b5a69788796a5b4c3d2e1f0", "data": { "title": "Dune" } })
+            .await
+            .unwrap();
+        let _ = data_store
+            .database
+            .try_insert_one(
+                table,
+                doc! { "_id": "0e1f2a3b4c5d6e7f8091a2b3c4d5e6f7", "data": { "name": "Emma" }, "_schema_version": 1 },
+            )
+            .await
+            .unwrap();
+
+        //Lazy: the read upgrades the version 0 document and writes it back
+        let read_res = data_store
+            .try_read::<MigratedBookRecord>(table, "5f1e2d3c4b5a69788796a5b4c3d2e1f0")
+            .await
+            .unwrap();
+
+        assert_eq!(CacheState::Miss, read_res.state);
+        assert_eq!(
+            MigratedBook {
+                name: "Dune".to_owned(),
+                author: "Unknown".to_owned(),
+            },
+            read_res.data.data
+        );
+
+        let stored = data_store
+            .database
+            .try_read_one(table, "5f1e2d3c4b5a69788796a5b4c3d2e1f0")
+            .await
+            .unwrap();
+        assert_eq!(Ok(2), stored.get_i64("_schema_version"));
+
+        //Eager: only the version 1 document is left to migrate
+        let report = data_store.try_migrate::<MigratedBookRecord>().await.unwrap();
+        assert_eq!(1, report.migrated);
+
+        let stored = data_store
+            .database
+            .try_read_one(table, "0e1f2a3b4c5d6e7f8091a2b3c4d5e6f7")
+            .await
+            .unwrap();
+        let migrated_record = from_document::<MigratedBookRecord>(stored).unwrap();
+        assert_eq!("Unknown", migrated_record.data.author);
+
+        let report = data_store.try_migrate::<MigratedBookRecord>().await.unwrap();
+        assert_eq!(0, report.migrated);
+        assert!(!report.cache_invalidated);
+
+        data_store.database.try_delete_all(table).await.unwrap();
+    }
+
+    #[test]
+    fn test_21_json_schema_from_types() {
+        let json_schema = BookRecord::json_schema().unwrap();
+
+        assert_eq!(Ok("object"), json_schema.get_str("bsonType"));
+        assert_eq!(
+            vec!["_id", "data"],
+            json_schema
+                .get_array("required")
+                .unwrap()
+                .iter()
+                .filter_map(|field| field.as_str())
+                .collect::<Vec<&str>>()
+        );
+
+        let book_schema = json_schema
+            .get_document("properties")
+            .and_then(|properties| properties.get_document("data"))
+            .unwrap();
+        let name_schema = book_schema
+            .get_document("properties")
+            .and_then(|properties| properties.get_document("name"))
+            .unwrap();
+
+        assert_eq!(Ok("string"), name_schema.get_str("bsonType"));
+        assert_eq!(3, book_schema.get_array("required").unwrap().len());
+
+        use crate::BsonSchema;
+
+        #[derive(BsonSchema, Serialize)]
+        struct Note {
+            title: String,
+            #[serde(skip_serializing_if = "String::is_empty")]
+            body: String,
+        }
+
+        //A field serde leaves out when empty may be missing from the document
+        assert_eq!(
+            Ok(&vec![bson::Bson::from("title")]),
+            Note::bson_schema().get_array("required")
+        );
+
+        #[derive(BsonSchema, Serialize)]
+        struct ShelvedBook {
+            bookstore_id: Option<String>,
+        }
+
+        #[derive(BsonSchema, Serialize)]
+        struct ShelvedBookRecord {
+            _id: String,
+            data: ShelvedBook,
+        }
+
+        //Set-null is only kept for a key the target schema lets go missing
+        let relation = Relation::one_to_many("books", "shelved_books", "data.bookstore_id");
+        assert!(
+            relation
+                .clone()
+                .on_delete_set_null::<ShelvedBookRecord>()
+                .nullable_key
+        );
+        assert!(!relation.on_delete_set_null::<BookRecord>().nullable_key);
+    }
+
+    #[tokio::test]
+    async fn test_22_validator_and_report() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let invalid_id = "9a8b7c6d5e4f30211203f4e5d6c7b8a9";
+
+        //Warn lets the malformed book in, the report still finds it
+        data_store
+            .try_apply_validator::<BookRecord>(ValidationLevel::Strict, ValidationAction::Warn)
+            .await
+            .unwrap();
+
+        let _ = data_store
+            .database
+            .try_delete_matching(BookRecord::COLLECTION, doc! { "_id": invalid_id }, None)
+            .await
+            .unwrap();
+        let _ = data_store
+            .database
+            .try_insert_one(
+                BookRecord::COLLECTION,
+                doc! { "_id": invalid_id, "data": { "name": "Untitled" } },
+            )
+            .await
+            .unwrap();
+
+        let report = data_store
+            .try_validation_report::<BookRecord>()
+            .await
+            .unwrap();
+        assert!(report.invalid_ids.contains(&invalid_id.to_owned()));
+
+        let _ = data_store
+            .database
+            .try_delete_matching(BookRecord::COLLECTION, doc! { "_id": invalid_id }, None)
+            .await
+            .unwrap();
+
+        //Error rejects it outright
+        data_store
+            .try_apply_validator::<BookRecord>(ValidationLevel::Strict, ValidationAction::Error)
+            .await
+            .unwrap();
+
+        let insert_res = data_store
+            .database
+            .try_insert_one(
+                BookRecord::COLLECTION,
+                doc! { "_id": invalid_id, "data": { "name": "Untitled" } },
+            )
+            .await;
+        assert!(insert_res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_23_generated_ids() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let bookstore_id = "7c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5f";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        assert_eq!(32, IdStrategy::UuidV4.generate().unwrap().len());
+        assert_eq!(26, IdStrategy::Ulid.generate().unwrap().len());
+        assert_eq!(None, IdStrategy::Sequence.generate());
+
+        //BookRecord keeps the ObjectId default, the id comes back with the record
+        let books = data_store.collection::<BookRecord>();
+        let book_record = books
+            .try_create_one(
+                BookRecord {
+                    _id: String::new(),
+                    data: Book {
+                        name: "Never Let Me Go".to_owned(),
+                        author: "Kazuo Ishiguro".to_owned(),
+                        bookstore_id: bookstore_id.to_owned(),
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(24, book_record._id.len());
+        assert_eq!(book_record, books.try_read(&book_record._id).await.unwrap().data);
+
+        books.try_delete(&book_record._id).await.unwrap();
+
+        //A sequence hands out a consecutive block per batch
+        let sequenced_books = data_store.collection::<SequencedBookRecord>();
+        let new_record = |name: &str| SequencedBookRecord {
+            _id: String::new(),
+            data: MigratedBook {
+                name: name.to_owned(),
+                author: "Ursula K. Le Guin".to_owned(),
+            },
+        };
+
+        let created = sequenced_books
+            .try_create_many(
+                vec![new_record("The Dispossessed"), new_record("The Lathe of Heaven")],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let sequence: Vec<u64> = created
+            .iter()
+            .map(|record| record._id.parse().unwrap())
+            .collect();
+        assert_eq!(sequence[0] + 1, sequence[1]);
+
+        //A counter lost from Redis starts again after the highest id Mongo holds
+        data_store
+            .cache
+            .try_apply(vec![CacheOp::Evict {
+                keys: vec![format!("id_seq:{}", SequencedBookRecord::COLLECTION)],
+            }])
+            .await
+            .unwrap();
+        let after_eviction = sequenced_books
+            .try_create_one(new_record("The Word for World Is Forest"), None)
+            .await
+            .unwrap();
+        assert!(after_eviction._id.parse::<u64>().unwrap() > sequence[1]);
+
+        data_store
+            .database
+            .try_delete_all(SequencedBookRecord::COLLECTION)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_24_managed_timestamps() {
+        let db_name = "fnchart";
+        //Far enough ahead that nothing else written to books is modified since then
+        let created_at = Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(MockClock::new(created_at));
+        let data_store = Datastore::try_new(db_name)
+            .await
+            .unwrap()
+            .with_clock(clock.clone());
+        let bookstore_id = "8d2e3f4a5b6c7d8e9f0a1b2c3d4e5f6a";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let books = data_store.collection::<BookRecord>();
+        let mut book_record = BookRecord {
+            _id: "9e3f4a5b6c7d8e9f0a1b2c3d4e5f6a7b".to_owned(),
+            data: Book {
+                name: "Piranesi".to_owned(),
+                author: "Susanna Clarke".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+        let _ = books.try_delete(&book_record._id).await;
+
+        let _ = books
+            .try_create_one(book_record.clone(), None)
+            .await
+            .unwrap();
+
+        let created = books.try_read_timestamped(&book_record._id).await.unwrap();
+        assert_eq!(Some(created_at), created.created_at);
+        assert_eq!(Some(created_at), created.updated_at);
+
+        //The cached copy carries the timestamps without changing the record
+        let cached = books.try_read(&book_record._id).await.unwrap();
+        assert_eq!(CacheState::Hit, cached.state);
+        assert_eq!(book_record, cached.data);
+
+        clock.advance(chrono::Duration::hours(1));
+        book_record.data.name = "Piranesi (Paperback)".to_owned();
+        let _ = books
+            .try_update_one(book_record.clone(), None)
+            .await
+            .unwrap();
+
+        let updated = books.try_read_timestamped(&book_record._id).await.unwrap();
+        assert_eq!(book_record, updated.record);
+        assert_eq!(Some(created_at), updated.created_at);
+        assert_eq!(Some(clock.now()), updated.updated_at);
+
+        let modified = books
+            .try_read_modified_since(created_at + chrono::Duration::minutes(1), None)
+            .await
+            .unwrap();
+        assert_eq!(vec![updated.clone()], modified);
+
+        //Picking up after the last record seen skips it, even at the same updated_at
+        let after_last = books
+            .try_read_modified_since(ModifiedCursor::after(&updated).unwrap(), None)
+            .await
+            .unwrap();
+        assert!(after_last.is_empty());
+
+        //An upsert that updates keeps created_at
+        clock.advance(chrono::Duration::hours(1));
+        let _ = books
+            .try_upsert_one(book_record.clone(), None, None)
+            .await
+            .unwrap();
+
+        let upserted = books.try_read_timestamped(&book_record._id).await.unwrap();
+        assert_eq!(Some(created_at), upserted.created_at);
+        assert_eq!(Some(clock.now()), upserted.updated_at);
+
+        books.try_delete(&book_record._id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_25_soft_delete() {
+        let db_name = "fnchart";
+        let clock = Arc::new(MockClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+        ));
+        let data_store = Datastore::try_new(db_name)
+            .await
+            .unwrap()
+            .with_clock(clock.clone());
+
+        let archived_books = data_store.collection::<ArchivedBookRecord>();
+        let book_record = ArchivedBookRecord {
+            _id: "0f4a5b6c7d8e9f0a1b2c3d4e5f6a7b8c".to_owned(),
+            data: MigratedBook {
+                name: "The Left Hand of Darkness".to_owned(),
+                author: "Ursula K. Le Guin".to_owned(),
+            },
+        };
+        data_store
+            .database
+            .try_delete_all(ArchivedBookRecord::COLLECTION)
+            .await
+            .unwrap();
+
+        let _ = archived_books
+            .try_create_one(book_record.clone(), None)
+            .await
+            .unwrap();
+        archived_books.try_delete(&book_record._id).await.unwrap();
+
+        //Hidden from the cache and from Atlas, but still stored
+        assert!(archived_books.try_read(&book_record._id).await.is_err());
+        assert!(archived_books.try_read_all().await.unwrap().is_empty());
+        assert!(archived_books
+            .try_update_one(book_record.clone(), None)
+            .await
+            .is_err());
+
+        let deleted = archived_books
+            .include_deleted()
+            .try_read_timestamped(&book_record._id)
+            .await
+            .unwrap();
+        assert_eq!(book_record, deleted.record);
+        assert_eq!(Some(clock.now()), deleted.deleted_at);
+
+        let _ = data_store
+            .try_read_with::<ArchivedBookRecord>(
+                ArchivedBookRecord::COLLECTION,
+                &book_record._id,
+                ReadOptions::include_deleted(),
+            )
+            .await
+            .unwrap();
+
+        //Restoring caches the record again
+        let restored = archived_books
+            .try_restore(&book_record._id, None)
+            .await
+            .unwrap();
+        assert_eq!(book_record, restored);
+
+        let cached = archived_books.try_read(&book_record._id).await.unwrap();
+        assert_eq!(CacheState::Hit, cached.state);
+        assert!(archived_books
+            .try_restore(&book_record._id, None)
+            .await
+            .is_err());
+
+        //Only records deleted longer ago than the cutoff are purged
+        archived_books.try_delete(&book_record._id).await.unwrap();
+        assert_eq!(
+            0,
+            archived_books
+                .try_purge_deleted(chrono::Duration::days(30))
+                .await
+                .unwrap()
+        );
+
+        clock.advance(chrono::Duration::days(31));
+        assert_eq!(
+            1,
+            archived_books
+                .try_purge_deleted(chrono::Duration::days(30))
+                .await
+                .unwrap()
+        );
+        assert!(archived_books
+            .include_deleted()
+            .try_read(&book_record._id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_26_version_history() {
+        let db_name = "fnchart";
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let clock = Arc::new(MockClock::new(created_at));
+        let data_store = Datastore::try_new(db_name)
+            .await
+            .unwrap()
+            .with_clock(clock.clone());
+        let editor = data_store.clone().with_actor("editor");
+
+        for table in ["versioned_books", "versioned_books_history"] {
+            data_store.database.try_delete_all(table).await.unwrap();
+        }
+
+        let versioned_books = editor.collection::<VersionedBookRecord>();
+        let first = VersionedBookRecord {
+            _id: "1a5b6c7d8e9f0a1b2c3d4e5f6a7b8c9d".to_owned(),
+            data: MigratedBook {
+                name: "The Word for World Is Forest".to_owned(),
+                author: "Ursula Le Guin".to_owned(),
+            },
+        };
+        let _ = versioned_books
+            .try_create_one(first.clone(), None)
+            .await
+            .unwrap();
+
+        clock.advance(chrono::Duration::days(1));
+        let mut second = first.clone();
+        second.data.author = "Ursula K. Le Guin".to_owned();
+        let _ = versioned_books
+            .try_update_one(second.clone(), None)
+            .await
+            .unwrap();
+
+        let versions = editor
+            .try_list_versions::<VersionedBookRecord>(&first._id)
+            .await
+            .unwrap();
+        assert_eq!(1, versions.len());
+        assert_eq!(1, versions[0].version);
+        assert_eq!(first, versions[0].record);
+        assert_eq!(clock.now(), versions[0].recorded_at);
+        assert_eq!(Some("editor".to_owned()), versions[0].actor);
+
+        //Before the update, after it and before the record existed
+        let as_of = |at| editor.try_read_as_of::<VersionedBookRecord>(&first._id, at);
+        assert_eq!(
+            Some(first.clone()),
+            as_of(created_at + chrono::Duration::hours(1))
+                .await
+                .unwrap()
+        );
+        assert_eq!(Some(second.clone()), as_of(clock.now()).await.unwrap());
+        assert_eq!(
+            None,
+            as_of(created_at - chrono::Duration::hours(1))
+                .await
+                .unwrap()
+        );
+
+        //A revert is a write of its own, the replaced version is kept and the cache follows
+        clock.advance(chrono::Duration::days(1));
+        let reverted = editor
+            .try_revert::<VersionedBookRecord>(&first._id, 1, None)
+            .await
+            .unwrap();
+        assert_eq!(first, reverted);
+
+        let cached = versioned_books.try_read(&first._id).await.unwrap();
+        assert_eq!(CacheState::Hit, cached.state);
+        assert_eq!(first, cached.data);
+
+        let versions = editor
+            .try_list_versions::<VersionedBookRecord>(&first._id)
+            .await
+            .unwrap();
+        assert_eq!(2, versions.len());
+        assert_eq!(second, versions[1].record);
+
+        versioned_books.try_delete(&first._id).await.unwrap();
+        assert_eq!(
+            None,
+            as_of(clock.now() + chrono::Duration::hours(1))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_27_audit_log() {
+        let db_name = "fnchart";
+        let audit_config = AuditConfig::mongo("audit_log", Duration::from_secs(60 * 60));
+        let data_store = Datastore::try_new(db_name)
+            .await
+            .unwrap()
+            .with_audit(audit_config);
+        let auditor = data_store.clone().with_actor("auditor");
+
+        for table in ["audited_books", "audit_log"] {
+            data_store.database.try_delete_all(table).await.unwrap();
+        }
+
+        let audited_books = auditor.collection::<AuditedBookRecord>();
+        let book = AuditedBookRecord {
+            _id: "2b6c7d8e9f0a1b2c3d4e5f6a7b8c9d0e".to_owned(),
+            data: MigratedBook {
+                name: "The Dispossessed".to_owned(),
+                author: "Ursula Le Guin".to_owned(),
+            },
+        };
+        let _ = audited_books
+            .try_create_one(book.clone(), None)
+            .await
+            .unwrap();
+
+        let mut renamed = book.clone();
+        renamed.data.name = "The Dispossessed: An Ambiguous Utopia".to_owned();
+        let _ = audited_books
+            .try_update_one(renamed.clone(), None)
+            .await
+            .unwrap();
+        audited_books.try_delete(&book._id).await.unwrap();
+
+        //Writes made without an actor are filtered out
+        let _ = data_store
+            .collection::<AuditedBookRecord>()
+            .try_restore(&book._id, None)
+            .await
+            .unwrap();
+
+        let filter = AuditFilter {
+            record_id: Some(book._id.clone()),
+            actor: Some("auditor".to_owned()),
+            ..AuditFilter::default()
+        };
+        let entries = data_store.try_read_audit(filter).await.unwrap();
+        let operations: Vec<AuditOperation> = entries.iter().map(|entry| entry.operation).collect();
+        assert_eq!(
+            vec![
+                AuditOperation::Delete,
+                AuditOperation::Update,
+                AuditOperation::Create
+            ],
+            operations
+        );
+        assert!(entries
+            .iter()
+            .all(|entry| entry.collection == "audited_books"));
+
+        //Only the renamed field changed, timestamps are left out
+        let update = &entries[1];
+        assert_eq!(1, update.changes.len());
+        assert_eq!("data.name", update.changes[0].path);
+        assert_eq!(
+            bson::Bson::from("The Dispossessed"),
+            update.changes[0].before
+        );
+        assert_eq!(
+            bson::Bson::from("The Dispossessed: An Ambiguous Utopia"),
+            update.changes[0].after
+        );
+
+        let create = &entries[2];
+        assert!(create
+            .changes
+            .iter()
+            .all(|change| change.before == bson::Bson::Null));
+
+        //A soft delete only sets deleted_at
+        let delete = &entries[0];
+        assert_eq!(1, delete.changes.len());
+        assert_eq!("deleted_at", delete.changes[0].path);
+
+        let all_entries = data_store
+            .try_read_audit(AuditFilter {
+                collection: Some("audited_books".to_owned()),
+                ..AuditFilter::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(4, all_entries.len());
+        assert_eq!(AuditOperation::Update, all_entries[0].operation);
+        assert_eq!(None, all_entries[0].actor);
+
+        let limited = data_store
+            .try_read_audit(AuditFilter {
+                limit: Some(2),
+                ..AuditFilter::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, limited.len());
+    }
+
+    #[tokio::test]
+    async fn test_28_tenants() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let acme = data_store.tenant("acme").unwrap();
+        let globex = data_store.tenant("globex").unwrap();
+
+        for tenant in [&acme, &globex] {
+            tenant.try_clear_tenant().await.unwrap();
+        }
+        let acme = data_store.tenant("acme").unwrap();
+        let globex = data_store.tenant("globex").unwrap();
+
+        //The same id in both tenants, each one only ever sees its own
+        let acme_book = VersionedBookRecord {
+            _id: "3c7d8e9f0a1b2c3d4e5f6a7b8c9d0e1f".to_owned(),
+            data: MigratedBook {
+                name: "Solaris".to_owned(),
+                author: "Stanislaw Lem".to_owned(),
+            },
+        };
+        let mut globex_book = acme_book.clone();
+        globex_book.data.name = "The Cyberiad".to_owned();
+
+        let _ = acme
+            .collection::<VersionedBookRecord>()
+            .try_create_one(acme_book.clone(), None)
+            .await
+            .unwrap();
+        let _ = globex
+            .collection::<VersionedBookRecord>()
+            .try_create_one(globex_book.clone(), None)
+            .await
+            .unwrap();
+
+        let acme_read = acme
+            .collection::<VersionedBookRecord>()
+            .try_read(&acme_book._id)
+            .await
+            .unwrap();
+        assert_eq!(CacheState::Hit, acme_read.state);
+        assert_eq!(acme_book, acme_read.data);
+
+        let globex_read = globex
+            .collection::<VersionedBookRecord>()
+            .try_read(&globex_book._id)
+            .await
+            .unwrap();
+        assert_eq!(CacheState::Hit, globex_read.state);
+        assert_eq!(globex_book, globex_read.data);
+
+        assert_eq!(Some("acme"), acme.tenant_id());
+        assert_eq!("fnchart__acme", acme.database.db.name());
+
+        //Scoping is one level deep and ids end up in database names
+        let nested = acme.tenant("globex").err().unwrap();
+        assert!(matches!(
+            nested.downcast_ref::<TenantError>(),
+            Some(TenantError::AlreadyScoped { .. })
+        ));
+        let invalid = data_store.tenant("acme.books").err().unwrap();
+        assert_eq!(
+            Some(&TenantError::InvalidId("acme.books".to_owned())),
+            invalid.downcast_ref::<TenantError>()
+        );
+        let unscoped = data_store.try_export_tenant().await.unwrap_err();
+        assert_eq!(
+            Some(&TenantError::NotScoped("try_export_tenant")),
+            unscoped.downcast_ref::<TenantError>()
+        );
+
+        let export = acme.try_export_tenant().await.unwrap();
+        let exported_books = export.get("versioned_books").unwrap();
+        assert_eq!(1, exported_books.len());
+        assert_eq!(
+            "Solaris",
+            exported_books[0]
+                .get_document("data")
+                .unwrap()
+                .get_str("name")
+                .unwrap()
+        );
+
+        //Clearing one tenant leaves the other alone, in Atlas and in the cache
+        acme.try_clear_tenant().await.unwrap();
+        let acme = data_store.tenant("acme").unwrap();
+        assert!(acme
+            .collection::<VersionedBookRecord>()
+            .try_read(&acme_book._id)
+            .await
+            .is_err());
+
+        let globex_read = globex
+            .collection::<VersionedBookRecord>()
+            .try_read(&globex_book._id)
+            .await
+            .unwrap();
+        assert_eq!(CacheState::Hit, globex_read.state);
+        assert_eq!(globex_book, globex_read.data);
+
+        //Clearing the root cache does not reach into the tenants
+        data_store.try_clear_cache().await.unwrap();
+        let globex_read = globex
+            .collection::<VersionedBookRecord>()
+            .try_read(&globex_book._id)
+            .await
+            .unwrap();
+        assert_eq!(CacheState::Hit, globex_read.state);
+    }
+
+    #[tokio::test]
+    async fn test_29_config() {
+        let toml = r#"
+            mongodb_uri = "mongodb://localhost:27017"
+            redis_uri = "redis://localhost:6379"
+            database = "fnchart"
+            key_prefix = "shop"
+            read_concern = "majority"
+
+            [redis_pool]
+            max_open = 32
+
+            [cache_ttls]
+            books = 3600
+
+            [write_concern]
+            w = "majority"
+            timeout_ms = 500
+        "#;
+        let from_toml = DatastoreConfig::from_toml(toml).unwrap();
+        assert_eq!(Some("fnchart".to_owned()), from_toml.database);
+        assert_eq!(Some(ReadConcernLevel::Majority), from_toml.read_concern);
+        assert_eq!(Some(&3600), from_toml.cache_ttls.get("books"));
+        //Unset pool values keep their defaults
+        assert_eq!(32, from_toml.redis_pool.max_open);
+        assert_eq!(
+            RedisPoolConfig::default().max_idle,
+            from_toml.redis_pool.max_idle
+        );
+
+        let yaml = "
+            mongodb_uri: mongodb://localhost:27017
+            redis_uri: redis://localhost:6379
+            database: fnchart
+            key_prefix: shop
+            read_concern: majority
+            redis_pool:
+              max_open: 32
+            cache_ttls:
+              books: 3600
+            write_concern:
+              w: majority
+              timeout_ms: 500
+        ";
+        assert_eq!(from_toml, DatastoreConfig::from_yaml(yaml).unwrap());
+
+        let built = DatastoreConfig::default()
+            .with_mongodb_uri("mongodb://localhost:27017")
+            .with_redis_uri("redis://localhost:6379")
+            .with_database("fnchart")
+            .with_key_prefix("shop")
+            .with_cache_ttl("books", 3600);
+        assert_eq!(Some(&3600), built.cache_ttls.get("books"));
+
+        //Typos and bad values are rejected instead of ignored
+        assert!(DatastoreConfig::from_toml("databse = \"fnchart\"").is_err());
+        assert!(DatastoreConfig::from_yaml("read_concern: strong").is_err());
+
+        //Missing URIs are errors rather than panics
+        let missing =
+            Datastore::try_with_config(DatastoreConfig::default().with_database("fnchart"))
+                .await
+                .err()
+                .unwrap();
+        assert_eq!(
+            Some(&ConfigError::Missing {
+                key: "mongodb_uri",
+                env_var: "MONGODB_URI",
+            }),
+            missing.downcast_ref::<ConfigError>()
+        );
+
+        let unsupported = DatastoreConfig::from_file("datastore.json").err().unwrap();
+        assert!(unsupported.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_30_error_kinds() {
+        let db_name = "fnchart";
+        let data_store = Datastore::try_new(db_name).await.unwrap();
+        let table = "books30";
+        let bookstore_id = "3e0c5d7a9b1f42c68e2d4a6b8c0e1f30";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        let missing_id = "0000000000000000000000000000ee30";
+        let read_err = data_store
+            .try_read::<BookRecord>(table, missing_id)
+            .await
+            .unwrap_err();
+        assert!(read_err.is_not_found());
+        assert!(matches!(
+            read_err,
+            DatastoreError::NotFound { ref collection, ref id } if collection == table && id == missing_id
+        ));
+
+        let update_err = data_store
+            .try_update_one(
+                table,
+                "books",
+                BookRecord {
+                    _id: missing_id.to_owned(),
+                    data: Book {
+                        name: "The Pearl".to_owned(),
+                        author: "John Steinbeck".to_owned(),
+                        bookstore_id: bookstore_id.to_owned(),
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(update_err.is_not_found());
+
+        let dangling_err = data_store
+            .try_create_one(
+                table,
+                "books",
+                BookRecord {
+                    _id: "1f2e3d4c5b6a79880716253443526130".to_owned(),
+                    data: Book {
+                        name: "Of Mice and Men".to_owned(),
+                        author: "John Steinbeck".to_owned(),
+                        bookstore_id: "ffffffffffffffffffffffffffffff30".to_owned(),
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(dangling_err, DatastoreError::Validation(_)));
+        assert!(dangling_err.is::<IntegrityError>());
+
+        //Same name as the seeded store
+        let duplicate_err = data_store
+            .try_create_one(
+                BookstoreRecord::COLLECTION,
+                "bookstores",
+                BookstoreRecord {
+                    _id: "3e0c5d7a9b1f42c68e2d4a6b8c0e1f31".to_owned(),
+                    data: Bookstore {
+                        name: format!("Book Warehouse {}", bookstore_id),
+                        address: "632 W Broadway, Vancouver, BC V5Z 1G1".to_owned(),
+                        number: "(604) 872-5711".to_owned(),
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(duplicate_err, DatastoreError::Conflict(_)));
+        assert!(duplicate_err.is::<UniqueViolation>());
+
+        let config_err = Datastore::try_with_config(DatastoreConfig::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(config_err, DatastoreError::Configuration(_)));
+
+        data_store
+            .try_delete::<BookstoreRecord>(BookstoreRecord::COLLECTION, bookstore_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_31_cache_circuit_breaker() {
+        //Nothing listens on port 1, every Redis call fails as unavailable
+        let config = DatastoreConfig::from_env()
+            .unwrap()
+            .with_database("fnchart")
+            .with_redis_uri("redis://127.0.0.1:1")
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 1,
+                open_seconds: 60,
+                ..CircuitBreakerConfig::default()
+            });
+        let data_store = Datastore::try_with_config(config).await.unwrap();
+        assert_eq!(data_store.cache_status().state, BreakerState::Closed);
+
+        let table = "books31";
+        let bookstore_id = "7b3c9e1d5f2a4b6c8d0e2f4a6b8c0d31";
+        seed_bookstore(&data_store, bookstore_id).await;
+
+        //Atlas takes the write, the cache entries it skipped are remembered
+        let book_record = BookRecord {
+            _id: "5d1e9f3a7b2c4d6e8f0a1b3c5d7e9f31".to_owned(),
+            data: Book {
+                name: "Cannery Row".to_owned(),
+                author: "John Steinbeck".to_owned(),
+                bookstore_id: bookstore_id.to_owned(),
+            },
+        };
+        let created = data_store
+            .try_create_one(table, "books", book_record.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(created, book_record);
+
+        let status = data_store.cache_status();
+        assert_eq!(status.state, BreakerState::Open);
+        assert!(status.pending_invalidations >= 1);
+        assert!(status.last_error.is_some());
+
+        //Reads skip Redis without waiting on the pool
+        let read = data_store
+            .try_read::<BookRecord>(table, &book_record._id)
+            .await
+            .unwrap();
+        assert!(matches!(read.state, CacheState::Miss));
+        assert_eq!(read.data, book_record);
+
+        data_store
+            .try_delete::<BookRecord>(table, &book_record._id)
+            .await
+            .unwrap();
+        data_store
+            .try_delete::<BookstoreRecord>(BookstoreRecord::COLLECTION, bookstore_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_32_retry_policy() {
+        let from_toml = DatastoreConfig::from_toml(
+            r#"
+            [mongo_retry]
+            max_attempts = 5
+            jitter = false
+
+            [redis_retry]
+            max_attempts = 1
+        "#,
+        )
+        .unwrap();
+        assert_eq!(5, from_toml.mongo_retry.max_attempts);
+        assert_eq!(
+            RetryPolicy::default().initial_backoff_ms,
+            from_toml.mongo_retry.initial_backoff_ms
+        );
+        assert_eq!(1, from_toml.redis_retry.max_attempts);
+
+        //Doubles from the first wait up to the cap
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            jitter: false,
+        };
+        let waits: Vec<u128> = (1..=6)
+            .map(|attempt| policy.backoff(attempt).as_millis())
+            .collect();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000], waits);
+        assert_eq!(Duration::from_millis(1000), policy.backoff(u32::MAX));
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        assert!((1..=6).all(|attempt| jittered.backoff(attempt) <= policy.backoff(attempt)));
+
+        //Nothing listens on port 1. A refused connection never reached Redis, so even
+        //an increment is tried again, every attempt counts against the breaker
+        let config = DatastoreConfig::default()
+            .with_redis_uri("redis://127.0.0.1:1")
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 10,
+                ..CircuitBreakerConfig::default()
+            })
+            .with_redis_retry(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 1,
+                jitter: false,
+            });
+        let cache = RedisCache::try_with_config(&config).await.unwrap();
+
+        let read_err = cache.try_get("retry32").await.unwrap_err();
+        assert!(matches!(read_err, DatastoreError::CacheUnavailable(_)));
+        assert_eq!(3, cache.breaker_status().consecutive_failures);
+
+        let increment_err = cache.try_increment("retry32", 1).await.unwrap_err();
+        assert!(matches!(increment_err, DatastoreError::CacheUnavailable(_)));
+        assert_eq!(6, cache.breaker_status().consecutive_failures);
+
+        let single = RedisCache::try_with_config(&config.with_redis_retry(RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }))
+        .await
+        .unwrap();
+        let _ = single.try_get("retry32").await.unwrap_err();
+        assert_eq!(1, single.breaker_status().consecutive_failures);
+    }
+
+    #[tokio::test]
+    async fn test_33_health() {
+        let data_store = Datastore::try_new("fnchart").await.unwrap();
+
+        let report = data_store.health().await;
+        assert!(report.is_ready());
+        assert_ne!(HealthStatus::Unhealthy, report.mongo.status);
+        assert!(report.mongo.latency.is_some());
+        assert!(report.mongo.role.is_some());
+
+        assert_ne!(HealthStatus::Unhealthy, report.redis.status);
+        assert!(report.redis.latency.is_some());
+        assert_eq!(Some(ReplicationRole::Primary), report.redis.role);
+        assert!(report.redis.pool.is_some());
+        assert_eq!(BreakerState::Closed, report.cache_breaker.state);
+
+        //Healthy unless something was reported
+        for dependency in [&report.mongo, &report.redis] {
+            assert_eq!(
+                dependency.reasons.is_empty(),
+                dependency.status == HealthStatus::Healthy
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_34_health_unreachable() {
+        //Nothing listens on port 1 for either of them
+        let config = DatastoreConfig::default()
+            .with_mongodb_uri("mongodb://127.0.0.1:1")
+            .with_redis_uri("redis://127.0.0.1:1")
+            .with_database("fnchart")
+            .with_health(HealthConfig {
+                timeout_ms: 300,
+                ..HealthConfig::default()
+            });
+        let data_store = Datastore::try_with_config(config).await.unwrap();
+
+        let report = data_store.health().await;
+        assert_eq!(HealthStatus::Unhealthy, report.status);
+        assert!(!report.is_ready());
+
+        assert_eq!(HealthStatus::Unhealthy, report.mongo.status);
+        assert_eq!(None, report.mongo.latency);
+        assert!(!report.mongo.reasons.is_empty());
+
+        //Pool usage is reported even without Redis
+        assert_eq!(HealthStatus::Unhealthy, report.redis.status);
+        assert_eq!(None, report.redis.latency);
+        assert!(report.redis.pool.is_some());
+
+        //Probes bypass the breaker and leave it alone
+        assert_eq!(0, report.cache_breaker.consecutive_failures);
+    }
+
+    #[test]
+    fn test_36_breaker_counts_checkout_failures() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_seconds: 0,
+            ..CircuitBreakerConfig::default()
+        });
+        let timeout = DatastoreError::from(mobc::Error::<RedisError>::Timeout);
+
+        //Timeouts only open a closed breaker once enough came in a row
+        breaker.record_checkout_failure(&timeout);
+        assert_eq!(BreakerState::Closed, breaker.status().state);
+        breaker.record_checkout_failure(&timeout);
+        assert_eq!(BreakerState::Open, breaker.status().state);
+
+        //A probe whose checkout times out opens the breaker again
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+        breaker.record_checkout_failure(&timeout);
+        assert_eq!(BreakerState::Open, breaker.status().state);
 
-        let _ = data_store.clear_datastore("books").await.unwrap();
+        //Any other error hands the probe to the next caller
+        assert!(breaker.try_acquire());
+        breaker.record_checkout_failure(&DatastoreError::validation("checkout refused"));
+        assert_eq!(BreakerState::HalfOpen, breaker.status().state);
+        assert!(breaker.try_acquire());
     }
 }
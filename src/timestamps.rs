@@ -0,0 +1,265 @@
+use std::sync::{Arc, Mutex};
+
+use bson::{doc, from_document, Bson, Document};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::book_types::MongoStorable;
+use crate::error::Result;
+use crate::migrations::try_upgrade;
+use crate::soft_delete::{visible, ReadOptions, DELETED_AT_FIELD};
+use crate::Datastore;
+
+//Managed by the Datastore next to the record's own fields, in Atlas and in the cached copy.
+//Record types don't declare them, they are read through Timestamped
+pub const CREATED_AT_FIELD: &str = "created_at";
+pub const UPDATED_AT_FIELD: &str = "updated_at";
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+//Stands still until it is moved, for tests that assert on timestamps
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        if let Ok(mut current) = self.now.lock() {
+            *current = now;
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        if let Ok(mut current) = self.now.lock() {
+            *current += by;
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.now
+            .lock()
+            .map(|now| *now)
+            .unwrap_or_else(|poisoned| *poisoned.into_inner())
+    }
+}
+
+//A record with the timestamps stored alongside it. Records written before timestamps
+//were managed have neither, deleted_at is only set on soft-deleted records
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timestamped<T> {
+    pub record: T,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Where a sync job picks up again, the updated_at and _id of the last record it saw.
+//Records sharing that updated_at are told apart by _id, so a page boundary between
+//them neither skips nor repeats any. Without an id everything at updated_at is included
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModifiedCursor {
+    pub updated_at: DateTime<Utc>,
+    pub id: Option<String>,
+}
+
+impl ModifiedCursor {
+    //None for a record written before timestamps were managed
+    pub fn after<T>(timestamped: &Timestamped<T>) -> Option<Self>
+    where
+        T: MongoStorable,
+    {
+        Some(Self {
+            updated_at: timestamped.updated_at?,
+            id: Some(timestamped.record.get_id().to_owned()),
+        })
+    }
+
+    fn filter(&self) -> Document {
+        let updated_at = bson::DateTime::from_chrono(self.updated_at);
+
+        match &self.id {
+            Some(id) => doc! {
+                "$or": [
+                    { UPDATED_AT_FIELD: { "$gt": updated_at } },
+                    { UPDATED_AT_FIELD: updated_at, "_id": { "$gt": id } },
+                ]
+            },
+            None => doc! { UPDATED_AT_FIELD: { "$gte": updated_at } },
+        }
+    }
+}
+
+impl From<DateTime<Utc>> for ModifiedCursor {
+    fn from(updated_at: DateTime<Utc>) -> Self {
+        Self {
+            updated_at,
+            id: None,
+        }
+    }
+}
+
+pub(crate) fn stamp_created(document: &mut Document, now: bson::DateTime) {
+    document.insert(CREATED_AT_FIELD, now);
+    document.insert(UPDATED_AT_FIELD, now);
+}
+
+pub(crate) fn stamp_updated(document: &mut Document, now: bson::DateTime) {
+    document.insert(UPDATED_AT_FIELD, now);
+}
+
+//Cached JSON of the record with the timestamps the write knows about, as relaxed extended
+//JSON. An update does not know when its record was created, its copy only gets updated_at
+pub(crate) fn cache_value<T>(
+    record: &T,
+    created_at: Option<bson::DateTime>,
+    updated_at: bson::DateTime,
+) -> Result<String>
+where
+    T: Serialize,
+{
+    let mut value = serde_json::to_value(record)?;
+
+    if let Value::Object(fields) = &mut value {
+        if let Some(created_at) = created_at {
+            fields.insert(
+                CREATED_AT_FIELD.to_owned(),
+                Bson::DateTime(created_at).into_relaxed_extjson(),
+            );
+        }
+        fields.insert(
+            UPDATED_AT_FIELD.to_owned(),
+            Bson::DateTime(updated_at).into_relaxed_extjson(),
+        );
+    }
+
+    Ok(serde_json::to_string(&value)?)
+}
+
+pub(crate) fn timestamped<T>(document: Document) -> Result<Timestamped<T>>
+where
+    T: DeserializeOwned,
+{
+    let created_at = document
+        .get_datetime(CREATED_AT_FIELD)
+        .ok()
+        .map(|at| at.to_chrono());
+    let updated_at = document
+        .get_datetime(UPDATED_AT_FIELD)
+        .ok()
+        .map(|at| at.to_chrono());
+    let deleted_at = document
+        .get_datetime(DELETED_AT_FIELD)
+        .ok()
+        .map(|at| at.to_chrono());
+
+    Ok(Timestamped {
+        record: from_document::<T>(document)?,
+        created_at,
+        updated_at,
+        deleted_at,
+    })
+}
+
+impl Datastore {
+    //Swaps the clock every write is stamped with, e.g. for a MockClock in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    //Millisecond precision, the same as Mongo stores
+    pub(crate) fn now(&self) -> bson::DateTime {
+        bson::DateTime::from_chrono(self.clock.now())
+    }
+
+    pub async fn try_read_timestamped<T>(&self, record_id: &str) -> Result<Timestamped<T>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        self.try_read_timestamped_with(record_id, ReadOptions::default())
+            .await
+    }
+
+    pub async fn try_read_timestamped_with<T>(
+        &self,
+        record_id: &str,
+        options: ReadOptions,
+    ) -> Result<Timestamped<T>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        let mut document = self
+            .database
+            .try_find_one(T::COLLECTION, visible(doc! { "_id": record_id }, options))
+            .await?;
+        let _ = try_upgrade::<T>(&mut document)?;
+
+        timestamped(document)
+    }
+
+    //Records changed since the cursor, oldest change first. A sync job passes back
+    //ModifiedCursor::after the last record it saw, limit pages through large backlogs
+    pub async fn try_read_modified_since<T>(
+        &self,
+        since: impl Into<ModifiedCursor>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Timestamped<T>>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        self.try_read_modified_since_with(since, limit, ReadOptions::default())
+            .await
+    }
+
+    //A soft delete counts as a change, sync jobs include deleted records to see them
+    pub async fn try_read_modified_since_with<T>(
+        &self,
+        since: impl Into<ModifiedCursor>,
+        limit: Option<i64>,
+        options: ReadOptions,
+    ) -> Result<Vec<Timestamped<T>>>
+    where
+        T: MongoStorable + DeserializeOwned,
+    {
+        let filter = since.into().filter();
+
+        let documents = self
+            .database
+            .try_find_sorted(
+                T::COLLECTION,
+                visible(filter, options),
+                doc! { UPDATED_AT_FIELD: 1, "_id": 1 },
+                limit,
+            )
+            .await?;
+
+        documents
+            .into_iter()
+            .map(|mut document| {
+                let _ = try_upgrade::<T>(&mut document)?;
+                timestamped(document)
+            })
+            .collect()
+    }
+}
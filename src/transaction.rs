@@ -0,0 +1,663 @@
+use std::sync::Arc;
+
+use bson::{doc, from_document, Bson, Document};
+use mongodb::{
+    error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    ClientSession,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::audit::{merged, prior_of, AuditDraft, AuditOperation};
+use crate::book_types::MongoStorable;
+use crate::cache::redis::{check_hash_key, record_cache_ops, CacheOp, RedisCache};
+use crate::error::{DatastoreError, Result};
+use crate::history;
+use crate::ids;
+use crate::integrity;
+use crate::migrations;
+use crate::mongodb::atlas::Atlas;
+use crate::relations::relation_clear_ops;
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::timestamps::{cache_value, stamp_created, stamp_updated, Clock, UPDATED_AT_FIELD};
+use crate::unique::{self, unique_clear_ops};
+use crate::{WriteOp, WriteOutcome};
+
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 5;
+
+//Handle passed into Datastore::transaction. Every Mongo call runs inside the session's
+//transaction, cache writes and audit entries are only queued and get applied once the
+//commit went through
+pub struct Transaction {
+    atlas: Atlas,
+    //Only used for sequence ids, cache writes are staged in cache_ops
+    cache: RedisCache,
+    session: ClientSession,
+    clock: Arc<dyn Clock>,
+    actor: Option<String>,
+    audited: bool,
+    cache_ops: Vec<CacheOp>,
+    audit_drafts: Vec<AuditDraft>,
+}
+
+impl Transaction {
+    pub(crate) fn new(
+        atlas: Atlas,
+        cache: RedisCache,
+        session: ClientSession,
+        clock: Arc<dyn Clock>,
+        actor: Option<String>,
+        audited: bool,
+    ) -> Self {
+        Self {
+            atlas,
+            cache,
+            session,
+            clock,
+            actor,
+            audited,
+            cache_ops: Vec::new(),
+            audit_drafts: Vec::new(),
+        }
+    }
+
+    fn now(&self) -> bson::DateTime {
+        bson::DateTime::from_chrono(self.clock.now())
+    }
+
+    //Same as for plain writes, the replaced version is kept in the history. It is written
+    //in the session as well, an abort drops it with the write
+    async fn try_read_prior<T>(
+        &mut self,
+        table: &str,
+        record_ids: &[String],
+    ) -> Result<Vec<Document>>
+    where
+        T: MongoStorable,
+    {
+        if !T::HISTORY && !self.audited {
+            return Ok(Vec::new());
+        }
+
+        history::try_read_prior(&self.atlas, Some(&mut self.session), table, record_ids).await
+    }
+
+    async fn try_write_history<T>(
+        &mut self,
+        prior: Vec<Document>,
+        now: bson::DateTime,
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        if prior.is_empty() {
+            return Ok(());
+        }
+
+        history::try_write_history::<T>(
+            &self.atlas,
+            Some(&mut self.session),
+            prior,
+            now,
+            self.actor.as_deref(),
+        )
+        .await
+    }
+
+    pub(crate) async fn try_begin(&mut self) -> Result<()> {
+        self.cache_ops.clear();
+        self.audit_drafts.clear();
+        self.session.start_transaction(None).await?;
+        Ok(())
+    }
+
+    //Commit can be retried on its own when the server could not tell whether it applied
+    pub(crate) async fn try_commit(&mut self) -> Result<(Vec<CacheOp>, Vec<AuditDraft>)> {
+        let mut attempt = 1;
+
+        loop {
+            match self.session.commit_transaction().await {
+                Ok(()) => {
+                    return Ok((
+                        std::mem::take(&mut self.cache_ops),
+                        std::mem::take(&mut self.audit_drafts),
+                    ))
+                }
+                Err(err)
+                    if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                        && attempt < MAX_TRANSACTION_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    //The server may already have aborted on its own, nothing left to undo in that case
+    pub(crate) async fn abort(&mut self) {
+        self.cache_ops.clear();
+        self.audit_drafts.clear();
+        let _ = self.session.abort_transaction().await;
+    }
+
+    //Outdated documents are upgraded in memory only, the transaction may still abort.
+    //Soft-deleted records are not found
+    pub async fn try_read<T>(&mut self, table: &str, record_id: &str) -> Result<T>
+    where
+        T: DeserializeOwned + MongoStorable,
+    {
+        let collection = self.atlas.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": record_id,
+            DELETED_AT_FIELD: Bson::Null,
+        };
+
+        let mut document = collection
+            .find_one_with_session(query, None, &mut self.session)
+            .await?
+            .ok_or_else(|| DatastoreError::not_found(table, record_id))?;
+
+        let _ = migrations::try_upgrade::<T>(&mut document)?;
+
+        Ok(from_document::<T>(document)?)
+    }
+
+    pub async fn try_create_one<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        mut record: T,
+        cache_expiry: Option<usize>,
+    ) -> Result<T>
+    where
+        T: Serialize + MongoStorable,
+    {
+        check_hash_key::<T>(hash_key)?;
+        //A sequence id taken here is not given back when the transaction aborts
+        ids::try_assign_ids(
+            &self.atlas,
+            &self.cache,
+            table,
+            std::slice::from_mut(&mut record),
+        )
+        .await?;
+
+        integrity::try_check_references(&self.atlas, Some(&mut self.session), &record).await?;
+        unique::try_check_unique_in_atlas(&self.atlas, Some(&mut self.session), table, &record)
+            .await?;
+
+        let now = self.now();
+        let collection = self.atlas.db.collection::<Document>(table);
+        let mut document = migrations::to_stored_document(&record)?;
+        stamp_created(&mut document, now);
+        let audit_draft = AuditDraft::new(
+            AuditOperation::Create,
+            table,
+            record.get_id(),
+            None,
+            Some(document.clone()),
+        );
+
+        collection
+            .insert_one_with_session(document, None, &mut self.session)
+            .await
+            .map_err(|err| unique::map_unique_violation(err.into(), &record))?;
+
+        self.audit_drafts.push(audit_draft);
+
+        self.stage_cache_set(table, hash_key, &record, Some(now), now, cache_expiry)?;
+        Ok(record)
+    }
+
+    pub async fn try_update_one<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        update_record: T,
+        cache_expiry: Option<usize>,
+    ) -> Result<T>
+    where
+        T: Serialize + MongoStorable,
+    {
+        check_hash_key::<T>(hash_key)?;
+        integrity::try_check_references(&self.atlas, Some(&mut self.session), &update_record)
+            .await?;
+        unique::try_check_unique_in_atlas(
+            &self.atlas,
+            Some(&mut self.session),
+            table,
+            &update_record,
+        )
+        .await?;
+
+        let now = self.now();
+        let collection = self.atlas.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": update_record.get_id(),
+            DELETED_AT_FIELD: Bson::Null,
+        };
+
+        let mut update_document = migrations::to_stored_document(&update_record)?;
+        stamp_updated(&mut update_document, now);
+
+        let prior = self
+            .try_read_prior::<T>(table, &[update_record.get_id().to_owned()])
+            .await?;
+        let before = prior_of(&prior, update_record.get_id());
+        let after = merged(before.as_ref(), &update_document);
+        let audit_draft = AuditDraft::new(
+            AuditOperation::Update,
+            table,
+            update_record.get_id(),
+            before,
+            Some(after),
+        );
+
+        let update = doc! {
+            "$set": update_document
+        };
+
+        let update_result = collection
+            .update_one_with_session(query, update, None, &mut self.session)
+            .await
+            .map_err(|err| unique::map_unique_violation(err.into(), &update_record))?;
+
+        if update_result.matched_count == 0 {
+            return Err(DatastoreError::not_found(table, update_record.get_id()));
+        }
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.audit_drafts.push(audit_draft);
+        self.stage_cache_set(table, hash_key, &update_record, None, now, cache_expiry)?;
+        Ok(update_record)
+    }
+
+    pub async fn try_delete<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        record_id: &str,
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        check_hash_key::<T>(hash_key)?;
+        if T::SOFT_DELETE {
+            return self.try_soft_delete::<T>(table, hash_key, record_id).await;
+        }
+
+        //Cascades run in the same session, an abort rolls them back with the delete
+        let now = self.now();
+        let cascade_ops = integrity::try_apply_delete_rules::<T>(
+            &self.atlas,
+            Some(&mut self.session),
+            &[record_id.to_owned()],
+            now,
+        )
+        .await?;
+
+        let collection = self.atlas.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": record_id
+        };
+        let prior = self
+            .try_read_prior::<T>(table, &[record_id.to_owned()])
+            .await?;
+        let audit_draft = AuditDraft::new(
+            AuditOperation::Delete,
+            table,
+            record_id,
+            prior_of(&prior, record_id),
+            None,
+        );
+
+        let delete_result = collection
+            .delete_one_with_session(query, None, &mut self.session)
+            .await?;
+
+        if delete_result.deleted_count == 0 {
+            return Err(DatastoreError::not_found(table, record_id));
+        }
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.audit_drafts.push(audit_draft);
+        self.cache_ops.extend(cascade_ops);
+        self.cache_ops.push(CacheOp::Delete {
+            hash_key: hash_key.to_owned(),
+            field: record_id.to_owned(),
+        });
+        self.cache_ops.extend(relation_clear_ops::<T>(record_id));
+        self.cache_ops
+            .extend(unique_clear_ops::<T>(table, record_id));
+        Ok(())
+    }
+
+    //Hard deletes whichever of the records exist, backs Datastore::try_delete_many
+    pub(crate) async fn try_delete_many<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        record_ids: &[String],
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        let now = self.now();
+        let cascade_ops = integrity::try_apply_delete_rules::<T>(
+            &self.atlas,
+            Some(&mut self.session),
+            record_ids,
+            now,
+        )
+        .await?;
+
+        let prior = self.try_read_prior::<T>(table, record_ids).await?;
+        let audit_drafts: Vec<AuditDraft> = record_ids
+            .iter()
+            .map(|record_id| {
+                AuditDraft::new(
+                    AuditOperation::Delete,
+                    table,
+                    record_id,
+                    prior_of(&prior, record_id),
+                    None,
+                )
+            })
+            .collect();
+
+        self.atlas
+            .try_delete_matching(
+                table,
+                doc! { "_id": { "$in": record_ids } },
+                Some(&mut self.session),
+            )
+            .await?;
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.audit_drafts.extend(audit_drafts);
+        self.cache_ops.extend(cascade_ops);
+        for record_id in record_ids {
+            self.cache_ops.push(CacheOp::Delete {
+                hash_key: hash_key.to_owned(),
+                field: record_id.to_owned(),
+            });
+            self.cache_ops.extend(relation_clear_ops::<T>(record_id));
+            self.cache_ops
+                .extend(unique_clear_ops::<T>(table, record_id));
+        }
+        Ok(())
+    }
+
+    //Backs Datastore::bulk_write for batches whose deletes trigger on-delete rules. The
+    //prior versions, the batch and the rules of the deletes that went through share the
+    //session, a restrict that no longer holds aborts the whole batch. Hands back the prior
+    //versions and the outcome of every operation sent, the cascade ops are staged
+    pub(crate) async fn try_bulk_write<T>(
+        &mut self,
+        table: &str,
+        document_ops: Vec<WriteOp<Document>>,
+        replaced_ids: &[String],
+        ordered: bool,
+        now: bson::DateTime,
+    ) -> Result<(Vec<Document>, Vec<WriteOutcome>)>
+    where
+        T: MongoStorable,
+    {
+        let prior = self.try_read_prior::<T>(table, replaced_ids).await?;
+
+        let delete_ids: Vec<Option<String>> = document_ops
+            .iter()
+            .map(|operation| match operation {
+                WriteOp::Delete(record_id) => Some(record_id.to_owned()),
+                _ => None,
+            })
+            .collect();
+        let outcomes = self
+            .atlas
+            .try_bulk_write(table, document_ops, ordered, Some(&mut self.session))
+            .await?;
+
+        let deleted_ids: Vec<String> = delete_ids
+            .into_iter()
+            .zip(outcomes.iter())
+            .filter(|(_, outcome)| outcome.is_applied())
+            .filter_map(|(record_id, _)| record_id)
+            .collect();
+
+        if !deleted_ids.is_empty() {
+            let cascade_ops = integrity::try_apply_delete_rules::<T>(
+                &self.atlas,
+                Some(&mut self.session),
+                &deleted_ids,
+                now,
+            )
+            .await?;
+            self.cache_ops.extend(cascade_ops);
+        }
+
+        Ok((prior, outcomes))
+    }
+
+    //Backs Datastore::try_purge_deleted. The ids, the cascades they trigger and the delete
+    //are read and written in the session, a record restored meanwhile conflicts and the
+    //transaction is retried without it
+    pub(crate) async fn try_purge_deleted<T>(
+        &mut self,
+        table: &str,
+        cutoff: bson::DateTime,
+    ) -> Result<u64>
+    where
+        T: MongoStorable,
+    {
+        let now = self.now();
+        let filter = doc! { DELETED_AT_FIELD: { "$lte": cutoff } };
+
+        let purge_ids: Vec<String> = self
+            .atlas
+            .try_distinct(table, "_id", filter.clone(), Some(&mut self.session))
+            .await?
+            .into_iter()
+            .filter_map(|id| id.as_str().map(str::to_owned))
+            .collect();
+
+        if purge_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let cascade_ops = integrity::try_apply_delete_rules::<T>(
+            &self.atlas,
+            Some(&mut self.session),
+            &purge_ids,
+            now,
+        )
+        .await?;
+
+        //The history kept these at the soft delete already, only the audit log wants them
+        let prior = match self.audited {
+            true => {
+                history::try_read_prior(&self.atlas, Some(&mut self.session), table, &purge_ids)
+                    .await?
+            }
+            false => Vec::new(),
+        };
+        let audit_drafts: Vec<AuditDraft> = prior
+            .into_iter()
+            .filter_map(|document| {
+                let record_id = document.get_str("_id").ok()?.to_owned();
+                Some(AuditDraft::new(
+                    AuditOperation::Delete,
+                    table,
+                    &record_id,
+                    Some(document),
+                    None,
+                ))
+            })
+            .collect();
+
+        let mut purge_filter = filter;
+        purge_filter.insert("_id", doc! { "$in": &purge_ids });
+        let purged = self
+            .atlas
+            .try_delete_matching(table, purge_filter, Some(&mut self.session))
+            .await?;
+
+        self.audit_drafts.extend(audit_drafts);
+        self.cache_ops.extend(cascade_ops);
+        Ok(purged)
+    }
+
+    //Same as Datastore::try_delete for soft-delete types, the cascades wait for the purge
+    async fn try_soft_delete<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        record_id: &str,
+    ) -> Result<()>
+    where
+        T: MongoStorable,
+    {
+        integrity::try_check_restrict::<T>(
+            &self.atlas,
+            Some(&mut self.session),
+            &[record_id.to_owned()],
+        )
+        .await?;
+
+        let now = self.now();
+        let collection = self.atlas.db.collection::<Document>(table);
+
+        let query = doc! {
+            "_id": record_id,
+            DELETED_AT_FIELD: Bson::Null,
+        };
+
+        let deleted_fields = doc! { DELETED_AT_FIELD: now, UPDATED_AT_FIELD: now };
+        let prior = self
+            .try_read_prior::<T>(table, &[record_id.to_owned()])
+            .await?;
+        let before = prior_of(&prior, record_id);
+        let after = merged(before.as_ref(), &deleted_fields);
+        let audit_draft = AuditDraft::new(
+            AuditOperation::Delete,
+            table,
+            record_id,
+            before,
+            Some(after),
+        );
+
+        let update = doc! {
+            "$set": deleted_fields
+        };
+
+        let update_result = collection
+            .update_one_with_session(query, update, None, &mut self.session)
+            .await?;
+
+        if update_result.matched_count == 0 {
+            return Err(DatastoreError::not_found(table, record_id));
+        }
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.audit_drafts.push(audit_draft);
+        self.cache_ops.push(CacheOp::Delete {
+            hash_key: hash_key.to_owned(),
+            field: record_id.to_owned(),
+        });
+        self.cache_ops.extend(relation_clear_ops::<T>(record_id));
+        self.cache_ops
+            .extend(unique_clear_ops::<T>(table, record_id));
+        Ok(())
+    }
+
+    //Soft deletes whichever of the records are live, backs Datastore::try_delete_many for
+    //soft-delete types. Returns how many were marked
+    pub(crate) async fn try_soft_delete_many<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        record_ids: &[String],
+    ) -> Result<u64>
+    where
+        T: MongoStorable,
+    {
+        integrity::try_check_restrict::<T>(&self.atlas, Some(&mut self.session), record_ids)
+            .await?;
+
+        let now = self.now();
+        let deleted_fields = doc! { DELETED_AT_FIELD: now, UPDATED_AT_FIELD: now };
+        let prior = self.try_read_prior::<T>(table, record_ids).await?;
+        let deleted = self
+            .atlas
+            .try_update_matching(
+                table,
+                doc! { "_id": { "$in": record_ids }, DELETED_AT_FIELD: Bson::Null },
+                doc! { "$set": &deleted_fields },
+                Some(&mut self.session),
+            )
+            .await?;
+
+        //Records that were deleted already kept their version on the first delete
+        let prior: Vec<Document> = prior
+            .into_iter()
+            .filter(|document| !matches!(document.get(DELETED_AT_FIELD), Some(Bson::DateTime(_))))
+            .collect();
+        let audit_drafts: Vec<AuditDraft> = prior
+            .iter()
+            .filter_map(|document| {
+                let record_id = document.get_str("_id").ok()?;
+                Some(AuditDraft::new(
+                    AuditOperation::Delete,
+                    table,
+                    record_id,
+                    Some(document.clone()),
+                    Some(merged(Some(document), &deleted_fields)),
+                ))
+            })
+            .collect();
+
+        self.try_write_history::<T>(prior, now).await?;
+
+        self.audit_drafts.extend(audit_drafts);
+        for record_id in record_ids {
+            self.cache_ops.push(CacheOp::Delete {
+                hash_key: hash_key.to_owned(),
+                field: record_id.to_owned(),
+            });
+            self.cache_ops.extend(relation_clear_ops::<T>(record_id));
+            self.cache_ops
+                .extend(unique_clear_ops::<T>(table, record_id));
+        }
+        Ok(deleted)
+    }
+
+    fn stage_cache_set<T>(
+        &mut self,
+        table: &str,
+        hash_key: &str,
+        record: &T,
+        created_at: Option<bson::DateTime>,
+        updated_at: bson::DateTime,
+        expiry: Option<usize>,
+    ) -> Result<()>
+    where
+        T: Serialize + MongoStorable,
+    {
+        let value = cache_value(record, created_at, updated_at)?;
+        self.cache_ops
+            .extend(record_cache_ops(table, hash_key, record, value, expiry)?);
+        Ok(())
+    }
+}
+
+pub(crate) fn is_transient(err: &DatastoreError) -> bool {
+    err.downcast_ref::<MongoError>()
+        .map(|mongo_err| mongo_err.contains_label(TRANSIENT_TRANSACTION_ERROR))
+        .unwrap_or(false)
+}
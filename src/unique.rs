@@ -0,0 +1,261 @@
+use bson::{doc, to_document, Bson, Document};
+use mongodb::ClientSession;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::book_types::MongoStorable;
+use crate::cache::redis::CacheOp;
+use crate::error::{DatastoreError, Result};
+use crate::indexes::{IndexOrder, IndexSpec};
+use crate::mongodb::atlas::{duplicate_key_error, Atlas};
+use crate::relations::field_values;
+use crate::soft_delete::DELETED_AT_FIELD;
+use crate::Datastore;
+
+//A field, or a set of fields taken together, no two records of a collection may share
+#[derive(Clone, Debug, PartialEq)]
+pub struct UniqueKey {
+    pub name: &'static str,
+    pub fields: Vec<&'static str>,
+}
+
+impl UniqueKey {
+    pub fn single(field: &'static str) -> Self {
+        Self {
+            name: field,
+            fields: vec![field],
+        }
+    }
+
+    pub fn compound(name: &'static str, fields: Vec<&'static str>) -> Self {
+        Self { name, fields }
+    }
+
+    pub fn index_name(&self) -> String {
+        format!("unique_{}", self.name)
+    }
+
+    pub fn index_spec(&self) -> IndexSpec {
+        IndexSpec::compound(
+            self.fields
+                .iter()
+                .map(|field| (*field, IndexOrder::Ascending))
+                .collect(),
+        )
+        .named(&self.index_name())
+        .unique()
+    }
+
+    //value -> record id, the hash the pre-checks read
+    pub fn cache_key(&self, collection: &str) -> String {
+        format!("unique:{}:{}", collection, self.name)
+    }
+
+    //record id -> value, so an update can release the value it held before
+    pub fn owner_cache_key(&self, collection: &str) -> String {
+        format!("unique:{}:{}:ids", collection, self.name)
+    }
+
+    //None when the record leaves any of the fields out, there is nothing to collide on
+    fn value_of(&self, document: &Document) -> Option<(Document, String)> {
+        let mut filter = Document::new();
+        let mut parts = Vec::new();
+
+        for field in self.fields.iter() {
+            let value = field_values(document, field).into_iter().next()?;
+            parts.push(unique_value(&value));
+            filter.insert(*field, value);
+        }
+
+        Some((filter, parts.join(",")))
+    }
+
+    fn field(&self) -> String {
+        self.fields.join(",")
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("{field} must be unique, {value} is already taken")]
+pub struct UniqueViolation {
+    pub field: String,
+    pub value: String,
+}
+
+//Mongo is the authority, this asks it directly. Used inside transactions and to confirm
+//a conflict reported by the Redis index, which may still hold a value released earlier
+pub(crate) async fn try_check_unique_in_atlas<T>(
+    atlas: &Atlas,
+    mut session: Option<&mut ClientSession>,
+    table: &str,
+    record: &T,
+) -> Result<()>
+where
+    T: MongoStorable + Serialize,
+{
+    let document = to_document(record)?;
+
+    for unique_key in T::unique_keys() {
+        if let Some((mut filter, value)) = unique_key.value_of(&document) {
+            filter.insert("_id", doc! { "$ne": record.get_id() });
+            //Same scope as the partial index, a soft deleted record holds no values
+            if T::SOFT_DELETE {
+                filter.insert(DELETED_AT_FIELD, Bson::Null);
+            }
+
+            let count = atlas
+                .try_count_matching(table, filter, session.as_deref_mut())
+                .await?;
+
+            if count > 0 {
+                return Err(UniqueViolation {
+                    field: unique_key.field(),
+                    value,
+                }
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Turns a duplicate key error on one of the declared unique indexes into a UniqueViolation,
+//anything else (including a duplicate _id) is passed through untouched
+pub(crate) fn map_unique_violation<T>(err: DatastoreError, record: &T) -> DatastoreError
+where
+    T: MongoStorable + Serialize,
+{
+    match duplicate_key_error(&err) {
+        Some((_, message)) => unique_violation(&message, record, false)
+            .map(DatastoreError::from)
+            .unwrap_or(err),
+        None => err,
+    }
+}
+
+//Same for a batch. The violation is reported for the record at the failed position, or for
+//the one whose values the error message names when Mongo gives no position
+pub(crate) fn map_unique_violations<T>(err: DatastoreError, records: &[T]) -> DatastoreError
+where
+    T: MongoStorable + Serialize,
+{
+    let violation = match duplicate_key_error(&err) {
+        Some((Some(index), message)) => records
+            .get(index)
+            .and_then(|record| unique_violation(&message, record, false)),
+        Some((None, message)) => records
+            .iter()
+            .find_map(|record| unique_violation(&message, record, true)),
+        None => None,
+    };
+
+    violation.map(DatastoreError::from).unwrap_or(err)
+}
+
+fn unique_violation<T>(message: &str, record: &T, match_values: bool) -> Option<UniqueViolation>
+where
+    T: MongoStorable + Serialize,
+{
+    let document = to_document(record).ok()?;
+
+    T::unique_keys()
+        .into_iter()
+        .filter(|unique_key| message.contains(&format!("index: {} ", unique_key.index_name())))
+        .find_map(|unique_key| {
+            let (filter, value) = unique_key.value_of(&document)?;
+            let named = filter
+                .values()
+                .all(|field_value| message.contains(&unique_value(field_value)));
+
+            (!match_values || named).then(|| UniqueViolation {
+                field: unique_key.field(),
+                value,
+            })
+        })
+}
+
+//Cache ops moving every unique value of the record in the Redis index of table
+pub fn unique_cache_ops<T>(table: &str, record: &T) -> Result<Vec<CacheOp>>
+where
+    T: MongoStorable + Serialize,
+{
+    let document = to_document(record)?;
+
+    Ok(T::unique_keys()
+        .into_iter()
+        .map(|unique_key| CacheOp::Unique {
+            hash_key: unique_key.cache_key(table),
+            owner_hash_key: unique_key.owner_cache_key(table),
+            record_id: record.get_id().to_owned(),
+            value: unique_key.value_of(&document).map(|(_, value)| value),
+        })
+        .collect())
+}
+
+//Cache ops releasing every unique value a deleted record held
+pub fn unique_clear_ops<T>(table: &str, record_id: &str) -> Vec<CacheOp>
+where
+    T: MongoStorable,
+{
+    T::unique_keys()
+        .into_iter()
+        .map(|unique_key| CacheOp::Unique {
+            hash_key: unique_key.cache_key(table),
+            owner_hash_key: unique_key.owner_cache_key(table),
+            record_id: record_id.to_owned(),
+            value: None,
+        })
+        .collect()
+}
+
+impl Datastore {
+    //Fast pre-check against the Redis index. Only a hit held by another record goes on
+    //to Atlas, so a stale entry can never reject a valid write
+    pub(crate) async fn try_check_unique<T>(&self, table: &str, record: &T) -> Result<()>
+    where
+        T: MongoStorable + Serialize,
+    {
+        let unique_keys = T::unique_keys();
+
+        if unique_keys.is_empty() {
+            return Ok(());
+        }
+
+        self.ensure_indexes_once::<T>(table).await;
+
+        let document = to_document(record)?;
+        let mut suspected = false;
+
+        for unique_key in unique_keys.iter() {
+            if let Some((_, value)) = unique_key.value_of(&document) {
+                let owners = self
+                    .cache
+                    .try_read_fields(&unique_key.cache_key(table), vec![value])
+                    .await;
+
+                suspected |= match owners {
+                    Ok(owners) => owners
+                        .into_iter()
+                        .flatten()
+                        .any(|owner_id| owner_id != record.get_id()),
+                    //Without the cache nothing can be ruled out
+                    Err(_) => true,
+                };
+            }
+        }
+
+        if suspected {
+            try_check_unique_in_atlas(&self.database, None, table, record).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn unique_value(value: &Bson) -> String {
+    value
+        .as_str()
+        .map(str::to_owned)
+        .unwrap_or_else(|| value.to_string())
+}